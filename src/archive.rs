@@ -0,0 +1,510 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures_util::TryStreamExt;
+use http_body_util::{combinators::BoxBody, BodyExt, StreamBody};
+use hyper::body::{Bytes, Frame};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_stream::wrappers::ReceiverStream;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Whether `.static` should treat `path` as an archive rather than a
+/// directory: a `.tar`, `.tar.gz`, `.tgz` or `.zip` file
+pub fn is_archive(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    [".tar", ".tar.gz", ".tgz", ".zip"]
+        .iter()
+        .any(|ext| name.ends_with(ext))
+        && path.is_file()
+}
+
+/// An archive's file and directory index, built once and shared until the
+/// archive changes on disk
+pub struct Archive {
+    path: PathBuf,
+    /// A compressed tarball unpacked to a temporary file, which entry offsets
+    /// point into. Removed when the index is dropped.
+    unpacked: Option<tempfile::TempPath>,
+    files: HashMap<String, File>,
+    /// Every directory, including ones only implied by a file's path. The
+    /// root is `""`.
+    dirs: BTreeSet<String>,
+}
+
+pub struct File {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    data: Data,
+    hash: OnceLock<String>,
+}
+
+/// Where a file's bytes live
+enum Data {
+    /// Stored as-is at this offset in the archive: `.tar` and stored `.zip`
+    /// entries, and `.tar.gz` entries in its unpacked copy, read straight
+    /// from disk
+    Stored(u64),
+    /// A deflated `.zip` entry: inflated on each read
+    Deflated { offset: u64, compressed: u64 },
+}
+
+struct Cached {
+    modified: Option<SystemTime>,
+    len: u64,
+    last_used: Instant,
+    /// Filled by whichever request indexes the archive first; the others
+    /// wait on it rather than indexing it again
+    archive: Arc<Mutex<Option<Arc<Archive>>>>,
+}
+
+impl Cached {
+    fn matches(&self, meta: &std::fs::Metadata) -> bool {
+        self.modified == meta.modified().ok() && self.len == meta.len()
+    }
+}
+
+static ARCHIVES: OnceLock<Mutex<HashMap<PathBuf, Cached>>> = OnceLock::new();
+
+/// An index that hasn't been opened for this long is dropped, along with
+/// its unpacked copy, once nothing still reads from it
+const IDLE: Duration = Duration::from_secs(300);
+
+impl Archive {
+    /// The index for the archive at `path`, built on first use and rebuilt
+    /// when its size or mtime changes. Indexes of archives that changed,
+    /// disappeared or went unused are dropped as others are opened.
+    pub fn open(path: &Path) -> io::Result<Arc<Archive>> {
+        let cache = ARCHIVES.get_or_init(Default::default);
+        let meta = match std::fs::metadata(path) {
+            Ok(meta) => meta,
+            Err(err) => {
+                cache.lock().expect("archive cache poisoned").remove(path);
+                return Err(err);
+            }
+        };
+
+        let slot = {
+            let mut cache = cache.lock().expect("archive cache poisoned");
+            let now = Instant::now();
+            cache.retain(|_, cached| now.duration_since(cached.last_used) < IDLE);
+            if !cache.get(path).is_some_and(|cached| cached.matches(&meta)) {
+                // A new index: sweep out those of archives changed or
+                // removed since
+                cache.retain(|path, cached| {
+                    std::fs::metadata(path).is_ok_and(|meta| cached.matches(&meta))
+                });
+                cache.insert(
+                    path.to_path_buf(),
+                    Cached {
+                        modified: meta.modified().ok(),
+                        len: meta.len(),
+                        last_used: now,
+                        archive: Default::default(),
+                    },
+                );
+            }
+            let cached = cache.get_mut(path).expect("just inserted");
+            cached.last_used = now;
+            cached.archive.clone()
+        };
+
+        // A panic while indexing leaves the slot empty, for the next request
+        // to try again
+        let mut slot = slot.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(archive) = &*slot {
+            return Ok(archive.clone());
+        }
+        let archive = Arc::new(Self::index(path)?);
+        *slot = Some(archive.clone());
+        Ok(archive)
+    }
+
+    fn index(path: &Path) -> io::Result<Archive> {
+        let mut archive = Archive {
+            path: path.to_path_buf(),
+            unpacked: None,
+            files: HashMap::new(),
+            dirs: BTreeSet::from([String::new()]),
+        };
+        let name = path.to_string_lossy().to_ascii_lowercase();
+        if name.ends_with(".zip") {
+            archive.index_zip()?;
+        } else if name.ends_with(".tar") {
+            archive.index_tar()?;
+        } else {
+            archive.index_tar_gz()?;
+        }
+
+        // Archives needn't list a directory before its files
+        let implied: Vec<String> = archive
+            .files
+            .keys()
+            .chain(archive.dirs.iter())
+            .flat_map(|name| name.match_indices('/').map(|(i, _)| name[..i].to_string()))
+            .collect();
+        archive.dirs.extend(implied);
+        Ok(archive)
+    }
+
+    /// The file entry offsets point into: the archive, or its unpacked copy
+    fn source(&self) -> &Path {
+        self.unpacked.as_deref().unwrap_or(&self.path)
+    }
+
+    fn index_tar(&mut self) -> io::Result<()> {
+        let file = std::fs::File::open(self.source())?;
+        let len = file.metadata()?.len();
+        let mut tar = tar::Archive::new(file);
+        for entry in tar.entries_with_seek()? {
+            let entry = entry?;
+            let offset = entry.raw_file_position();
+            if offset.checked_add(entry.size()).is_none_or(|end| end > len) {
+                return Err(truncated(&entry.path()?.to_string_lossy()));
+            }
+            self.add_tar_entry(&entry, Data::Stored(offset))?;
+        }
+        Ok(())
+    }
+
+    /// A compressed tarball can't be read at an offset, so it's unpacked to
+    /// a temporary file, a buffer at a time, and indexed as a plain `.tar`
+    fn index_tar_gz(&mut self) -> io::Result<()> {
+        let file = BufReader::new(std::fs::File::open(&self.path)?);
+        let mut unpacked = tempfile::NamedTempFile::new()?;
+        io::copy(&mut flate2::read::GzDecoder::new(file), &mut unpacked)?;
+        self.unpacked = Some(unpacked.into_temp_path());
+        self.index_tar()
+    }
+
+    fn add_tar_entry<R: Read>(&mut self, entry: &tar::Entry<'_, R>, data: Data) -> io::Result<()> {
+        let Some(name) = normalize(&entry.path()?.to_string_lossy()) else {
+            return Ok(());
+        };
+        let kind = entry.header().entry_type();
+        if kind.is_dir() {
+            self.dirs.insert(name);
+        } else if kind.is_file() {
+            // Links and special files are left out
+            self.files.insert(
+                name,
+                File {
+                    size: entry.size(),
+                    modified: entry
+                        .header()
+                        .mtime()
+                        .ok()
+                        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+                    data,
+                    hash: OnceLock::new(),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn index_zip(&mut self) -> io::Result<()> {
+        let file = std::fs::File::open(&self.path)?;
+        let len = file.metadata()?.len();
+        let mut zip = zip::ZipArchive::new(BufReader::new(file)).map_err(io::Error::other)?;
+        for i in 0..zip.len() {
+            let entry = zip.by_index_raw(i).map_err(io::Error::other)?;
+            let Some(name) = normalize(entry.name()) else {
+                continue;
+            };
+            if entry.is_dir() {
+                self.dirs.insert(name);
+                continue;
+            }
+            if entry.is_symlink() {
+                continue;
+            }
+            if entry.encrypted() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("{name}: encrypted entries aren't supported"),
+                ));
+            }
+            let (offset, compressed) = (entry.data_start(), entry.compressed_size());
+            if offset.checked_add(compressed).is_none_or(|end| end > len) {
+                return Err(truncated(&name));
+            }
+            let data = match entry.compression() {
+                // A stored entry's size is its compressed size; trust that
+                zip::CompressionMethod::Stored if entry.size() == compressed => {
+                    Data::Stored(offset)
+                }
+                zip::CompressionMethod::Stored => return Err(truncated(&name)),
+                zip::CompressionMethod::Deflated => Data::Deflated { offset, compressed },
+                method => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("{name}: unsupported compression {method}"),
+                    ))
+                }
+            };
+            let modified = entry.last_modified().and_then(|t| {
+                let date = chrono::NaiveDate::from_ymd_opt(
+                    t.year().into(),
+                    t.month().into(),
+                    t.day().into(),
+                )?;
+                let time =
+                    date.and_hms_opt(t.hour().into(), t.minute().into(), t.second().into())?;
+                Some(time.and_utc().into())
+            });
+            self.files.insert(
+                name,
+                File {
+                    size: entry.size(),
+                    modified,
+                    data,
+                    hash: OnceLock::new(),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    pub fn file(&self, name: &str) -> Option<&File> {
+        self.files.get(name)
+    }
+
+    pub fn is_dir(&self, name: &str) -> bool {
+        self.dirs.contains(name)
+    }
+
+    /// Direct children of the directory `dir`: name, and the file unless
+    /// it's a directory
+    pub fn children(&self, dir: &str) -> Vec<(&str, Option<&File>)> {
+        self.dirs
+            .iter()
+            .filter_map(|name| Some((child(dir, name)?, None)))
+            .chain(
+                self.files
+                    .iter()
+                    .filter_map(|(name, file)| Some((child(dir, name)?, Some(file)))),
+            )
+            .collect()
+    }
+
+    /// xxh3 fingerprint of a file's contents, computed once per index
+    pub fn fingerprint(&self, file: &File) -> io::Result<String> {
+        if let Some(hash) = file.hash.get() {
+            return Ok(hash.clone());
+        }
+        let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+        let mut reader = self.reader(file)?;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        let hash = crate::static_files::short_hash(hasher.digest());
+        Ok(file.hash.get_or_init(|| hash).clone())
+    }
+
+    /// Blocking reader over a file's contents, never yielding more than its
+    /// declared size
+    fn reader(&self, file: &File) -> io::Result<Box<dyn Read + Send>> {
+        let mut archive = std::fs::File::open(self.source())?;
+        Ok(match &file.data {
+            Data::Stored(offset) => {
+                archive.seek(SeekFrom::Start(*offset))?;
+                Box::new(archive.take(file.size))
+            }
+            Data::Deflated { offset, compressed } => {
+                archive.seek(SeekFrom::Start(*offset))?;
+                Box::new(
+                    flate2::read::DeflateDecoder::new(BufReader::new(archive.take(*compressed)))
+                        .take(file.size),
+                )
+            }
+        })
+    }
+
+    /// The bytes of `range` within a file, streamed from disk. Deflated
+    /// files are inflated on a blocking thread, a buffer at a time,
+    /// discarding whatever comes before the range.
+    pub async fn read(
+        self: &Arc<Self>,
+        file: &File,
+        range: Range<u64>,
+    ) -> io::Result<BoxBody<Bytes, BoxError>> {
+        match &file.data {
+            Data::Stored(offset) => {
+                let mut archive = tokio::fs::File::open(self.source()).await?;
+                archive.seek(SeekFrom::Start(offset + range.start)).await?;
+                let stream =
+                    tokio_util::io::ReaderStream::new(archive.take(range.end - range.start))
+                        .map_ok(Frame::data)
+                        .map_err(|e| Box::new(e) as BoxError);
+                Ok(BodyExt::boxed(StreamBody::new(stream)))
+            }
+            Data::Deflated { .. } => {
+                let mut reader = self.reader(file)?;
+                let (tx, rx) = tokio::sync::mpsc::channel(4);
+                tokio::task::spawn_blocking(move || {
+                    let skipped = io::copy(&mut (&mut reader).take(range.start), &mut io::sink());
+                    if let Err(err) = skipped {
+                        let _ = tx.blocking_send(Err(err));
+                        return;
+                    }
+                    let mut reader = reader.take(range.end - range.start);
+                    let mut buf = vec![0; 64 * 1024];
+                    loop {
+                        match reader.read(&mut buf) {
+                            Ok(0) => break,
+                            Ok(n) => {
+                                let chunk = Bytes::copy_from_slice(&buf[..n]);
+                                if tx.blocking_send(Ok(chunk)).is_err() {
+                                    break;
+                                }
+                            }
+                            Err(err) => {
+                                let _ = tx.blocking_send(Err(err));
+                                break;
+                            }
+                        }
+                    }
+                });
+                let stream = ReceiverStream::new(rx)
+                    .map_ok(Frame::data)
+                    .map_err(|e| Box::new(e) as BoxError);
+                Ok(BodyExt::boxed(StreamBody::new(stream)))
+            }
+        }
+    }
+}
+
+/// An entry whose declared size runs past the end of the archive
+fn truncated(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{name}: entry extends past the end of the archive"),
+    )
+}
+
+/// `name` relative to `dir`, if it's directly inside it
+fn child<'a>(dir: &str, name: &'a str) -> Option<&'a str> {
+    let rest = if dir.is_empty() {
+        name
+    } else {
+        name.strip_prefix(dir)?.strip_prefix('/')?
+    };
+    (!rest.is_empty() && !rest.contains('/')).then_some(rest)
+}
+
+/// An entry name as a `/`-joined relative path. `None` for the root itself
+/// and for names that would escape it.
+fn normalize(name: &str) -> Option<String> {
+    let mut parts = Vec::new();
+    for part in name.split(['/', '\\']) {
+        match part {
+            "" | "." => {}
+            ".." => return None,
+            part => parts.push(part),
+        }
+    }
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize("./docs/index.html"),
+            Some("docs/index.html".into())
+        );
+        assert_eq!(normalize("docs/"), Some("docs".into()));
+        assert_eq!(normalize("/abs//path"), Some("abs/path".into()));
+        assert_eq!(normalize("./"), None);
+        assert_eq!(normalize("docs/../../etc/passwd"), None);
+    }
+
+    fn write_tar_gz(path: &Path, contents: &[u8]) {
+        let gz = flate2::write::GzEncoder::new(
+            std::fs::File::create(path).unwrap(),
+            flate2::Compression::default(),
+        );
+        let mut tar = tar::Builder::new(gz);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        tar.append_data(&mut header, "data.txt", contents).unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+    }
+
+    fn cached(path: &Path) -> bool {
+        ARCHIVES.get().unwrap().lock().unwrap().contains_key(path)
+    }
+
+    #[test]
+    fn test_open_shares_and_evicts() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("site.tar.gz");
+        write_tar_gz(&path, b"first");
+
+        // Concurrent first opens share a single index
+        let opened: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| Archive::open(&path).unwrap()))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert!(opened.iter().all(|a| Arc::ptr_eq(a, &opened[0])));
+        let unpacked = opened[0].source().to_path_buf();
+        assert!(unpacked.exists());
+        drop(opened);
+
+        // A changed archive is indexed again, and the stale unpacked copy
+        // goes with the old index
+        write_tar_gz(&path, b"second, and longer");
+        let archive = Archive::open(&path).unwrap();
+        assert_eq!(archive.file("data.txt").unwrap().size, 18);
+        assert!(!unpacked.exists());
+
+        // A removed archive's index is swept out when another is opened
+        let unpacked = archive.source().to_path_buf();
+        drop(archive);
+        std::fs::remove_file(&path).unwrap();
+        let other = tmp.path().join("other.tar.gz");
+        write_tar_gz(&other, b"other");
+        Archive::open(&other).unwrap();
+        assert!(!cached(&path));
+        assert!(!unpacked.exists());
+    }
+
+    #[test]
+    fn test_rejects_sizes_past_the_end() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("site.tar");
+        let mut tar = tar::Builder::new(std::fs::File::create(&path).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(10);
+        header.set_mode(0o644);
+        tar.append_data(&mut header, "data.txt", &b"0123456789"[..])
+            .unwrap();
+        tar.into_inner().unwrap();
+        assert!(Archive::index(&path).unwrap().file("data.txt").is_some());
+
+        // The header still says 10 bytes; only 5 are left
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(512 + 5).unwrap();
+        let err = Archive::index(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
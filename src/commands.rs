@@ -1,10 +1,12 @@
 use crate::bus::Bus;
 use crate::logging::log_print;
+use crate::request::BodyLimits;
 use crate::response::{Response, ResponseBodyType};
 use nu_engine::command_prelude::*;
 use nu_protocol::{
     shell_error::generic::GenericError, ByteStream, ByteStreamType, Category, Config, CustomValue,
-    PipelineData, PipelineMetadata, ShellError, Signature, Span, SyntaxShape, Type, Value,
+    ListStream, PipelineData, PipelineMetadata, Record, ShellError, Signature, Span, SyntaxShape,
+    Type, Value,
 };
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
@@ -125,6 +127,27 @@ impl CustomValue for CompiledTemplate {
 
 thread_local! {
     pub static RESPONSE_TX: RefCell<Option<oneshot::Sender<Response>>> = const { RefCell::new(None) };
+    pub static BODY_LIMITS: RefCell<Option<Arc<BodyLimits>>> = const { RefCell::new(None) };
+    pub static SPILLED_PARTS: RefCell<Option<Arc<SpilledParts>>> = const { RefCell::new(None) };
+}
+
+/// Files `from multipart` spilled to disk during a request. Any the script
+/// didn't move or delete are removed once the response completes, so an
+/// upload whose handler fails or stops reading leaves nothing behind.
+#[derive(Default)]
+pub struct SpilledParts(std::sync::Mutex<Vec<PathBuf>>);
+
+impl SpilledParts {
+    fn push(&self, path: PathBuf) {
+        self.0.lock().expect("spilled parts poisoned").push(path);
+    }
+
+    /// Remove the spilled files still at the path they were written to
+    pub fn remove_all(&self) {
+        for path in self.0.lock().expect("spilled parts poisoned").drain(..) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -148,20 +171,29 @@ impl Command for StaticCommand {
     }
 
     fn description(&self) -> &str {
-        "Serve static files from a directory"
+        "Serve static files from a directory or archive"
     }
 
     fn signature(&self) -> Signature {
         Signature::build(".static")
-            .required("root", SyntaxShape::String, "root directory path")
+            .required(
+                "root",
+                SyntaxShape::String,
+                "root directory, or a .tar, .tar.gz or .zip archive",
+            )
             .required("path", SyntaxShape::String, "request path")
+            .optional(
+                "config",
+                SyntaxShape::Record(vec![]),
+                "optional configuration (index, listing, cache, headers)",
+            )
             .named(
                 "fallback",
                 SyntaxShape::String,
                 "fallback file when request missing",
                 None,
             )
-            .input_output_types(vec![(Type::Nothing, Type::Nothing)])
+            .input_output_types(vec![(Type::Nothing, Type::Any)])
             .category(Category::Custom("http".into()))
     }
 
@@ -172,18 +204,148 @@ impl Command for StaticCommand {
         call: &Call,
         _input: PipelineData,
     ) -> Result<PipelineData, ShellError> {
-        let root: String = call.req(engine_state, stack, 0)?;
+        use crate::static_files::{Listing, Resolved};
+
+        let root: Spanned<String> = call.req(engine_state, stack, 0)?;
         let path: String = call.req(engine_state, stack, 1)?;
+        let config: Option<Value> = call.opt(engine_state, stack, 2)?;
 
         let fallback: Option<String> = call.get_flag(engine_state, stack, "fallback")?;
 
+        let invalid = |msg: String, span: Span| {
+            ShellError::Generic(GenericError::new("Invalid .static config", msg, span))
+        };
+        let mut index = vec!["index.html".to_string()];
+        let mut listing = None;
+        let mut cache = Vec::new();
+        let mut headers = HashMap::new();
+        if let Some(config) = &config {
+            let record = config.as_record()?;
+            if let Some(value) = record.get("index") {
+                index = match value {
+                    Value::String { val, .. } => vec![val.clone()],
+                    Value::List { vals, .. } => vals
+                        .iter()
+                        .map(|v| v.as_str().map(str::to_string))
+                        .collect::<Result<_, _>>()?,
+                    _ => {
+                        return Err(invalid(
+                            "index must be a file name or a list of them".into(),
+                            value.span(),
+                        ))
+                    }
+                };
+            }
+            if let Some(value) = record.get("listing") {
+                listing = match value {
+                    Value::Nothing { .. } | Value::Bool { val: false, .. } => None,
+                    Value::String { val, .. } if val == "html" => Some(Listing::Html),
+                    Value::String { val, .. } if val == "json" => Some(Listing::Json),
+                    _ => {
+                        return Err(invalid(
+                            "listing must be \"html\", \"json\" or false".into(),
+                            value.span(),
+                        ))
+                    }
+                };
+            }
+            if let Some(value) = record.get("cache") {
+                for (glob, policy) in value.as_record()?.iter() {
+                    let pattern = nu_glob::Pattern::new(glob).map_err(|err| {
+                        invalid(format!("bad glob '{glob}': {err}"), value.span())
+                    })?;
+                    cache.push((pattern, policy.as_str()?.to_string()));
+                }
+            }
+            if let Some(value) = record.get("headers") {
+                headers = config_headers(value);
+            }
+        }
+
+        let source = open_static_root(std::path::Path::new(&root.item), root.span)?;
+        let root = PathBuf::from(root.item);
+        let (path, file, immutable) = match crate::static_files::resolve(
+            &source,
+            &path,
+            fallback.as_deref(),
+            &index,
+            listing.is_some(),
+        ) {
+            Resolved::File {
+                path,
+                file,
+                immutable,
+            } => (path, file, immutable),
+            Resolved::Redirect(location) => {
+                headers.insert(
+                    "location".into(),
+                    crate::response::HeaderValue::Single(location),
+                );
+                return Ok(static_output(Value::nothing(call.head), 307, None, headers));
+            }
+            Resolved::Listing { rel } => {
+                let entries = source.read_listing(&rel).map_err(|err| {
+                    ShellError::Generic(GenericError::new(
+                        "Failed to list directory",
+                        err.to_string(),
+                        call.head,
+                    ))
+                })?;
+                let span = call.head;
+                return Ok(match listing {
+                    Some(Listing::Html) => {
+                        let title = if rel.is_empty() {
+                            "/".to_string()
+                        } else {
+                            format!("/{rel}/")
+                        };
+                        let html = crate::static_files::listing_html(&title, &entries);
+                        static_output(Value::string(html, span), 200, Some("text/html"), headers)
+                    }
+                    _ => {
+                        let rows = entries
+                            .into_iter()
+                            .map(|entry| {
+                                let modified = entry
+                                    .modified
+                                    .map(|t| {
+                                        Value::date(chrono::DateTime::<chrono::Utc>::from(t).into(), span)
+                                    })
+                                    .unwrap_or_else(|| Value::nothing(span));
+                                Value::record(
+                                    nu_protocol::record! {
+                                        "name" => Value::string(entry.name, span),
+                                        "type" => Value::string(if entry.is_dir { "dir" } else { "file" }, span),
+                                        "size" => Value::filesize(entry.size as i64, span),
+                                        "modified" => modified,
+                                    },
+                                    span,
+                                )
+                            })
+                            .collect();
+                        static_output(Value::list(rows, span), 200, None, headers)
+                    }
+                });
+            }
+        };
+
+        let cache_control = if immutable {
+            Some(crate::static_files::IMMUTABLE.to_string())
+        } else {
+            file.as_deref()
+                .and_then(|file| crate::static_files::cache_control(&cache, file))
+                .map(str::to_string)
+        };
+
         let response = Response {
             status: 200,
             headers: HashMap::new(),
             body_type: ResponseBodyType::Static {
-                root: PathBuf::from(root),
+                root,
                 path,
                 fallback,
+                cache_control,
+                headers,
             },
         };
 
@@ -204,6 +366,269 @@ impl Command for StaticCommand {
     }
 }
 
+#[derive(Clone)]
+pub struct AssetUrlCommand;
+
+impl Default for AssetUrlCommand {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AssetUrlCommand {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Command for AssetUrlCommand {
+    fn name(&self) -> &str {
+        "asset-url"
+    }
+
+    fn description(&self) -> &str {
+        "Content-hashed URL for a file under a static root, for far-future caching"
+    }
+
+    fn extra_description(&self) -> &str {
+        "`.static` serves the fingerprinted path from the real file with an immutable Cache-Control. The hash is cached until the file changes."
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build("asset-url")
+            .required(
+                "root",
+                SyntaxShape::String,
+                "root directory, or a .tar, .tar.gz or .zip archive",
+            )
+            .required("path", SyntaxShape::String, "file path under root")
+            .input_output_types(vec![(Type::Nothing, Type::String)])
+            .category(Category::Custom("http".into()))
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![Example {
+            description: "Link a stylesheet that can be cached forever",
+            example: r#"$"<link rel=stylesheet href=(asset-url ./public /assets/app.css)>""#,
+            result: None,
+        }]
+    }
+
+    fn run(
+        &self,
+        engine_state: &EngineState,
+        stack: &mut Stack,
+        call: &Call,
+        _input: PipelineData,
+    ) -> Result<PipelineData, ShellError> {
+        let root: Spanned<String> = call.req(engine_state, stack, 0)?;
+        let path: Spanned<String> = call.req(engine_state, stack, 1)?;
+
+        let rel = std::path::Path::new(path.item.trim_start_matches('/'));
+        if !rel
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+        {
+            return Err(ShellError::Generic(GenericError::new(
+                "Invalid asset path",
+                "must be a file under the root",
+                path.span,
+            )));
+        }
+        let rel = rel
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let hash = open_static_root(&PathBuf::from(root.item), root.span)?
+            .fingerprint(&rel)
+            .map_err(|err| {
+                ShellError::Generic(GenericError::new(
+                    "Failed to fingerprint asset",
+                    err.to_string(),
+                    path.span,
+                ))
+            })?;
+        Ok(Value::string(
+            crate::static_files::fingerprinted(&path.item, &hash),
+            call.head,
+        )
+        .into_pipeline_data())
+    }
+}
+
+/// A `.static` root: a directory, or an archive indexed on first use
+fn open_static_root(
+    root: &std::path::Path,
+    span: Span,
+) -> Result<crate::static_files::Root, ShellError> {
+    crate::static_files::Root::open(root).map_err(|err| {
+        ShellError::Generic(GenericError::new(
+            "Failed to open archive",
+            err.to_string(),
+            span,
+        ))
+    })
+}
+
+/// Output for a `.static` answer produced in the closure (listings,
+/// redirects), carrying its status and headers as `http.response` metadata
+fn static_output(
+    value: Value,
+    status: u16,
+    content_type: Option<&str>,
+    headers: HashMap<String, crate::response::HeaderValue>,
+) -> PipelineData {
+    let span = value.span();
+    let headers = headers
+        .into_iter()
+        .map(|(k, v)| {
+            let v = match v {
+                crate::response::HeaderValue::Single(s) => Value::string(s, span),
+                crate::response::HeaderValue::Multiple(vals) => Value::list(
+                    vals.into_iter().map(|s| Value::string(s, span)).collect(),
+                    span,
+                ),
+            };
+            (k, v)
+        })
+        .collect();
+    let mut metadata = PipelineMetadata {
+        content_type: content_type.map(str::to_string),
+        ..Default::default()
+    };
+    metadata.custom.insert(
+        "http.response",
+        Value::record(
+            nu_protocol::record! {
+                "status" => Value::int(status as i64, span),
+                "headers" => Value::record(headers, span),
+            },
+            span,
+        ),
+    );
+    PipelineData::Value(value, Some(metadata))
+}
+
+/// A `headers` config record: string values, or lists of strings for
+/// repeated headers. Other values are skipped.
+fn config_headers(value: &Value) -> HashMap<String, crate::response::HeaderValue> {
+    let mut headers = HashMap::new();
+    if let Ok(record) = value.as_record() {
+        for (k, v) in record.iter() {
+            let header_value = match v {
+                Value::String { val, .. } => crate::response::HeaderValue::Single(val.clone()),
+                Value::List { vals, .. } => {
+                    let strings: Vec<String> = vals
+                        .iter()
+                        .filter_map(|v| v.as_str().ok())
+                        .map(|s| s.to_string())
+                        .collect();
+                    crate::response::HeaderValue::Multiple(strings)
+                }
+                _ => continue, // Skip non-string/non-list values
+            };
+            headers.insert(k.clone(), header_value);
+        }
+    }
+    headers
+}
+
+#[derive(Clone)]
+pub struct BodyLimitCommand;
+
+impl Default for BodyLimitCommand {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BodyLimitCommand {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Command for BodyLimitCommand {
+    fn name(&self) -> &str {
+        ".body-limit"
+    }
+
+    fn description(&self) -> &str {
+        "Override the request body size limit and read timeout for this request"
+    }
+
+    fn extra_description(&self) -> &str {
+        r#"Replaces the --max-body-size / --body-timeout defaults for the current request, e.g.
+to allow large uploads on a single route. Input is passed through unchanged, so it can
+sit in front of whatever consumes the body. Must run before the body is read."#
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(".body-limit")
+            .named(
+                "max-size",
+                SyntaxShape::Filesize,
+                "maximum request body size",
+                None,
+            )
+            .named(
+                "timeout",
+                SyntaxShape::Duration,
+                "maximum time to receive the request body",
+                None,
+            )
+            .input_output_types(vec![(Type::Any, Type::Any)])
+            .category(Category::Custom("http".into()))
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![Example {
+            description: "Accept uploads of up to 1GB on this route",
+            example: r#".body-limit --max-size 1GB --timeout 10min | save upload.bin"#,
+            result: None,
+        }]
+    }
+
+    fn run(
+        &self,
+        engine_state: &EngineState,
+        stack: &mut Stack,
+        call: &Call,
+        input: PipelineData,
+    ) -> Result<PipelineData, ShellError> {
+        let max_size: Option<Value> = call.get_flag(engine_state, stack, "max-size")?;
+        let timeout: Option<Value> = call.get_flag(engine_state, stack, "timeout")?;
+
+        let max_size = max_size
+            .map(|v| v.as_filesize().map(|f| f.get().max(0) as u64))
+            .transpose()?;
+        let timeout = timeout
+            .map(|v| match v.as_duration()? {
+                nanos if nanos > 0 => Ok(std::time::Duration::from_nanos(nanos as u64)),
+                _ => Err(ShellError::IncorrectValue {
+                    msg: "timeout must be greater than zero".into(),
+                    val_span: v.span(),
+                    call_span: call.head,
+                }),
+            })
+            .transpose()?;
+
+        BODY_LIMITS.with(|limits| {
+            if let Some(limits) = limits.borrow().as_ref() {
+                if let Some(max_size) = max_size {
+                    limits.set_max_size(Some(max_size));
+                }
+                if let Some(timeout) = timeout {
+                    limits.set_timeout(Some(timeout));
+                }
+            }
+        });
+
+        Ok(input)
+    }
+}
+
 const LINE_ENDING: &str = "\n";
 
 #[derive(Clone)]
@@ -406,19 +831,355 @@ fn value_to_json(val: &Value, config: &Config) -> serde_json::Result<serde_json:
             for (k, v) in val.iter() {
                 map.insert(k.clone(), value_to_json(v, config)?);
             }
-            serde_json::Value::Object(map)
+            serde_json::Value::Object(map)
+        }
+        Value::Nothing { .. } => serde_json::Value::Null,
+        other => serde_json::Value::String(other.to_expanded_string("", config)),
+    })
+}
+
+fn update_metadata(metadata: Option<PipelineMetadata>) -> Option<PipelineMetadata> {
+    metadata
+        .map(|md| md.with_content_type(Some("text/event-stream".into())))
+        .or_else(|| {
+            Some(PipelineMetadata::default().with_content_type(Some("text/event-stream".into())))
+        })
+}
+
+#[derive(Clone)]
+pub struct FromMultipartCommand {
+    #[cfg(feature = "cross-stream")]
+    store: Option<xs::store::Store>,
+}
+
+impl Default for FromMultipartCommand {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FromMultipartCommand {
+    pub fn new() -> Self {
+        Self {
+            #[cfg(feature = "cross-stream")]
+            store: None,
+        }
+    }
+
+    #[cfg(feature = "cross-stream")]
+    pub fn with_store(store: xs::store::Store) -> Self {
+        Self { store: Some(store) }
+    }
+}
+
+impl Command for FromMultipartCommand {
+    fn name(&self) -> &str {
+        "from multipart"
+    }
+
+    fn description(&self) -> &str {
+        "Parse a multipart/form-data request body into a stream of parts"
+    }
+
+    fn extra_description(&self) -> &str {
+        r#"Yields one record per part: {name, filename, content_type, headers, body}. The body
+is parsed as it streams in. Fields without a filename become strings (binary if not valid
+UTF-8); file parts are binary.
+
+With --spill-over, any part larger than the threshold is written to a file in --spill-dir
+(default: the system temp directory) instead of memory; its body is null and `path` holds
+the file. Move it to keep it: files still at `path` are removed once the response completes. With --cas (requires --store), file
+parts are streamed into the store's content-addressable storage; their body is null and
+`hash` can be read back with .cas."#
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build("from multipart")
+            .named(
+                "boundary",
+                SyntaxShape::String,
+                "boundary, or the request's Content-Type header (default: sniffed from the body)",
+                Some('b'),
+            )
+            .named(
+                "spill-over",
+                SyntaxShape::Filesize,
+                "write parts larger than this to a temp file",
+                None,
+            )
+            .named(
+                "spill-dir",
+                SyntaxShape::String,
+                "directory for spilled parts",
+                None,
+            )
+            .switch("cas", "stream file parts into the store's CAS", None)
+            .input_output_types(vec![
+                (Type::Binary, Type::List(Box::new(Type::record()))),
+                (Type::String, Type::List(Box::new(Type::record()))),
+                (Type::Nothing, Type::List(Box::new(Type::record()))),
+            ])
+            .category(Category::Formats)
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                description: "Save uploaded files, spilling anything over 1MB to disk",
+                example: r#"{|req| from multipart --boundary $req.headers.content-type --spill-over 1MB | where filename != null }"#,
+                result: None,
+            },
+            Example {
+                description: "Store uploads in cross.stream",
+                example: r#"{|req| from multipart --cas | each {|p| $p.hash | .append upload } }"#,
+                result: None,
+            },
+        ]
+    }
+
+    fn run(
+        &self,
+        engine_state: &EngineState,
+        stack: &mut Stack,
+        call: &Call,
+        input: PipelineData,
+    ) -> Result<PipelineData, ShellError> {
+        let span = call.head;
+        let boundary: Option<String> = call.get_flag(engine_state, stack, "boundary")?;
+        let spill_over: Option<Value> = call.get_flag(engine_state, stack, "spill-over")?;
+        let spill_dir: Option<String> = call.get_flag(engine_state, stack, "spill-dir")?;
+        let cas = call.has_flag(engine_state, stack, "cas")?;
+
+        let spill_over = spill_over
+            .map(|v| v.as_filesize().map(|f| f.get().max(0) as u64))
+            .transpose()?;
+        let spill_dir = spill_dir
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir);
+
+        #[cfg(feature = "cross-stream")]
+        let store = match (cas, &self.store) {
+            (true, None) => return Err(cas_requires_store(span)),
+            (true, Some(store)) => Some(store.clone()),
+            (false, _) => None,
+        };
+        #[cfg(not(feature = "cross-stream"))]
+        if cas {
+            return Err(cas_requires_store(span));
+        }
+
+        let reader: Box<dyn Read + Send> = match input {
+            PipelineData::ByteStream(stream, _) => match stream.reader() {
+                Some(reader) => Box::new(reader),
+                None => return Ok(Value::list(vec![], span).into_pipeline_data()),
+            },
+            PipelineData::Value(Value::Binary { val, .. }, _) => {
+                Box::new(std::io::Cursor::new(val))
+            }
+            PipelineData::Value(Value::String { val, .. }, _) => {
+                Box::new(std::io::Cursor::new(val.into_bytes()))
+            }
+            PipelineData::Empty | PipelineData::Value(Value::Nothing { .. }, _) => {
+                return Ok(Value::list(vec![], span).into_pipeline_data())
+            }
+            PipelineData::Value(other, _) => {
+                return Err(ShellError::OnlySupportsThisInputType {
+                    exp_input_type: "binary or string".into(),
+                    wrong_type: other.get_type().to_string(),
+                    dst_span: span,
+                    src_span: other.span(),
+                })
+            }
+            PipelineData::ListStream(stream, _) => {
+                return Err(ShellError::OnlySupportsThisInputType {
+                    exp_input_type: "binary or string".into(),
+                    wrong_type: "list".into(),
+                    dst_span: span,
+                    src_span: stream.span(),
+                })
+            }
+        };
+
+        let parts = MultipartParts {
+            reader: crate::multipart::MultipartReader::new(
+                reader,
+                boundary
+                    .as_deref()
+                    .map(crate::multipart::boundary_from)
+                    .as_deref(),
+            ),
+            spill_over,
+            spill_dir,
+            // Taken here, on the request's thread: the parts may be read on
+            // another
+            spilled: SPILLED_PARTS.with(|spilled| spilled.borrow().clone()),
+            #[cfg(feature = "cross-stream")]
+            store,
+            span,
+            done: false,
+        };
+        Ok(PipelineData::ListStream(
+            ListStream::new(parts, span, engine_state.signals().clone()),
+            None,
+        ))
+    }
+}
+
+fn cas_requires_store(span: Span) -> ShellError {
+    ShellError::Generic(GenericError::new(
+        "--cas requires a store",
+        "start http-nu with --store to stream parts into CAS",
+        span,
+    ))
+}
+
+/// Lazily parses parts as `from multipart`'s output stream is consumed.
+struct MultipartParts {
+    reader: crate::multipart::MultipartReader<Box<dyn Read + Send>>,
+    spill_over: Option<u64>,
+    spill_dir: PathBuf,
+    /// The request's spilled files; `None` outside a request (`http-nu eval`)
+    spilled: Option<Arc<SpilledParts>>,
+    #[cfg(feature = "cross-stream")]
+    store: Option<xs::store::Store>,
+    span: Span,
+    done: bool,
+}
+
+impl MultipartParts {
+    fn next_part(&mut self) -> Result<Option<Value>, ShellError> {
+        let span = self.span;
+        let io_err = |err: std::io::Error| {
+            ShellError::Generic(GenericError::new(
+                "Invalid multipart body",
+                err.to_string(),
+                span,
+            ))
+        };
+
+        let Some(part) = self.reader.next_part().map_err(io_err)? else {
+            return Ok(None);
+        };
+        let is_file = part.filename.is_some();
+        let opt_string =
+            |s: Option<String>| s.map_or(Value::nothing(span), |s| Value::string(s, span));
+
+        let mut headers = Record::new();
+        for (name, value) in part.headers {
+            headers.push(name, Value::string(value, span));
+        }
+        let mut record = record! {
+            "name" => opt_string(part.name),
+            "filename" => opt_string(part.filename),
+            "content_type" => opt_string(part.content_type),
+            "headers" => Value::record(headers, span),
+        };
+
+        #[cfg(feature = "cross-stream")]
+        if let (true, Some(store)) = (is_file, &self.store) {
+            let cas_err =
+                |err: String| ShellError::Generic(GenericError::new("CAS write failed", err, span));
+            let mut writer = store
+                .cas_writer_sync()
+                .map_err(|e| cas_err(e.to_string()))?;
+            self.reader.copy_body(&mut writer).map_err(io_err)?;
+            let hash = writer.commit().map_err(|e| cas_err(e.to_string()))?;
+            record.push("body", Value::nothing(span));
+            record.push("hash", Value::string(hash.to_string(), span));
+            return Ok(Some(Value::record(record, span)));
+        }
+
+        let mut sink = SpillBuffer::Memory(Vec::new());
+        let mut writer = SpillWriter {
+            sink: &mut sink,
+            limit: self.spill_over,
+            dir: &self.spill_dir,
+        };
+        self.reader.copy_body(&mut writer).map_err(io_err)?;
+        match sink {
+            SpillBuffer::Memory(bytes) => {
+                let body = match (is_file, String::from_utf8(bytes)) {
+                    (false, Ok(s)) => Value::string(s, span),
+                    (true, Ok(s)) => Value::binary(s.into_bytes(), span),
+                    (_, Err(e)) => Value::binary(e.into_bytes(), span),
+                };
+                record.push("body", body);
+            }
+            SpillBuffer::File(file) => {
+                let path = file.into_temp_path().keep().map_err(|e| io_err(e.error))?;
+                if let Some(spilled) = &self.spilled {
+                    spilled.push(path.clone());
+                }
+                record.push("body", Value::nothing(span));
+                record.push("path", Value::string(path.to_string_lossy(), span));
+            }
+        }
+        Ok(Some(Value::record(record, span)))
+    }
+}
+
+impl Iterator for MultipartParts {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        if self.done {
+            return None;
+        }
+        match self.next_part() {
+            Ok(Some(value)) => Some(value),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Value::error(err, self.span))
+            }
         }
-        Value::Nothing { .. } => serde_json::Value::Null,
-        other => serde_json::Value::String(other.to_expanded_string("", config)),
-    })
+    }
 }
 
-fn update_metadata(metadata: Option<PipelineMetadata>) -> Option<PipelineMetadata> {
-    metadata
-        .map(|md| md.with_content_type(Some("text/event-stream".into())))
-        .or_else(|| {
-            Some(PipelineMetadata::default().with_content_type(Some("text/event-stream".into())))
-        })
+enum SpillBuffer {
+    Memory(Vec<u8>),
+    File(tempfile::NamedTempFile),
+}
+
+/// Buffers a part in memory until it outgrows `limit`, then moves it to a
+/// temp file in `dir`.
+struct SpillWriter<'a> {
+    sink: &'a mut SpillBuffer,
+    limit: Option<u64>,
+    dir: &'a std::path::Path,
+}
+
+impl std::io::Write for SpillWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if let SpillBuffer::Memory(buf) = &mut *self.sink {
+            if self
+                .limit
+                .is_none_or(|limit| (buf.len() + data.len()) as u64 <= limit)
+            {
+                buf.extend_from_slice(data);
+                return Ok(data.len());
+            }
+            let mut file = tempfile::Builder::new()
+                .prefix("http-nu-upload-")
+                .tempfile_in(self.dir)?;
+            file.write_all(buf)?;
+            *self.sink = SpillBuffer::File(file);
+        }
+        match &mut *self.sink {
+            SpillBuffer::File(file) => file.write(data),
+            SpillBuffer::Memory(_) => unreachable!("spilled above"),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut *self.sink {
+            SpillBuffer::File(file) => file.flush(),
+            SpillBuffer::Memory(_) => Ok(()),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -447,13 +1208,21 @@ impl Command for ReverseProxyCommand {
 
     fn signature(&self) -> Signature {
         Signature::build(".reverse-proxy")
-            .required("target_url", SyntaxShape::String, "backend URL to proxy to")
+            .required(
+                "target_url",
+                // List first: a list literal would also parse as a bare string
+                SyntaxShape::OneOf(vec![
+                    SyntaxShape::List(Box::new(SyntaxShape::String)),
+                    SyntaxShape::String,
+                ]),
+                "backend URL to proxy to, or a list of them to balance across",
+            )
             .optional(
                 "config",
                 SyntaxShape::Record(vec![]),
-                "optional configuration (headers, preserve_host, strip_prefix, query)",
+                "optional configuration (headers, preserve_host, forwarded, strip_prefix, query, timeout, strategy, hash_on, max_fails, fail_timeout, health_check, on_response)",
             )
-            .input_output_types(vec![(Type::Any, Type::Nothing)])
+            .input_output_types(vec![(Type::Any, Type::Any)])
             .category(Category::Custom("http".into()))
     }
 
@@ -464,76 +1233,38 @@ impl Command for ReverseProxyCommand {
         call: &Call,
         input: PipelineData,
     ) -> Result<PipelineData, ShellError> {
-        let target_url: String = call.req(engine_state, stack, 0)?;
-
-        // Convert input pipeline data to bytes for request body
-        let request_body = match input {
-            PipelineData::Empty => Vec::new(),
-            PipelineData::Value(value, _) => crate::response::value_to_bytes(value),
-            PipelineData::ByteStream(stream, _) => {
-                // Collect all bytes from the stream
-                let mut body_bytes = Vec::new();
-                if let Some(mut reader) = stream.reader() {
-                    loop {
-                        let mut buffer = vec![0; 8192];
-                        match reader.read(&mut buffer) {
-                            Ok(0) => break, // EOF
-                            Ok(n) => {
-                                buffer.truncate(n);
-                                body_bytes.extend_from_slice(&buffer);
-                            }
-                            Err(_) => break,
-                        }
-                    }
-                }
-                body_bytes
-            }
-            PipelineData::ListStream(stream, _) => {
-                // Convert list stream to JSON array
-                let items: Vec<_> = stream.into_iter().collect();
-                let json_value = serde_json::Value::Array(
-                    items
-                        .into_iter()
-                        .map(|v| crate::response::value_to_json(&v))
-                        .collect(),
-                );
-                serde_json::to_string(&json_value)
-                    .unwrap_or_default()
-                    .into_bytes()
-            }
+        let targets = match call.req::<Value>(engine_state, stack, 0)? {
+            Value::List { vals, .. } => vals
+                .iter()
+                .map(|v| v.as_str().map(str::to_string))
+                .collect::<Result<Vec<_>, _>>()?,
+            value => vec![value.as_str()?.to_string()],
         };
+        if targets.is_empty() {
+            return Err(ShellError::Generic(GenericError::new(
+                "No upstreams",
+                "provide at least one target URL",
+                call.arguments_span(),
+            )));
+        }
 
         // Parse optional config
         let config = call.opt::<Value>(engine_state, stack, 1);
 
         let mut headers = HashMap::new();
         let mut preserve_host = true;
+        let mut forwarded = true;
         let mut strip_prefix: Option<String> = None;
         let mut query: Option<HashMap<String, String>> = None;
+        let mut timeout: Option<std::time::Duration> = None;
+        let mut balance: Option<crate::upstream::Balance> = None;
+        let mut on_response: Option<nu_protocol::engine::Closure> = None;
 
         if let Ok(Some(config_value)) = config {
             if let Ok(record) = config_value.as_record() {
                 // Extract headers
                 if let Some(headers_value) = record.get("headers") {
-                    if let Ok(headers_record) = headers_value.as_record() {
-                        for (k, v) in headers_record.iter() {
-                            let header_value = match v {
-                                Value::String { val, .. } => {
-                                    crate::response::HeaderValue::Single(val.clone())
-                                }
-                                Value::List { vals, .. } => {
-                                    let strings: Vec<String> = vals
-                                        .iter()
-                                        .filter_map(|v| v.as_str().ok())
-                                        .map(|s| s.to_string())
-                                        .collect();
-                                    crate::response::HeaderValue::Multiple(strings)
-                                }
-                                _ => continue, // Skip non-string/non-list values
-                            };
-                            headers.insert(k.clone(), header_value);
-                        }
-                    }
+                    headers = config_headers(headers_value);
                 }
 
                 // Extract preserve_host
@@ -543,6 +1274,13 @@ impl Command for ReverseProxyCommand {
                     }
                 }
 
+                // Extract forwarded
+                if let Some(forwarded_value) = record.get("forwarded") {
+                    if let Ok(f) = forwarded_value.as_bool() {
+                        forwarded = f;
+                    }
+                }
+
                 // Extract strip_prefix
                 if let Some(strip_prefix_value) = record.get("strip_prefix") {
                     if let Ok(prefix) = strip_prefix_value.as_str() {
@@ -562,19 +1300,98 @@ impl Command for ReverseProxyCommand {
                         query = Some(query_map);
                     }
                 }
+
+                // Extract timeout
+                if let Some(timeout_value) = record.get("timeout") {
+                    let nanos = timeout_value.as_duration()?;
+                    timeout = Some(std::time::Duration::from_nanos(nanos.max(0) as u64));
+                }
+
+                balance = parse_balance(record)?;
+
+                // Extract on_response
+                if let Some(on_response_value) = record.get("on_response") {
+                    on_response = Some(on_response_value.as_closure()?.clone());
+                }
             }
         }
+        // Several targets are always balanced, with defaults if unconfigured
+        if targets.len() > 1 {
+            balance.get_or_insert_with(Default::default);
+        }
+
+        // The request body is streamed to the upstream: buffered inputs are
+        // queued up front, a ByteStream is pumped once the response is sent
+        let (body_tx, request_body) = tokio::sync::mpsc::channel(32);
+        let (request_body_len, stream) = match input {
+            PipelineData::Empty => (Some(0), None),
+            PipelineData::Value(value, _) => {
+                if !matches!(
+                    value,
+                    Value::Nothing { .. }
+                        | Value::String { .. }
+                        | Value::Int { .. }
+                        | Value::Float { .. }
+                        | Value::Bool { .. }
+                        | Value::Binary { .. }
+                        | Value::List { .. }
+                        | Value::Record { .. }
+                ) {
+                    return Err(ShellError::OnlySupportsThisInputType {
+                        exp_input_type: "string, binary, number, bool, list or record".into(),
+                        wrong_type: value.get_type().to_string(),
+                        dst_span: call.head,
+                        src_span: value.span(),
+                    });
+                }
+                let bytes = crate::response::value_to_bytes(value);
+                let len = bytes.len() as u64;
+                let _ = body_tx.try_send(Ok(bytes));
+                (Some(len), None)
+            }
+            PipelineData::ListStream(stream, _) => {
+                // Convert list stream to JSON array
+                let items: Vec<_> = stream.into_iter().collect();
+                let json_value = serde_json::Value::Array(
+                    items
+                        .into_iter()
+                        .map(|v| crate::response::value_to_json(&v))
+                        .collect(),
+                );
+                let bytes = serde_json::to_string(&json_value)
+                    .unwrap_or_default()
+                    .into_bytes();
+                let len = bytes.len() as u64;
+                let _ = body_tx.try_send(Ok(bytes));
+                (Some(len), None)
+            }
+            // Only the untouched request body carries a known size
+            PipelineData::ByteStream(stream, _) => (stream.known_size(), Some(stream)),
+        };
+
+        let (upstream_tx, upstream_rx) = match on_response {
+            Some(_) => {
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                (Some(tx), Some(rx))
+            }
+            None => (None, None),
+        };
 
         let response = Response {
             status: 200,
             headers: HashMap::new(),
             body_type: ResponseBodyType::ReverseProxy {
-                target_url,
+                targets,
+                balance: balance.map(Box::new),
                 headers,
                 preserve_host,
+                forwarded,
                 strip_prefix,
                 request_body,
+                request_body_len,
                 query,
+                timeout,
+                on_response: upstream_tx,
             },
         };
 
@@ -591,6 +1408,366 @@ impl Command for ReverseProxyCommand {
             Ok(())
         })?;
 
+        if let Some(mut reader) = stream.and_then(|stream| stream.reader()) {
+            let mut buffer = vec![0; 8192];
+            loop {
+                match reader.read(&mut buffer) {
+                    Ok(0) => break, // EOF
+                    Ok(n) => {
+                        if body_tx.blocking_send(Ok(buffer[..n].to_vec())).is_err() {
+                            break; // Upstream stopped reading
+                        }
+                    }
+                    Err(err) => {
+                        // Abort the upstream request rather than truncate it
+                        let _ = body_tx.blocking_send(Err(err));
+                        break;
+                    }
+                }
+            }
+        }
+        drop(body_tx);
+
+        // Closed if the upstream failed (502/504) or switched protocols
+        let (Some(closure), Some(Ok(upstream))) =
+            (on_response, upstream_rx.map(|rx| rx.blocking_recv()))
+        else {
+            return Ok(PipelineData::Empty);
+        };
+        on_response_output(engine_state, stack, closure, upstream, call.head)
+    }
+}
+
+/// Run an `on_response` closure over the upstream response. Its output is
+/// served like any other closure output; the upstream status and headers
+/// apply unless it sets its own `http.response` metadata.
+fn on_response_output(
+    engine_state: &EngineState,
+    stack: &mut Stack,
+    closure: nu_protocol::engine::Closure,
+    upstream: crate::response::UpstreamResponse,
+    span: Span,
+) -> Result<PipelineData, ShellError> {
+    use nu_engine::ClosureEvalOnce;
+
+    let content_type = upstream
+        .headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    // Single values as strings, repeated ones (Set-Cookie) as lists: the
+    // same shape `http.response` headers take, so they can be handed back
+    let mut headers = Record::new();
+    for key in upstream.headers.keys() {
+        // Framing and content type are set afresh for the new body
+        let skip = [
+            http::header::CONTENT_LENGTH,
+            http::header::TRANSFER_ENCODING,
+            http::header::CONNECTION,
+            http::header::CONTENT_TYPE,
+        ];
+        if skip.contains(key) || key == "keep-alive" {
+            continue;
+        }
+        let mut values: Vec<Value> = upstream
+            .headers
+            .get_all(key)
+            .iter()
+            .map(|v| Value::string(String::from_utf8_lossy(v.as_bytes()), span))
+            .collect();
+        let value = if values.len() == 1 {
+            values.remove(0)
+        } else {
+            Value::list(values, span)
+        };
+        headers.push(key.as_str(), value);
+    }
+    let headers = Value::record(headers, span);
+    let status = Value::int(upstream.status as i64, span);
+
+    let mut body_rx = upstream.body;
+    let body = ByteStream::from_fn(
+        span,
+        engine_state.signals().clone(),
+        ByteStreamType::Unknown,
+        move |buffer: &mut Vec<u8>| match body_rx.blocking_recv() {
+            Some(Ok(bytes)) => {
+                buffer.extend_from_slice(&bytes);
+                Ok(true)
+            }
+            Some(Err(err)) => Err(ShellError::Generic(GenericError::new_internal(
+                "Upstream body read error",
+                err.to_string(),
+            ))),
+            None => Ok(false),
+        },
+    );
+    let metadata = PipelineMetadata {
+        content_type: content_type.clone(),
+        ..Default::default()
+    };
+
+    let res = Value::record(
+        nu_protocol::record! {
+            "status" => status.clone(),
+            "headers" => headers.clone(),
+        },
+        span,
+    );
+    let mut output = ClosureEvalOnce::new(engine_state, stack, closure)
+        .add_arg(res)?
+        .run_with_input(PipelineData::ByteStream(body, Some(metadata)))?;
+
+    let mut metadata = output.take_metadata().unwrap_or_default();
+    // Collecting `$in` drops its metadata: keep the upstream content type
+    // for bodies that are still raw text or bytes
+    if metadata.content_type.is_none()
+        && matches!(
+            output,
+            PipelineData::ByteStream(..)
+                | PipelineData::Value(Value::String { .. } | Value::Binary { .. }, _)
+        )
+    {
+        metadata.content_type = content_type;
+    }
+    let mut http_response = metadata
+        .custom
+        .get("http.response")
+        .and_then(|v| v.as_record().ok())
+        .cloned()
+        .unwrap_or_default();
+    if !http_response.contains("status") {
+        http_response.push("status", status);
+    }
+    if !http_response.contains("headers") {
+        http_response.push("headers", headers);
+    }
+    metadata
+        .custom
+        .insert("http.response", Value::record(http_response, span));
+    Ok(output.set_metadata(Some(metadata)))
+}
+
+/// Load balancing options from a `.reverse-proxy` config record. `None` if
+/// the record sets none of them.
+fn parse_balance(record: &Record) -> Result<Option<crate::upstream::Balance>, ShellError> {
+    use crate::upstream::{Balance, HashOn, HealthCheck, Strategy};
+
+    const KEYS: [&str; 5] = [
+        "strategy",
+        "hash_on",
+        "max_fails",
+        "fail_timeout",
+        "health_check",
+    ];
+    if !KEYS.iter().any(|key| record.contains(key)) {
+        return Ok(None);
+    }
+
+    let duration = |value: &Value| -> Result<std::time::Duration, ShellError> {
+        Ok(std::time::Duration::from_nanos(
+            value.as_duration()?.max(0) as u64
+        ))
+    };
+    let invalid = |msg: &str, value: &Value| {
+        ShellError::Generic(GenericError::new(
+            "Invalid .reverse-proxy config",
+            msg.to_string(),
+            value.span(),
+        ))
+    };
+
+    let mut balance = Balance::default();
+
+    let hash_on = match record.get("hash_on") {
+        Some(value) => Some(
+            HashOn::parse(value.as_str()?)
+                .ok_or_else(|| invalid("expected \"header:<name>\" or \"cookie:<name>\"", value))?,
+        ),
+        None => None,
+    };
+    if let Some(value) = record.get("strategy") {
+        balance.strategy = match value.as_str()? {
+            "round-robin" => Strategy::RoundRobin,
+            "least-inflight" => Strategy::LeastInflight,
+            "hash" => Strategy::Hash(
+                hash_on.ok_or_else(|| invalid("strategy \"hash\" requires hash_on", value))?,
+            ),
+            _ => {
+                return Err(invalid(
+                    "expected \"round-robin\", \"least-inflight\" or \"hash\"",
+                    value,
+                ))
+            }
+        };
+    } else if let Some(on) = hash_on {
+        balance.strategy = Strategy::Hash(on);
+    }
+
+    if let Some(value) = record.get("max_fails") {
+        let max_fails = value.as_int()?;
+        if max_fails < 1 {
+            return Err(invalid("max_fails must be at least 1", value));
+        }
+        balance.max_fails = max_fails.min(u32::MAX as i64) as u32;
+    }
+    if let Some(value) = record.get("fail_timeout") {
+        balance.fail_timeout = duration(value)?;
+    }
+    if let Some(value) = record.get("health_check") {
+        let check = value.as_record()?;
+        let path = check
+            .get("path")
+            .ok_or_else(|| invalid("health_check requires a path", value))?
+            .as_str()?;
+        balance.health_check = Some(HealthCheck {
+            path: if path.starts_with('/') {
+                path.to_string()
+            } else {
+                format!("/{path}")
+            },
+            interval: match check.get("interval") {
+                Some(v) => duration(v)?.max(std::time::Duration::from_millis(100)),
+                None => std::time::Duration::from_secs(5),
+            },
+            timeout: match check.get("timeout") {
+                Some(v) => duration(v)?,
+                None => std::time::Duration::from_secs(2),
+            },
+        });
+    }
+
+    Ok(Some(balance))
+}
+
+#[derive(Clone)]
+pub struct WebSocketCommand;
+
+impl Default for WebSocketCommand {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WebSocketCommand {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Command for WebSocketCommand {
+    fn name(&self) -> &str {
+        ".websocket"
+    }
+
+    fn description(&self) -> &str {
+        "Upgrade the request to a WebSocket connection"
+    }
+
+    fn extra_description(&self) -> &str {
+        r#"The closure runs once per connection. Inbound text and binary messages arrive as a
+stream on `$in`; each value the closure yields is sent as a message: strings and
+records/lists (as JSON) become text frames, binary becomes binary frames. The
+connection closes when the closure finishes or the client disconnects."#
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(".websocket")
+            .required(
+                "closure",
+                SyntaxShape::Closure(None),
+                "handler receiving inbound messages as $in",
+            )
+            .input_output_types(vec![(Type::Any, Type::Nothing)])
+            .category(Category::Custom("http".into()))
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![Example {
+            description: "Echo every message back to the client",
+            example: r#"{|req| .websocket {|| each {|msg| $"echo: ($msg)" } } }"#,
+            result: None,
+        }]
+    }
+
+    fn run(
+        &self,
+        engine_state: &EngineState,
+        stack: &mut Stack,
+        call: &Call,
+        _input: PipelineData,
+    ) -> Result<PipelineData, ShellError> {
+        use crate::response::WebSocketMessage;
+        use nu_engine::ClosureEvalOnce;
+        use nu_protocol::{engine::Closure, ListStream};
+
+        let head = call.head;
+        let closure: Closure = call.req(engine_state, stack, 0)?;
+
+        let (inbound_tx, mut inbound_rx) = tokio::sync::mpsc::channel(32);
+        let (outbound_tx, outbound_rx) = tokio::sync::mpsc::channel(32);
+
+        let response = Response {
+            status: 101,
+            headers: HashMap::new(),
+            body_type: ResponseBodyType::WebSocket {
+                inbound: inbound_tx,
+                outbound: outbound_rx,
+            },
+        };
+
+        let sent = RESPONSE_TX.with(|tx| -> Result<_, ShellError> {
+            if let Some(tx) = tx.borrow_mut().take() {
+                tx.send(response).map_err(|_| {
+                    ShellError::Generic(GenericError::new(
+                        "Failed to send response",
+                        "Channel closed",
+                        head,
+                    ))
+                })?;
+                return Ok(true);
+            }
+            Ok(false)
+        })?;
+
+        // Outside a request (e.g. `http-nu eval`) there is no connection to upgrade
+        if !sent {
+            return Ok(PipelineData::Empty);
+        }
+
+        // Inbound frames as a Nu stream; ends when the client disconnects
+        let messages = ListStream::new(
+            std::iter::from_fn(move || {
+                inbound_rx.blocking_recv().map(|msg| match msg {
+                    WebSocketMessage::Text(text) => Value::string(text, head),
+                    WebSocketMessage::Binary(bytes) => Value::binary(bytes, head),
+                })
+            }),
+            head,
+            engine_state.signals().clone(),
+        );
+
+        let output = ClosureEvalOnce::new(engine_state, stack, closure)
+            .run_with_input(PipelineData::ListStream(messages, None))?;
+
+        for value in output {
+            let msg = match value {
+                Value::Error { error, .. } => return Err(*error),
+                Value::Nothing { .. } => continue,
+                Value::Binary { val, .. } => WebSocketMessage::Binary(val),
+                Value::String { val, .. } => WebSocketMessage::Text(val),
+                // Anything else goes out as JSON text
+                other => WebSocketMessage::Text(
+                    serde_json::to_string(&crate::response::value_to_json(&other))
+                        .unwrap_or_default(),
+                ),
+            };
+            if outbound_tx.blocking_send(msg).is_err() {
+                break;
+            }
+        }
+
         Ok(PipelineData::Empty)
     }
 }
@@ -1595,6 +2772,97 @@ Messages appear in both human-readable and JSONL output modes.
     }
 }
 
+// === error make, with an HTTP response ===
+
+#[derive(Clone)]
+pub struct ErrorMakeCommand;
+
+impl Default for ErrorMakeCommand {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ErrorMakeCommand {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Command for ErrorMakeCommand {
+    fn name(&self) -> &str {
+        "error make"
+    }
+
+    fn description(&self) -> &str {
+        "Create an error, optionally with the HTTP response it should become"
+    }
+
+    fn extra_description(&self) -> &str {
+        r#"As nushell's `error make`, with one more `error_struct` key:
+
+  * `http: record<status: int, headers: record>`
+
+If the error fails the request, the response gets that status and those
+headers rather than a 500, and the error's `msg` is shown to the client."#
+    }
+
+    fn signature(&self) -> Signature {
+        nu_cmd_lang::ErrorMake.signature()
+    }
+
+    fn run(
+        &self,
+        engine_state: &EngineState,
+        stack: &mut Stack,
+        call: &Call,
+        input: PipelineData,
+    ) -> Result<PipelineData, ShellError> {
+        let arg: Option<Value> = call.opt(engine_state, stack, 0)?;
+        let http = match arg.as_ref().and_then(|v| v.as_record().ok()?.get("http")) {
+            Some(http) => {
+                let meta = crate::response::http_response_meta(http.as_record()?);
+                match meta.status {
+                    Some(100..=599) => {}
+                    Some(status) => {
+                        return Err(ShellError::IncorrectValue {
+                            msg: format!("{status} isn't an HTTP status"),
+                            val_span: http.span(),
+                            call_span: call.head,
+                        })
+                    }
+                    None => {
+                        return Err(ShellError::IncorrectValue {
+                            msg: "http needs a status".into(),
+                            val_span: http.span(),
+                            call_span: call.head,
+                        })
+                    }
+                }
+                Some(meta)
+            }
+            None => None,
+        };
+
+        let error = match nu_cmd_lang::ErrorMake.run(engine_state, stack, call, input) {
+            Err(error) => error,
+            ok => return ok,
+        };
+        Err(match http {
+            Some(http) => crate::error_page::HttpError { error, http }.into_shell_error(call.head),
+            None => error,
+        })
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![Example {
+            description: "Answer with a 404",
+            example: r#"error make {msg: "No such user", http: {status: 404}}"#,
+            result: None,
+        }]
+    }
+}
+
 // === .run: parse, compile, and evaluate a nushell pipeline string in a sandbox ===
 
 #[derive(Clone)]
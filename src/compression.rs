@@ -6,6 +6,7 @@ use headers::Header;
 use http_body_util::{combinators::BoxBody, BodyExt, StreamBody};
 use http_encoding_headers::{AcceptEncoding, Encoding};
 use hyper::body::Frame;
+use std::io::Write;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::sync::mpsc;
@@ -14,102 +15,282 @@ use tokio_stream::{Stream, StreamExt};
 
 type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
 
-const BROTLI_QUALITY: i32 = 4;
 const OUTBUF_CAP: usize = 16 * 1024;
 
-/// Check if the request accepts brotli encoding.
+/// Response compression settings, set from the command line.
+#[derive(Clone, Debug)]
+pub struct CompressionConfig {
+    /// Buffered bodies smaller than this are sent uncompressed. Streams are
+    /// always compressed: their size isn't known up front.
+    pub min_size: usize,
+    /// 0-11
+    pub brotli_quality: u32,
+    /// 1-22
+    pub zstd_level: i32,
+    /// 0-9
+    pub gzip_level: u32,
+    /// Content types to compress, as `type/subtype` patterns (see
+    /// [`DEFAULT_TYPES`])
+    pub types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            min_size: 1024,
+            brotli_quality: 4,
+            zstd_level: 3,
+            gzip_level: 6,
+            types: DEFAULT_TYPES.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+}
+
+/// A content coding http-nu can produce.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentCoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl ContentCoding {
+    /// Token for `Content-Encoding` (and ETag suffixes).
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentCoding::Brotli => "br",
+            ContentCoding::Zstd => "zstd",
+            ContentCoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Pick the coding to respond with from `Accept-Encoding`.
 ///
-/// Parses the `Accept-Encoding` header respecting quality values.
-/// Returns `true` only if `br` is present with quality > 0.
+/// The client's quality values win; ties go to br, then zstd, then gzip.
 #[must_use]
-pub fn accepts_brotli(headers: &hyper::header::HeaderMap) -> bool {
-    let Ok(accept) =
-        AcceptEncoding::decode(&mut headers.get_all(hyper::header::ACCEPT_ENCODING).iter())
+pub fn negotiate(headers: &hyper::header::HeaderMap) -> Option<ContentCoding> {
+    let accept =
+        AcceptEncoding::decode(&mut headers.get_all(hyper::header::ACCEPT_ENCODING).iter()).ok()?;
+    let supported = [
+        (&Encoding::Br, 1.0),
+        (&Encoding::Zstd, 0.9),
+        (&Encoding::Gzip, 0.8),
+    ];
+    match accept.preferred_allowed_weighted(supported.into_iter())? {
+        Encoding::Br => Some(ContentCoding::Brotli),
+        Encoding::Zstd => Some(ContentCoding::Zstd),
+        Encoding::Gzip => Some(ContentCoding::Gzip),
+        _ => None,
+    }
+}
+
+/// Content types compressed by default: text-like ones. Images, archives,
+/// video and opaque binary are already compressed, or unknown.
+pub const DEFAULT_TYPES: &[&str] = &[
+    "text/*",
+    "*/*+json",
+    "*/*+xml",
+    "application/json",
+    "application/x-ndjson",
+    "application/javascript",
+    "application/x-javascript",
+    "application/ecmascript",
+    "application/xml",
+    "application/x-yaml",
+    "application/yaml",
+    "application/toml",
+    "application/wasm",
+    "image/x-icon",
+    "font/ttf",
+    "font/otf",
+];
+
+impl CompressionConfig {
+    /// Whether a response with this Content-Type is worth compressing: it
+    /// matches one of `types`. A response without one is compressed, as it
+    /// always has been.
+    #[must_use]
+    pub fn compresses(&self, content_type: Option<&str>) -> bool {
+        let Some(content_type) = content_type else {
+            return true;
+        };
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        self.types
+            .iter()
+            .any(|pattern| mime_matches(&pattern.to_ascii_lowercase(), &mime))
+    }
+}
+
+/// Match a MIME type against `type/subtype`, where either half may be `*`,
+/// and a subtype of `*+suffix` matches any subtype with that suffix.
+fn mime_matches(pattern: &str, mime: &str) -> bool {
+    let (Some((p_type, p_sub)), Some((m_type, m_sub))) =
+        (pattern.split_once('/'), mime.split_once('/'))
     else {
         return false;
     };
-    accept.preferred_allowed([Encoding::Br].iter()).is_some()
+    let type_ok = p_type == "*" || p_type == m_type;
+    let sub_ok = match p_sub.strip_prefix('*') {
+        Some("") => true,
+        Some(suffix) => m_sub.ends_with(suffix),
+        None => p_sub == m_sub,
+    };
+    type_ok && sub_ok
 }
 
-/// A streaming brotli compressor that flushes per chunk.
-pub struct BrotliStream<S> {
-    inner: S,
-    encoder: BrotliEncoderStateStruct<StandardAlloc>,
-    out_scratch: Vec<u8>,
-    tmp: Vec<u8>,
-    finished: bool,
+enum Op {
+    Process,
+    Flush,
+    Finish,
 }
 
-impl<S> BrotliStream<S> {
-    pub fn new(inner: S) -> Self {
-        let params = BrotliEncoderParams {
-            quality: BROTLI_QUALITY,
-            ..Default::default()
-        };
+/// One encoder per coding, driven the same way: feed input, flush at burst
+/// boundaries, finish at the end.
+enum Encoder {
+    Brotli {
+        state: Box<BrotliEncoderStateStruct<StandardAlloc>>,
+        tmp: Vec<u8>,
+    },
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+}
 
-        let mut encoder = BrotliEncoderStateStruct::new(StandardAlloc::default());
-        encoder.params = params;
+impl Encoder {
+    fn new(coding: ContentCoding, config: &CompressionConfig) -> Result<Self, BoxError> {
+        Ok(match coding {
+            ContentCoding::Brotli => {
+                let mut state = BrotliEncoderStateStruct::new(StandardAlloc::default());
+                state.params = BrotliEncoderParams {
+                    quality: config.brotli_quality as i32,
+                    ..Default::default()
+                };
+                Encoder::Brotli {
+                    state: Box::new(state),
+                    tmp: vec![0u8; OUTBUF_CAP],
+                }
+            }
+            ContentCoding::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(
+                Vec::with_capacity(OUTBUF_CAP),
+                config.zstd_level,
+            )?),
+            ContentCoding::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                Vec::with_capacity(OUTBUF_CAP),
+                flate2::Compression::new(config.gzip_level),
+            )),
+        })
+    }
 
-        Self {
-            inner,
-            encoder,
-            out_scratch: Vec::with_capacity(OUTBUF_CAP),
-            tmp: vec![0u8; OUTBUF_CAP],
-            finished: false,
+    /// Run one operation, returning whatever output it produced.
+    fn encode(&mut self, input: &[u8], op: Op) -> Result<Bytes, BoxError> {
+        match self {
+            Encoder::Brotli { state, tmp } => brotli_encode(state, tmp, input, op),
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(input)?;
+                match op {
+                    Op::Process => {}
+                    Op::Flush => encoder.flush()?,
+                    Op::Finish => encoder.do_finish()?,
+                }
+                Ok(Bytes::from(std::mem::take(encoder.get_mut())))
+            }
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(input)?;
+                match op {
+                    Op::Process => {}
+                    Op::Flush => encoder.flush()?,
+                    Op::Finish => encoder.try_finish()?,
+                }
+                Ok(Bytes::from(std::mem::take(encoder.get_mut())))
+            }
         }
     }
+}
 
-    /// Unified Brotli driver for PROCESS/FLUSH/FINISH.
-    fn encode(&mut self, input: &[u8], op: BrotliEncoderOperation) -> Result<Bytes, BoxError> {
-        self.out_scratch.clear();
-        let mut in_offset = 0usize;
+/// Unified Brotli driver for PROCESS/FLUSH/FINISH.
+fn brotli_encode(
+    encoder: &mut BrotliEncoderStateStruct<StandardAlloc>,
+    tmp: &mut [u8],
+    input: &[u8],
+    op: Op,
+) -> Result<Bytes, BoxError> {
+    let op = match op {
+        Op::Process => BrotliEncoderOperation::BROTLI_OPERATION_PROCESS,
+        Op::Flush => BrotliEncoderOperation::BROTLI_OPERATION_FLUSH,
+        Op::Finish => BrotliEncoderOperation::BROTLI_OPERATION_FINISH,
+    };
+    let mut out = Vec::new();
+    let mut in_offset = 0usize;
 
-        loop {
-            let mut avail_in = input.len().saturating_sub(in_offset);
-            let mut avail_out = self.tmp.len();
-            let mut out_offset = 0usize;
-
-            let ok = self.encoder.compress_stream(
-                op,
-                &mut avail_in,
-                &input[in_offset..],
-                &mut in_offset,
-                &mut avail_out,
-                &mut self.tmp,
-                &mut out_offset,
-                &mut None,
-                &mut |_, _, _, _| (),
-            );
-
-            if !ok {
-                return Err("brotli compression failed".into());
-            }
+    loop {
+        let mut avail_in = input.len().saturating_sub(in_offset);
+        let mut avail_out = tmp.len();
+        let mut out_offset = 0usize;
 
-            if out_offset > 0 {
-                self.out_scratch.extend_from_slice(&self.tmp[..out_offset]);
-            }
+        let ok = encoder.compress_stream(
+            op,
+            &mut avail_in,
+            &input[in_offset..],
+            &mut in_offset,
+            &mut avail_out,
+            tmp,
+            &mut out_offset,
+            &mut None,
+            &mut |_, _, _, _| (),
+        );
 
-            let done = match op {
-                BrotliEncoderOperation::BROTLI_OPERATION_FINISH => self.encoder.is_finished(),
-                BrotliEncoderOperation::BROTLI_OPERATION_FLUSH => !self.encoder.has_more_output(),
-                BrotliEncoderOperation::BROTLI_OPERATION_PROCESS => {
-                    in_offset >= input.len() && !self.encoder.has_more_output()
-                }
-                _ => unreachable!("unexpected Brotli operation"),
-            };
+        if !ok {
+            return Err("brotli compression failed".into());
+        }
 
-            if done {
-                break;
+        if out_offset > 0 {
+            out.extend_from_slice(&tmp[..out_offset]);
+        }
+
+        let done = match op {
+            BrotliEncoderOperation::BROTLI_OPERATION_FINISH => encoder.is_finished(),
+            BrotliEncoderOperation::BROTLI_OPERATION_FLUSH => !encoder.has_more_output(),
+            BrotliEncoderOperation::BROTLI_OPERATION_PROCESS => {
+                in_offset >= input.len() && !encoder.has_more_output()
             }
+            _ => unreachable!("unexpected Brotli operation"),
+        };
+
+        if done {
+            break;
         }
+    }
 
-        // Take ownership while preserving capacity for next call
-        let result = std::mem::replace(&mut self.out_scratch, Vec::with_capacity(OUTBUF_CAP));
-        Ok(Bytes::from(result))
+    Ok(Bytes::from(out))
+}
+
+/// A streaming compressor (br, zstd or gzip) that flushes per burst.
+pub struct CompressStream<S> {
+    inner: S,
+    encoder: Encoder,
+    finished: bool,
+}
+
+impl<S> CompressStream<S> {
+    pub fn new(
+        inner: S,
+        coding: ContentCoding,
+        config: &CompressionConfig,
+    ) -> Result<Self, BoxError> {
+        Ok(Self {
+            inner,
+            encoder: Encoder::new(coding, config)?,
+            finished: false,
+        })
     }
 }
 
-impl<S> Stream for BrotliStream<S>
+impl<S> Stream for CompressStream<S>
 where
     S: Stream<Item = Result<Vec<u8>, BoxError>> + Unpin,
 {
@@ -120,10 +301,10 @@ where
             return Poll::Ready(None);
         }
 
-        // Drain ready chunks with PROCESS (lets brotli batch for compression),
-        // then FLUSH once at the boundary -- when the source goes idle, ends,
-        // or the per-poll budget is hit. One brotli sync-point per burst
-        // instead of per chunk.
+        // Drain ready chunks with PROCESS (lets the encoder batch for
+        // compression), then FLUSH once at the boundary -- when the source
+        // goes idle, ends, or the per-poll budget is hit. One sync-point per
+        // burst instead of per chunk.
         const DRAIN_BUDGET: usize = 32;
         let mut accumulated: Vec<u8> = Vec::new();
         let mut drained = 0usize;
@@ -131,7 +312,7 @@ where
         loop {
             match Pin::new(&mut self.inner).poll_next(cx) {
                 Poll::Ready(Some(Ok(chunk))) => {
-                    match self.encode(&chunk, BrotliEncoderOperation::BROTLI_OPERATION_PROCESS) {
+                    match self.encoder.encode(&chunk, Op::Process) {
                         Ok(out) => accumulated.extend_from_slice(&out),
                         Err(e) => return Poll::Ready(Some(Err(e))),
                     }
@@ -139,7 +320,7 @@ where
                     if drained >= DRAIN_BUDGET {
                         // Cap the loop so a chatty source can't starve other
                         // tasks. Flush what we have and yield.
-                        match self.encode(&[], BrotliEncoderOperation::BROTLI_OPERATION_FLUSH) {
+                        match self.encoder.encode(&[], Op::Flush) {
                             Ok(out) => accumulated.extend_from_slice(&out),
                             Err(e) => return Poll::Ready(Some(Err(e))),
                         }
@@ -152,9 +333,11 @@ where
                 // immediately. If nothing is buffered, we're truly Pending --
                 // the inner already registered our waker.
                 Poll::Pending => {
-                    match self.encode(&[], BrotliEncoderOperation::BROTLI_OPERATION_FLUSH) {
-                        Ok(out) => accumulated.extend_from_slice(&out),
-                        Err(e) => return Poll::Ready(Some(Err(e))),
+                    if drained > 0 {
+                        match self.encoder.encode(&[], Op::Flush) {
+                            Ok(out) => accumulated.extend_from_slice(&out),
+                            Err(e) => return Poll::Ready(Some(Err(e))),
+                        }
                     }
                     if accumulated.is_empty() {
                         return Poll::Pending;
@@ -164,8 +347,8 @@ where
 
                 // Inner errored (e.g. SSE cancel): propagate the error, mark
                 // ourselves finished so the next poll yields None. We do NOT
-                // run the brotli FINISH op -- the deliberately truncated body
-                // lets the client see this as a fetch error and auto-retry.
+                // run the FINISH op -- the deliberately truncated body lets
+                // the client see this as a fetch error and auto-retry.
                 Poll::Ready(Some(Err(e))) => {
                     self.finished = true;
                     return Poll::Ready(Some(Err(e)));
@@ -173,7 +356,7 @@ where
 
                 Poll::Ready(None) => {
                     self.finished = true;
-                    match self.encode(&[], BrotliEncoderOperation::BROTLI_OPERATION_FINISH) {
+                    match self.encoder.encode(&[], Op::Finish) {
                         Ok(out) => accumulated.extend_from_slice(&out),
                         Err(e) => return Poll::Ready(Some(Err(e))),
                     }
@@ -187,21 +370,26 @@ where
     }
 }
 
-/// Wrap a streaming response body with brotli compression.
-pub fn compress_stream(rx: mpsc::Receiver<Vec<u8>>) -> BoxBody<Bytes, BoxError> {
+/// Wrap a streaming response body with compression.
+pub fn compress_stream(
+    rx: mpsc::Receiver<Vec<u8>>,
+    coding: ContentCoding,
+    config: &CompressionConfig,
+) -> Result<BoxBody<Bytes, BoxError>, BoxError> {
     let stream = ReceiverStream::new(rx).map(Ok::<Vec<u8>, BoxError>);
-    let brotli_stream = BrotliStream::new(stream);
-    StreamBody::new(brotli_stream).boxed()
+    let compressed = CompressStream::new(stream, coding, config)?;
+    Ok(StreamBody::new(compressed).boxed())
 }
 
 /// Compress an entire body eagerly.
-pub fn compress_full(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
-    let mut output = Vec::new();
-    let params = BrotliEncoderParams {
-        quality: BROTLI_QUALITY,
-        ..Default::default()
-    };
-    brotli::BrotliCompress(&mut &*data, &mut output, &params)?;
+pub fn compress_full(
+    data: &[u8],
+    coding: ContentCoding,
+    config: &CompressionConfig,
+) -> Result<Vec<u8>, BoxError> {
+    let mut encoder = Encoder::new(coding, config)?;
+    let mut output = encoder.encode(data, Op::Process)?.to_vec();
+    output.extend_from_slice(&encoder.encode(&[], Op::Finish)?);
     Ok(output)
 }
 
@@ -210,40 +398,86 @@ mod tests {
     use super::*;
     use hyper::header::{HeaderMap, HeaderValue, ACCEPT_ENCODING};
 
-    #[test]
-    fn test_accepts_brotli_simple() {
+    fn negotiate_for(accept: &str) -> Option<ContentCoding> {
         let mut headers = HeaderMap::new();
-        headers.insert(
-            ACCEPT_ENCODING,
-            HeaderValue::from_static("gzip, deflate, br"),
-        );
-        assert!(accepts_brotli(&headers));
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_str(accept).unwrap());
+        negotiate(&headers)
     }
 
     #[test]
-    fn test_rejects_brotli_quality_zero() {
-        let mut headers = HeaderMap::new();
-        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip, br;q=0"));
-        assert!(!accepts_brotli(&headers));
+    fn test_negotiate_prefers_br_then_zstd_then_gzip() {
+        assert_eq!(
+            negotiate_for("gzip, deflate, br, zstd"),
+            Some(ContentCoding::Brotli)
+        );
+        assert_eq!(negotiate_for("gzip, zstd"), Some(ContentCoding::Zstd));
+        assert_eq!(negotiate_for("gzip, deflate"), Some(ContentCoding::Gzip));
+        assert_eq!(negotiate_for("deflate"), None);
+        assert_eq!(negotiate(&HeaderMap::new()), None);
     }
 
     #[test]
-    fn test_no_brotli() {
-        let mut headers = HeaderMap::new();
-        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip, deflate"));
-        assert!(!accepts_brotli(&headers));
+    fn test_negotiate_respects_client_quality() {
+        assert_eq!(
+            negotiate_for("br;q=0.5, gzip;q=1.0"),
+            Some(ContentCoding::Gzip)
+        );
+        assert_eq!(negotiate_for("br;q=0, gzip;q=0"), None);
+        assert_eq!(
+            negotiate_for("zstd;q=0.8, gzip;q=0.8"),
+            Some(ContentCoding::Zstd)
+        );
+        assert_eq!(negotiate_for("gzip, br;q=0"), Some(ContentCoding::Gzip));
+        // Refusing identity doesn't refuse the codings offered
+        assert_eq!(
+            negotiate_for("identity;q=0, gzip"),
+            Some(ContentCoding::Gzip)
+        );
+        assert_eq!(negotiate_for("identity;q=0"), None);
     }
 
     #[test]
-    fn test_no_accept_encoding_header() {
-        let headers = HeaderMap::new();
-        assert!(!accepts_brotli(&headers));
+    fn test_compresses() {
+        let config = CompressionConfig::default();
+        assert!(config.compresses(Some("text/html; charset=utf-8")));
+        assert!(config.compresses(Some("application/json")));
+        assert!(config.compresses(Some("application/ld+json")));
+        assert!(config.compresses(Some("image/svg+xml")));
+        assert!(!config.compresses(Some("image/png")));
+        assert!(!config.compresses(Some("application/octet-stream")));
+        assert!(!config.compresses(Some("application/zip")));
+        assert!(config.compresses(None));
+
+        let config = CompressionConfig {
+            types: vec!["text/html".into(), "application/*".into()],
+            ..Default::default()
+        };
+        assert!(config.compresses(Some("text/html")));
+        assert!(!config.compresses(Some("text/plain")));
+        assert!(config.compresses(Some("application/octet-stream")));
     }
 
     #[test]
-    fn test_brotli_only() {
-        let mut headers = HeaderMap::new();
-        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("br"));
-        assert!(accepts_brotli(&headers));
+    fn test_compress_full_roundtrip() {
+        use std::io::Read;
+        let data = "hello compression ".repeat(100);
+        let config = CompressionConfig::default();
+
+        let br = compress_full(data.as_bytes(), ContentCoding::Brotli, &config).unwrap();
+        let mut out = String::new();
+        brotli::Decompressor::new(&br[..], 4096)
+            .read_to_string(&mut out)
+            .unwrap();
+        assert_eq!(out, data);
+
+        let zst = compress_full(data.as_bytes(), ContentCoding::Zstd, &config).unwrap();
+        assert_eq!(zstd::decode_all(&zst[..]).unwrap(), data.as_bytes());
+
+        let gz = compress_full(data.as_bytes(), ContentCoding::Gzip, &config).unwrap();
+        let mut out = String::new();
+        flate2::read::GzDecoder::new(&gz[..])
+            .read_to_string(&mut out)
+            .unwrap();
+        assert_eq!(out, data);
     }
 }
@@ -0,0 +1,245 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{Datelike, Utc};
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair,
+    KeyUsagePurpose,
+};
+use x509_parser::extensions::ParsedExtension;
+
+use crate::listener::CertSource;
+
+/// The names the development certificate is valid for
+pub const NAMES: &[&str] = &["localhost", "127.0.0.1", "::1"];
+
+const CA_DAYS: i64 = 10 * 365;
+const LEAF_DAYS: i64 = 365;
+/// A cached certificate this close to expiring is issued again
+const RENEW_DAYS: i64 = 30;
+
+/// A local CA and a certificate it issued for [`NAMES`], as files in one
+/// directory
+pub struct DevCert {
+    /// The CA certificate: the file to trust
+    pub ca: PathBuf,
+    /// The certificate and key to serve
+    pub source: CertSource,
+    /// Whether anything had to be generated, rather than all of it reused
+    pub generated: bool,
+}
+
+/// Where `--tls-self-signed` keeps its certificates: `http-nu/certs` under
+/// `$XDG_STATE_HOME` or `~/.local/state`, or `%LOCALAPPDATA%` on Windows
+pub fn default_dir() -> Option<PathBuf> {
+    let state = if cfg!(windows) {
+        std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_STATE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state"))
+            })
+    };
+    state.map(|dir| dir.join("http-nu").join("certs"))
+}
+
+impl DevCert {
+    /// The CA and certificate in `dir`, created on first use. The CA is kept
+    /// for as long as it's valid, so it only needs trusting once; the
+    /// certificate is issued again when it nears expiry or the CA changes.
+    pub fn ensure(dir: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let cert = Self {
+            ca: dir.join("ca.pem"),
+            source: CertSource {
+                cert: dir.join("localhost.pem"),
+                key: dir.join("localhost-key.pem"),
+            },
+            generated: false,
+        };
+        let ca_key = dir.join("ca-key.pem");
+        let invalid = |path: &Path, e: rcgen::Error| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {e}", path.display()),
+            )
+        };
+
+        let cached_ca = std::fs::read_to_string(&cert.ca)
+            .ok()
+            .zip(std::fs::read_to_string(&ca_key).ok())
+            .filter(|(ca_pem, _)| usable(ca_pem.as_bytes()).is_some());
+        let (ca_pem, ca_key_pem, mut generated) = match cached_ca {
+            Some((ca_pem, ca_key_pem)) => (ca_pem, ca_key_pem, false),
+            None => {
+                let key = KeyPair::generate().map_err(io::Error::other)?;
+                let ca = ca_params().self_signed(&key).map_err(io::Error::other)?;
+                write(&cert.ca, &ca.pem(), false)?;
+                write(&ca_key, &key.serialize_pem(), true)?;
+                (ca.pem(), key.serialize_pem(), true)
+            }
+        };
+
+        let ca_id = usable(ca_pem.as_bytes()).and_then(|ids| ids.subject);
+        let leaf_ok = std::fs::read(&cert.source.cert)
+            .ok()
+            .and_then(|pem| usable(&pem))
+            .is_some_and(|ids| ids.authority.is_some() && ids.authority == ca_id)
+            && cert.source.key.exists();
+        if !leaf_ok {
+            let ca_key = KeyPair::from_pem(&ca_key_pem).map_err(|e| invalid(&ca_key, e))?;
+            let issuer =
+                Issuer::from_ca_cert_pem(&ca_pem, ca_key).map_err(|e| invalid(&cert.ca, e))?;
+            let key = KeyPair::generate().map_err(io::Error::other)?;
+            let leaf = leaf_params()?
+                .signed_by(&key, &issuer)
+                .map_err(io::Error::other)?;
+            write(&cert.source.cert, &leaf.pem(), false)?;
+            write(&cert.source.key, &key.serialize_pem(), true)?;
+            generated = true;
+        }
+
+        Ok(Self { generated, ..cert })
+    }
+}
+
+fn ca_params() -> CertificateParams {
+    let mut params = CertificateParams::default();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "http-nu development CA");
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    valid_for(&mut params, CA_DAYS);
+    params
+}
+
+fn leaf_params() -> io::Result<CertificateParams> {
+    let names = NAMES
+        .iter()
+        .map(|name| name.to_string())
+        .collect::<Vec<_>>();
+    let mut params = CertificateParams::new(names).map_err(io::Error::other)?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "http-nu development certificate");
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    params.use_authority_key_identifier_extension = true;
+    valid_for(&mut params, LEAF_DAYS);
+    Ok(params)
+}
+
+/// Valid from yesterday, to allow for clock skew, until `days` from now
+fn valid_for(params: &mut CertificateParams, days: i64) {
+    let date = |days| {
+        let date = (Utc::now() + chrono::Duration::days(days)).date_naive();
+        rcgen::date_time_ymd(date.year(), date.month() as u8, date.day() as u8)
+    };
+    params.not_before = date(-1);
+    params.not_after = date(days);
+}
+
+struct KeyIds {
+    subject: Option<Vec<u8>>,
+    authority: Option<Vec<u8>>,
+}
+
+/// A cached certificate's key identifiers, or `None` if it can't be read or
+/// is about to expire
+fn usable(pem: &[u8]) -> Option<KeyIds> {
+    let (_, pem) = x509_parser::pem::parse_x509_pem(pem).ok()?;
+    let cert = pem.parse_x509().ok()?;
+    let renew_at = Utc::now() + chrono::Duration::days(RENEW_DAYS);
+    if cert.validity().not_after.timestamp() < renew_at.timestamp() {
+        return None;
+    }
+    let mut ids = KeyIds {
+        subject: None,
+        authority: None,
+    };
+    for ext in cert.extensions() {
+        match ext.parsed_extension() {
+            ParsedExtension::SubjectKeyIdentifier(id) => ids.subject = Some(id.0.to_vec()),
+            ParsedExtension::AuthorityKeyIdentifier(aki) => {
+                ids.authority = aki.key_identifier.as_ref().map(|id| id.0.to_vec())
+            }
+            _ => {}
+        }
+    }
+    Some(ids)
+}
+
+/// Write `contents` to `path`, readable only by the owner if it's a key
+fn write(path: &Path, contents: &str, private: bool) -> io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    io::Write::write_all(&mut options.open(path)?, contents.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::client::danger::ServerCertVerifier;
+    use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+
+    /// Check the served certificate chains to the CA for each of [`NAMES`]
+    fn verify(cert: &DevCert) {
+        let read_der = |path: &Path| {
+            let pem = std::fs::read(path).unwrap();
+            let (_, pem) = x509_parser::pem::parse_x509_pem(&pem).unwrap();
+            CertificateDer::from(pem.contents)
+        };
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(read_der(&cert.ca)).unwrap();
+        let verifier = rustls::client::WebPkiServerVerifier::builder_with_provider(
+            roots.into(),
+            rustls::crypto::aws_lc_rs::default_provider().into(),
+        )
+        .build()
+        .unwrap();
+        let leaf = read_der(&cert.source.cert);
+        for name in NAMES {
+            let name = ServerName::try_from(*name).unwrap();
+            verifier
+                .verify_server_cert(&leaf, &[], &name, &[], UnixTime::now())
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_dev_cert() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = DevCert::ensure(dir.path()).unwrap();
+        assert!(first.generated);
+        verify(&first);
+
+        // Cached: nothing changes
+        let ca = std::fs::read(&first.ca).unwrap();
+        let leaf = std::fs::read(&first.source.cert).unwrap();
+        let again = DevCert::ensure(dir.path()).unwrap();
+        assert!(!again.generated);
+        assert_eq!(std::fs::read(&again.ca).unwrap(), ca);
+        assert_eq!(std::fs::read(&again.source.cert).unwrap(), leaf);
+
+        // A new CA gets a new certificate to go with it
+        std::fs::remove_file(&first.ca).unwrap();
+        let renewed = DevCert::ensure(dir.path()).unwrap();
+        assert!(renewed.generated);
+        assert_ne!(std::fs::read(&renewed.source.cert).unwrap(), leaf);
+        verify(&renewed);
+    }
+}
@@ -21,12 +21,15 @@ use nu_protocol::{
 
 use crate::bus::Bus;
 use crate::commands::{
-    BusPubCommand, BusSubCommand, HighlightCommand, HighlightLangCommand, HighlightThemeCommand,
-    MdCommand, MjCommand, MjCompileCommand, MjRenderCommand, PrintCommand, ReverseProxyCommand,
-    RunNuCommand, StaticCommand, ToSse,
+    AssetUrlCommand, BodyLimitCommand, BusPubCommand, BusSubCommand, ErrorMakeCommand,
+    FromMultipartCommand, HighlightCommand, HighlightLangCommand, HighlightThemeCommand, MdCommand,
+    MjCommand, MjCompileCommand, MjRenderCommand, PrintCommand, ReverseProxyCommand, RunNuCommand,
+    StaticCommand, ToSse, WebSocketCommand,
 };
+use crate::error_page::ScriptError;
 use crate::logging::log_error;
 use crate::stdlib::load_http_nu_stdlib;
+use crate::upstream::Upstreams;
 use crate::Error;
 
 /// CLI options exposed to scripts as the `$HTTP_NU` const
@@ -46,10 +49,15 @@ pub struct HttpNuOptions {
 pub struct Engine {
     pub state: EngineState,
     pub closure: Option<Closure>,
+    /// `on_error` from a `{handler, on_error}` script: answers for a
+    /// closure that failed
+    pub error_handler: Option<Closure>,
     /// Local in-process pub/sub bus for ephemeral UI events
     pub bus: Arc<Bus>,
     /// Cancellation token for SSE streams
     pub sse_cancel_token: CancellationToken,
+    /// `.reverse-proxy` upstream pools: balancing, ejection and health state
+    pub upstreams: Arc<Upstreams>,
 }
 
 impl Engine {
@@ -69,8 +77,10 @@ impl Engine {
         Ok(Self {
             state: engine_state,
             closure: None,
+            error_handler: None,
             bus: Arc::new(Bus::new(64)),
             sse_cancel_token: CancellationToken::new(),
+            upstreams: Arc::new(Upstreams::default()),
         })
     }
 
@@ -198,20 +208,43 @@ impl Engine {
             Error::from(format_cli_error(None, &working_set, &err, None))
         })?;
 
-        let closure = result
-            .body
-            .into_value(Span::unknown())
-            .map_err(|err| {
-                let working_set = StateWorkingSet::new(&self.state);
-                Error::from(format_cli_error(None, &working_set, &err, None))
-            })?
-            .into_closure()
-            .map_err(|err| {
-                let working_set = StateWorkingSet::new(&self.state);
-                Error::from(format_cli_error(None, &working_set, &err, None))
-            })?;
-
-        // Verify closure accepts exactly one argument
+        let value = result.body.into_value(Span::unknown()).map_err(|err| {
+            let working_set = StateWorkingSet::new(&self.state);
+            Error::from(format_cli_error(None, &working_set, &err, None))
+        })?;
+
+        // Either the handler closure, or `{handler, on_error}`
+        let (closure, error_handler) = match value {
+            Value::Record { val, .. } => {
+                if let Some(key) = val
+                    .columns()
+                    .find(|key| !matches!(key.as_str(), "handler" | "on_error"))
+                {
+                    return Err(format!(
+                        "Unknown field '{key}': the script's record takes handler and on_error"
+                    )
+                    .into());
+                }
+                let field = |name: &str| -> Result<Option<Closure>, Error> {
+                    val.get(name)
+                        .map(|v| v.clone().into_closure())
+                        .transpose()
+                        .map_err(|_| format!("'{name}' must be a closure").into())
+                };
+                let closure =
+                    field("handler")?.ok_or("The script's record needs a handler closure")?;
+                (closure, field("on_error")?)
+            }
+            value => {
+                let closure = value.into_closure().map_err(|err| {
+                    let working_set = StateWorkingSet::new(&self.state);
+                    Error::from(format_cli_error(None, &working_set, &err, None))
+                })?;
+                (closure, None)
+            }
+        };
+
+        // Verify closures accept exactly one argument
         let block = self.state.get_block(closure.block_id);
         if block.signature.required_positional.len() != 1 {
             return Err(format!(
@@ -220,10 +253,21 @@ impl Engine {
             )
             .into());
         }
+        if let Some(on_error) = &error_handler {
+            let block = self.state.get_block(on_error.block_id);
+            if block.signature.required_positional.len() != 1 {
+                return Err(format!(
+                    "on_error must accept exactly one argument, found {}",
+                    block.signature.required_positional.len()
+                )
+                .into());
+            }
+        }
 
         self.state.merge_env(&mut stack)?;
 
         self.closure = Some(closure);
+        self.error_handler = error_handler;
         Ok(())
     }
 
@@ -318,7 +362,22 @@ impl Engine {
         pipeline_data: PipelineData,
     ) -> Result<PipelineData, Error> {
         let closure = self.closure.as_ref().ok_or("Closure not parsed")?;
+        self.run(closure, input, pipeline_data)
+    }
 
+    /// Run `on_error` with its `{request, error, phase}` record. Errors if
+    /// the script didn't set one.
+    pub fn run_error_handler(&self, input: Value) -> Result<PipelineData, Error> {
+        let closure = self.error_handler.as_ref().ok_or("No error handler")?;
+        self.run(closure, input, PipelineData::empty())
+    }
+
+    fn run(
+        &self,
+        closure: &Closure,
+        input: Value,
+        pipeline_data: PipelineData,
+    ) -> Result<PipelineData, Error> {
         let mut stack = Stack::new().captures_to_stack(closure.captures.clone());
         let mut stack =
             stack.push_redirection(Some(Redirection::Pipe(OutDest::PipeSeparate)), None);
@@ -331,10 +390,7 @@ impl Engine {
 
         eval_block_with_early_return::<WithoutDebug>(&self.state, &mut stack, block, pipeline_data)
             .map(|exec_data| exec_data.body)
-            .map_err(|err| {
-                let working_set = StateWorkingSet::new(&self.state);
-                Error::from(format_cli_error(None, &working_set, &err, None))
-            })
+            .map_err(|err| Error::from(ScriptError::from_shell_error(&self.state, &err)))
     }
 
     /// Adds http-nu custom commands to the engine
@@ -342,6 +398,10 @@ impl Engine {
         self.add_commands(vec![
             Box::new(ReverseProxyCommand::new()),
             Box::new(StaticCommand::new()),
+            Box::new(AssetUrlCommand::new()),
+            Box::new(WebSocketCommand::new()),
+            Box::new(BodyLimitCommand::new()),
+            Box::new(FromMultipartCommand::new()),
             Box::new(ToSse {}),
             Box::new(MjCommand::new()),
             Box::new(MjCompileCommand::new()),
@@ -351,6 +411,7 @@ impl Engine {
             Box::new(HighlightLangCommand::new()),
             Box::new(MdCommand::new()),
             Box::new(PrintCommand::new()),
+            Box::new(ErrorMakeCommand::new()),
             Box::new(RunNuCommand::new()),
             Box::new(BusPubCommand::new(self.bus.clone())),
             Box::new(BusSubCommand::new(self.bus.clone())),
@@ -389,6 +450,14 @@ impl Engine {
             Box::new(MjCompileCommand::with_store(store.clone())),
         ])
     }
+
+    /// Re-registers `from multipart` with store access for `--cas`
+    #[cfg(feature = "cross-stream")]
+    pub fn add_store_multipart_command(&mut self, store: &xs::store::Store) -> Result<(), Error> {
+        self.add_commands(vec![Box::new(FromMultipartCommand::with_store(
+            store.clone(),
+        ))])
+    }
 }
 
 /// Creates an engine from a script by cloning a base engine and parsing the closure.
@@ -397,6 +466,8 @@ pub fn script_to_engine(base: &Engine, script: &str, file: Option<&Path>) -> Opt
     let mut engine = base.clone();
     // Fresh cancellation token for this engine instance
     engine.sse_cancel_token = CancellationToken::new();
+    // Health checks stop with the old token, so pools start over too
+    engine.upstreams = Arc::new(Upstreams::default());
 
     if let Err(e) = engine.parse_closure(script, file) {
         log_error(&nu_utils::strip_ansi_string_likely(e.to_string()));
@@ -0,0 +1,557 @@
+use std::fmt::Write;
+
+use hyper::header::HeaderValue;
+use miette::Diagnostic;
+use nu_protocol::engine::{EngineState, StateWorkingSet};
+use nu_protocol::shell_error::generic::GenericError;
+use nu_protocol::{format_cli_error, ShellError, Span, Value};
+
+use crate::response::{value_to_json, HttpResponseMeta};
+
+/// Lines of source shown either side of a span on the `--dev` page
+const CONTEXT_LINES: usize = 2;
+
+/// A failed closure, kept structured until it's rendered for the client
+#[derive(Debug)]
+pub struct ScriptError {
+    /// One-line summary, e.g. `Division by zero.`
+    pub message: String,
+    /// The full report as printed to the terminal
+    pub report: String,
+    /// Source locations named by the error, outermost first
+    pub frames: Vec<Frame>,
+    /// From `error make {http: ...}`: the response the script asked for
+    pub http: HttpResponseMeta,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub file: String,
+    /// 1-based
+    pub line: usize,
+    /// 1-based, in characters
+    pub column: usize,
+    /// Characters underlined on `line`
+    pub width: usize,
+    pub label: Option<String>,
+    /// Numbered source lines around `line`
+    pub excerpt: Vec<(usize, String)>,
+}
+
+impl ScriptError {
+    pub fn from_shell_error(engine_state: &EngineState, err: &ShellError) -> Self {
+        let working_set = StateWorkingSet::new(engine_state);
+        let shown = Shown::new(err);
+        let mut frames = Vec::new();
+        collect_frames(engine_state, &shown, &mut frames);
+        // `each` and friends wrap the error that stopped them: its message
+        // is the one worth showing
+        let mut cause = err;
+        while let ShellError::EvalBlockWithInput { sources, .. } = cause {
+            match sources.first() {
+                Some(source) => cause = source,
+                None => break,
+            }
+        }
+        Self {
+            message: cause.to_string(),
+            report: format_cli_error(None, &working_set, &shown, None),
+            frames,
+            http: http_meta(err).unwrap_or_default(),
+        }
+    }
+
+    /// An error with no source to point at: a panic, or a failure outside
+    /// the closure
+    pub fn message(message: impl Into<String>) -> Self {
+        let message = message.into();
+        Self {
+            report: message.clone(),
+            message,
+            frames: Vec::new(),
+            http: HttpResponseMeta::default(),
+        }
+    }
+
+    /// The response status: 500 unless the script asked for another
+    pub fn status(&self) -> u16 {
+        self.http.status.unwrap_or(500)
+    }
+
+    /// Whether the script chose to fail with this status, making the
+    /// message meant for the client
+    fn intended(&self) -> bool {
+        self.http.status.is_some()
+    }
+
+    /// `{message, status, report, frames}`, as `on_error` receives it. `frames` are
+    /// `{file, line, column, label, source}`, `source` being the line itself.
+    pub fn to_value(&self, span: Span) -> Value {
+        let frames = self
+            .frames
+            .iter()
+            .map(|frame| {
+                let source = frame
+                    .excerpt
+                    .iter()
+                    .find(|(n, _)| *n == frame.line)
+                    .map_or_else(
+                        || Value::nothing(span),
+                        |(_, text)| Value::string(text, span),
+                    );
+                Value::record(
+                    nu_protocol::record! {
+                        "file" => Value::string(&frame.file, span),
+                        "line" => Value::int(frame.line as i64, span),
+                        "column" => Value::int(frame.column as i64, span),
+                        "label" => frame
+                            .label
+                            .as_ref()
+                            .map_or_else(|| Value::nothing(span), |l| Value::string(l, span)),
+                        "source" => source,
+                    },
+                    span,
+                )
+            })
+            .collect();
+        Value::record(
+            nu_protocol::record! {
+                "message" => Value::string(&self.message, span),
+                "status" => Value::int(self.status().into(), span),
+                "report" => Value::string(nu_utils::strip_ansi_string_likely(self.report.clone()), span),
+                "frames" => Value::list(frames, span),
+            },
+            span,
+        )
+    }
+}
+
+impl std::fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.report)
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// An error from `error make {http: ...}`: the error as nushell's
+/// `error make` built it, and the response the script asked for
+#[derive(Debug)]
+pub struct HttpError {
+    pub error: ShellError,
+    pub http: HttpResponseMeta,
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/// Holds an [`HttpError`] as a `ShellError`'s source. Nushell only hands
+/// back a source's own source, so this wrapper is what gets attached.
+#[derive(Debug)]
+struct Carrier(HttpError);
+
+impl std::fmt::Display for Carrier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl std::error::Error for Carrier {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+impl HttpError {
+    /// A `ShellError` carrying this one, so the response travels with it
+    /// through `try`/`catch` and `inner` errors
+    pub fn into_shell_error(self, span: Span) -> ShellError {
+        let msg = self.error.to_string();
+        ShellError::Generic(GenericError::new(msg, "", span).with_source(Carrier(self)))
+    }
+
+    /// The `HttpError` `diagnostic` carries, if it's one made by
+    /// [`into_shell_error`](Self::into_shell_error)
+    fn carried_by(diagnostic: &dyn Diagnostic) -> Option<&HttpError> {
+        diagnostic
+            .diagnostic_source()?
+            .source()?
+            .downcast_ref::<HttpError>()
+    }
+}
+
+/// The response asked for by the first [`HttpError`] in `diagnostic`,
+/// looking through whatever wraps it, outermost first
+fn http_meta(diagnostic: &dyn Diagnostic) -> Option<HttpResponseMeta> {
+    if let Some(http) = HttpError::carried_by(diagnostic) {
+        return Some(http.http.clone());
+    }
+    diagnostic
+        .related()
+        .into_iter()
+        .flatten()
+        .find_map(http_meta)
+        .or_else(|| diagnostic.diagnostic_source().and_then(http_meta))
+}
+
+/// A diagnostic as the script made it: anything carrying an [`HttpError`]
+/// is shown as the error `error make` built, so the carrier never appears in
+/// reports or frames
+struct Shown<'a> {
+    diagnostic: &'a dyn Diagnostic,
+    related: Vec<Shown<'a>>,
+    source: Option<Box<Shown<'a>>>,
+}
+
+impl<'a> Shown<'a> {
+    fn new(diagnostic: &'a dyn Diagnostic) -> Self {
+        let diagnostic = match HttpError::carried_by(diagnostic) {
+            Some(http) => &http.error,
+            None => diagnostic,
+        };
+        Self {
+            diagnostic,
+            related: diagnostic
+                .related()
+                .into_iter()
+                .flatten()
+                .map(Shown::new)
+                .collect(),
+            source: diagnostic
+                .diagnostic_source()
+                .map(|source| Box::new(Shown::new(source))),
+        }
+    }
+}
+
+impl std::fmt::Debug for Shown<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self.diagnostic, f)
+    }
+}
+
+impl std::fmt::Display for Shown<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self.diagnostic, f)
+    }
+}
+
+impl std::error::Error for Shown<'_> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self.source {
+            Some(_) => None,
+            None => self.diagnostic.source(),
+        }
+    }
+}
+
+impl Diagnostic for Shown<'_> {
+    fn code<'b>(&'b self) -> Option<Box<dyn std::fmt::Display + 'b>> {
+        self.diagnostic.code()
+    }
+
+    fn severity(&self) -> Option<miette::Severity> {
+        self.diagnostic.severity()
+    }
+
+    fn help<'b>(&'b self) -> Option<Box<dyn std::fmt::Display + 'b>> {
+        self.diagnostic.help()
+    }
+
+    fn url<'b>(&'b self) -> Option<Box<dyn std::fmt::Display + 'b>> {
+        self.diagnostic.url()
+    }
+
+    fn source_code(&self) -> Option<&dyn miette::SourceCode> {
+        self.diagnostic.source_code()
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = miette::LabeledSpan> + '_>> {
+        self.diagnostic.labels()
+    }
+
+    fn related<'b>(&'b self) -> Option<Box<dyn Iterator<Item = &'b dyn Diagnostic> + 'b>> {
+        if self.related.is_empty() {
+            return None;
+        }
+        Some(Box::new(
+            self.related
+                .iter()
+                .map(|related| related as &dyn Diagnostic),
+        ))
+    }
+
+    fn diagnostic_source(&self) -> Option<&dyn Diagnostic> {
+        self.source
+            .as_deref()
+            .map(|source| source as &dyn Diagnostic)
+    }
+}
+
+/// Labels of `diagnostic` and everything it wraps, outermost first
+fn collect_frames(
+    engine_state: &EngineState,
+    diagnostic: &dyn Diagnostic,
+    frames: &mut Vec<Frame>,
+) {
+    for label in diagnostic.labels().into_iter().flatten() {
+        if let Some(frame) = frame_at(engine_state, label.offset(), label.len(), label.label()) {
+            if !frames.contains(&frame) {
+                frames.push(frame);
+            }
+        }
+    }
+    for related in diagnostic.related().into_iter().flatten() {
+        collect_frames(engine_state, related, frames);
+    }
+    if let Some(source) = diagnostic.diagnostic_source() {
+        collect_frames(engine_state, source, frames);
+    }
+}
+
+fn frame_at(
+    engine_state: &EngineState,
+    offset: usize,
+    len: usize,
+    label: Option<&str>,
+) -> Option<Frame> {
+    let file = engine_state
+        .files()
+        .find(|f| f.covered_span.start <= offset && offset < f.covered_span.end)?;
+    let content = String::from_utf8_lossy(&file.content);
+    let at = (offset - file.covered_span.start).min(content.len());
+    let before = content.get(..at)?;
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let column = before[line_start..].chars().count() + 1;
+
+    let lines: Vec<&str> = content.lines().collect();
+    let line_len = lines.get(line - 1).map_or(0, |l| l.chars().count());
+    let first = line.saturating_sub(CONTEXT_LINES).max(1);
+    let last = (line + CONTEXT_LINES).min(lines.len());
+    Some(Frame {
+        file: file.name.to_string(),
+        line,
+        column,
+        // A span running past the line is cut at its end
+        width: len.clamp(1, line_len.saturating_sub(column - 1).max(1)),
+        label: label.map(str::to_string),
+        excerpt: (first..=last)
+            .map(|n| (n, lines[n - 1].to_string()))
+            .collect(),
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Html,
+    Json,
+    Text,
+}
+
+/// Pick a format from `Accept`. Browsers get HTML and API clients JSON;
+/// anyone who only sends wildcards (curl) gets plain text.
+fn negotiate(accept: Option<&HeaderValue>) -> Format {
+    let Some(accept) = accept.and_then(|v| v.to_str().ok()) else {
+        return Format::Text;
+    };
+    let ranges: Vec<(&str, f32)> = accept
+        .split(',')
+        .map(|range| {
+            let mut params = range.split(';');
+            let media = params.next().unwrap_or_default().trim();
+            let q = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            (media, q)
+        })
+        .collect();
+    // (q, specificity) of the best range matching `media`
+    let score = |media: &str| {
+        let major = media.split('/').next().unwrap_or_default();
+        ranges
+            .iter()
+            .filter_map(|&(range, q)| {
+                if range.eq_ignore_ascii_case(media) {
+                    Some((q, 2))
+                } else if range.strip_suffix("/*") == Some(major) {
+                    Some((q, 1))
+                } else if range == "*/*" {
+                    Some((q, 0))
+                } else {
+                    None
+                }
+            })
+            .max_by_key(|&(_, specificity)| specificity)
+    };
+    let mut best = (Format::Text, 0.0, 0);
+    for (format, media) in [
+        (Format::Html, "text/html"),
+        (Format::Json, "application/json"),
+        (Format::Json, "application/problem+json"),
+        (Format::Text, "text/plain"),
+    ] {
+        if let Some((q, specificity)) = score(media) {
+            if q > best.1 || (q == best.1 && specificity > best.2) {
+                best = (format, q, specificity);
+            }
+        }
+    }
+    if best.2 == 0 {
+        Format::Text
+    } else {
+        best.0
+    }
+}
+
+/// The body for a failed request, as `(content type, body)`. Under `--dev`
+/// it carries the source excerpts, the full report and the request record.
+/// Otherwise it says only that the script failed, unless the script chose
+/// the status, in which case its message is shown. JSON bodies are
+/// `application/problem+json` (RFC 9457).
+pub fn render(
+    err: &ScriptError,
+    dev: bool,
+    accept: Option<&HeaderValue>,
+    request: Option<&Value>,
+) -> (&'static str, Vec<u8>) {
+    let report = nu_utils::strip_ansi_string_likely(err.report.clone());
+    let status = err.status();
+    let reason = hyper::StatusCode::from_u16(status)
+        .ok()
+        .and_then(|s| s.canonical_reason())
+        .unwrap_or("Error");
+    let title = format!("{status} {reason}");
+    match (negotiate(accept), dev) {
+        (Format::Text, false) if err.intended() => (
+            "text/plain; charset=utf-8",
+            format!("{}\n", err.message).into_bytes(),
+        ),
+        (Format::Text, false) => ("text/plain; charset=utf-8", b"Script error\n".to_vec()),
+        (Format::Text, true) => (
+            "text/plain; charset=utf-8",
+            format!("Script error: {report}").into_bytes(),
+        ),
+        (Format::Json, dev) => {
+            let mut body = serde_json::json!({
+                "type": "about:blank",
+                "title": reason,
+                "status": status,
+                "detail": if dev || err.intended() { err.message.as_str() } else { "Script error" },
+            });
+            if dev {
+                let error = value_to_json(&err.to_value(Span::unknown()));
+                body["report"] = report.into();
+                body["frames"] = error["frames"].clone();
+                body["request"] = request.map(value_to_json).into();
+            }
+            ("application/problem+json", body.to_string().into_bytes())
+        }
+        (Format::Html, false) if err.intended() => (
+            "text/html; charset=utf-8",
+            page(
+                &title,
+                &format!(
+                    "<h1>{}</h1>\n<p class=\"message\">{}</p>\n",
+                    v_htmlescape::escape(&title),
+                    v_htmlescape::escape(&err.message)
+                ),
+            )
+            .into_bytes(),
+        ),
+        (Format::Html, false) => (
+            "text/html; charset=utf-8",
+            page(&title, "<h1>Script error</h1>\n").into_bytes(),
+        ),
+        (Format::Html, true) => {
+            let mut html = format!(
+                "<h1>Script error</h1>\n<p class=\"message\">{}</p>\n",
+                v_htmlescape::escape(&err.message)
+            );
+            for frame in &err.frames {
+                html.push_str(&frame_html(frame));
+            }
+            let _ = write!(
+                html,
+                "<h2>Report</h2>\n<pre>{}</pre>\n",
+                v_htmlescape::escape(&report)
+            );
+            if let Some(request) = request {
+                let json =
+                    serde_json::to_string_pretty(&value_to_json(request)).unwrap_or_default();
+                let _ = write!(
+                    html,
+                    "<h2>Request</h2>\n<pre>{}</pre>\n",
+                    v_htmlescape::escape(&json)
+                );
+            }
+            ("text/html; charset=utf-8", page(&title, &html).into_bytes())
+        }
+    }
+}
+
+fn frame_html(frame: &Frame) -> String {
+    let mut html = format!(
+        "<section>\n<h2>{}:{}:{}</h2>\n<pre>",
+        v_htmlescape::escape(&frame.file),
+        frame.line,
+        frame.column
+    );
+    let gutter = frame.excerpt.last().map_or(1, |(n, _)| n.to_string().len());
+    for (n, text) in &frame.excerpt {
+        let class = if *n == frame.line {
+            " class=\"hit\""
+        } else {
+            ""
+        };
+        let _ = writeln!(
+            html,
+            "<span{class}>{n:>gutter$} | {}</span>",
+            v_htmlescape::escape(text)
+        );
+        if *n == frame.line {
+            let _ = writeln!(
+                html,
+                "<span class=\"caret\">{:gutter$} | {}{} {}</span>",
+                "",
+                " ".repeat(frame.column - 1),
+                "^".repeat(frame.width),
+                v_htmlescape::escape(frame.label.as_deref().unwrap_or_default())
+            );
+        }
+    }
+    html.push_str("</pre>\n</section>\n");
+    html
+}
+
+fn page(title: &str, body: &str) -> String {
+    let title = v_htmlescape::escape(title);
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n<style>\nbody {{ font-family: system-ui, sans-serif; margin: 2rem; }}\npre {{ background: #f6f6f6; padding: 1rem; overflow-x: auto; }}\n.message {{ font-size: 1.2rem; }}\n.hit {{ font-weight: bold; }}\n.caret {{ color: #c00; }}\n</style>\n</head>\n<body>\n{body}</body>\n</html>\n"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let accept = |s: &'static str| negotiate(Some(&HeaderValue::from_static(s)));
+        assert_eq!(negotiate(None), Format::Text);
+        assert_eq!(accept("*/*"), Format::Text);
+        assert_eq!(
+            accept("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+            Format::Html
+        );
+        assert_eq!(accept("application/json, text/plain, */*"), Format::Json);
+        assert_eq!(accept("application/json;q=0.5, text/html"), Format::Html);
+        assert_eq!(accept("text/*"), Format::Html);
+    }
+}
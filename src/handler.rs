@@ -9,15 +9,18 @@ use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full, StreamBody};
 use hyper::body::{Bytes, Frame};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::sync::CancellationToken;
-use tower::Service;
-use tower_http::services::{ServeDir, ServeFile};
 
 use nu_protocol::shell_error::generic::GenericError;
 
 use crate::compression;
-use crate::logging::{log_request, log_response, LoggingBody, RequestGuard};
-use crate::request::{resolve_trusted_ip, Request};
-use crate::response::{Response, ResponseBodyType, ResponseTransport};
+use crate::error_page::ScriptError;
+use crate::listener::TlsInfo;
+use crate::logging::{log_error, log_request, log_response, LoggingBody, RequestGuard};
+use crate::proxy::{is_upgrade_request, ProxyClient, ProxyError};
+use crate::request::{
+    is_trusted_peer, resolve_trusted_ip, resolve_trusted_origin, BodyLimits, BodyTimeout, Request,
+};
+use crate::response::{Response, ResponseBodyType, ResponseTransport, UpstreamResponse};
 use crate::worker::{spawn_eval_thread, PipelineResult};
 
 type BoxError = Box<dyn std::error::Error + Send + Sync>;
@@ -27,10 +30,20 @@ const DATASTAR_JS_PATH: &str = "/datastar@1.0.2.js";
 const DATASTAR_JS: &[u8] = include_bytes!("stdlib/datastar/datastar@1.0.2.js");
 const DATASTAR_JS_BROTLI: &[u8] = include_bytes!("stdlib/datastar/datastar@1.0.2.js.br");
 
+#[derive(Clone)]
 pub struct AppConfig {
     pub trusted_proxies: Vec<ipnet::IpNet>,
     pub datastar: bool,
     pub dev: bool,
+    /// Default request body size limit; `.body-limit` overrides per request
+    pub max_body_size: Option<u64>,
+    /// Default time allowed to receive the request body
+    pub body_timeout: Option<std::time::Duration>,
+    /// Add a strong ETag to buffered (Full) responses
+    pub etag: bool,
+    pub compression: compression::CompressionConfig,
+    /// Shared upstream client for `.reverse-proxy`
+    pub proxy: ProxyClient,
 }
 
 pub async fn handle<B>(
@@ -46,20 +59,58 @@ where
 {
     // Load current engine snapshot - lock-free atomic operation
     let engine = engine.load_full();
+    let accept = req.headers().get(hyper::header::ACCEPT).cloned();
+    let dev = config.dev;
     match handle_inner(engine, addr, config, req).await {
         Ok(response) => Ok(response),
         Err(err) => {
             eprintln!("Error handling request: {err}");
-            let response = hyper::Response::builder().status(500).body(
-                Full::new(format!("Script error: {err}").into())
-                    .map_err(|never| match never {})
-                    .boxed(),
-            )?;
+            let err = ScriptError::message(err.to_string());
+            let (content_type, body) = crate::error_page::render(&err, dev, accept.as_ref(), None);
+            let response = hyper::Response::builder()
+                .status(500)
+                .header(hyper::header::CONTENT_TYPE, content_type)
+                .body(
+                    Full::new(body.into())
+                        .map_err(|never| match never {})
+                        .boxed(),
+                )?;
             Ok(response)
         }
     }
 }
 
+/// 308 to the same host, path and query over HTTPS on `port`: the answer to
+/// every request on a plain listener under --redirect-https
+pub fn redirect_to_https<B>(req: &hyper::Request<B>, port: u16) -> HTTPResult {
+    let host = req
+        .headers()
+        .get(hyper::header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<hyper::http::uri::Authority>().ok())
+        .or_else(|| req.uri().authority().cloned());
+    let Some(host) = host else {
+        return Ok(hyper::Response::builder().status(400).body(
+            Full::new(Bytes::from("Missing Host header"))
+                .map_err(|never| match never {})
+                .boxed(),
+        )?);
+    };
+    let port = if port == 443 {
+        String::new()
+    } else {
+        format!(":{port}")
+    };
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    Ok(hyper::Response::builder()
+        .status(308)
+        .header(
+            hyper::header::LOCATION,
+            format!("https://{}{port}{path}", host.host()),
+        )
+        .body(Empty::new().map_err(|never| match never {}).boxed())?)
+}
+
 async fn handle_inner<B>(
     engine: Arc<crate::Engine>,
     addr: Option<SocketAddr>,
@@ -71,14 +122,39 @@ where
     B::Data: Into<Bytes> + Clone + Send,
     B::Error: Into<BoxError> + Send,
 {
-    let (parts, mut body) = req.into_parts();
+    let (mut parts, mut body) = req.into_parts();
+
+    // Claimed here so `.websocket` can complete the upgrade after the closure runs
+    let on_upgrade = parts.extensions.remove::<hyper::upgrade::OnUpgrade>();
+
+    // Shared with the eval thread so `.body-limit` can adjust them
+    let body_limits = Arc::new(BodyLimits::new(config.max_body_size, config.body_timeout));
+
+    // Exact for a Content-Length body or one known to be empty
+    let known_size = body.size_hint().exact();
 
     // Create channels for request body streaming
     let (body_tx, mut body_rx) = tokio::sync::mpsc::channel::<Result<Vec<u8>, BoxError>>(32);
 
     // Spawn task to read request body frames
+    let reader_limits = body_limits.clone();
     tokio::task::spawn(async move {
-        while let Some(frame) = body.frame().await {
+        let started = tokio::time::Instant::now();
+        loop {
+            // Re-read the timeout each frame: `.body-limit` may have changed it
+            let frame = match reader_limits.timeout() {
+                Some(timeout) => {
+                    match tokio::time::timeout_at(started + timeout, body.frame()).await {
+                        Ok(frame) => frame,
+                        Err(_) => {
+                            let _ = body_tx.send(Err(Box::new(BodyTimeout))).await;
+                            break;
+                        }
+                    }
+                }
+                None => body.frame().await,
+            };
+            let Some(frame) = frame else { break };
             match frame {
                 Ok(frame) => {
                     if let Some(data) = frame.data_ref() {
@@ -96,22 +172,55 @@ where
         }
     });
 
-    // Create ByteStream for Nu pipeline
+    let content_length: Option<u64> = parts
+        .headers
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse().ok());
+
+    // Create ByteStream for Nu pipeline. The size limit is enforced here, as
+    // the body is consumed, so it sees any `.body-limit` override.
+    let stream_limits = body_limits.clone();
+    let mut received: u64 = 0;
     let stream = nu_protocol::ByteStream::from_fn(
         nu_protocol::Span::unknown(),
         engine.state.signals().clone(),
         nu_protocol::ByteStreamType::Unknown,
-        move |buffer: &mut Vec<u8>| match body_rx.blocking_recv() {
-            Some(Ok(bytes)) => {
-                buffer.extend_from_slice(&bytes);
-                Ok(true)
+        move |buffer: &mut Vec<u8>| {
+            let too_large = |len: u64| stream_limits.max_size().is_some_and(|max| len > max);
+            let payload_too_large = || {
+                stream_limits.trip(413);
+                nu_protocol::ShellError::Generic(GenericError::new_internal(
+                    "Payload too large",
+                    "request body exceeds the configured size limit",
+                ))
+            };
+            // Reject declared oversize bodies before reading any of them
+            if received == 0 && content_length.is_some_and(too_large) {
+                return Err(payload_too_large());
+            }
+            match body_rx.blocking_recv() {
+                Some(Ok(bytes)) => {
+                    received += bytes.len() as u64;
+                    if too_large(received) {
+                        return Err(payload_too_large());
+                    }
+                    buffer.extend_from_slice(&bytes);
+                    Ok(true)
+                }
+                Some(Err(err)) => {
+                    if err.is::<BodyTimeout>() {
+                        stream_limits.trip(408);
+                    }
+                    Err(nu_protocol::ShellError::Generic(
+                        GenericError::new_internal("Body read error", err.to_string()),
+                    ))
+                }
+                None => Ok(false),
             }
-            Some(Err(err)) => Err(nu_protocol::ShellError::Generic(
-                GenericError::new_internal("Body read error", err.to_string()),
-            )),
-            None => Ok(false),
         },
-    );
+    )
+    // Lets `.reverse-proxy` keep the framing of an untouched body
+    .with_known_size(known_size);
 
     // Generate request ID and guard for logging
     let start_time = Instant::now();
@@ -121,13 +230,28 @@ where
     let remote_ip = addr.as_ref().map(|a| a.ip());
     let trusted_ip = resolve_trusted_ip(&parts.headers, remote_ip, &config.trusted_proxies);
 
+    // This hop's scheme and host; trusted proxies may report the client's own
+    let tls = parts.extensions.get::<TlsInfo>().cloned();
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let host = parts
+        .headers
+        .get(hyper::header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or(parts.uri.authority().map(|a| a.as_str()))
+        .map(str::to_string);
+    let (trusted_scheme, trusted_host) =
+        resolve_trusted_origin(&parts.headers, remote_ip, &config.trusted_proxies);
+
     let request = Request {
         proto: format!("{:?}", parts.version),
+        tls,
         method: parts.method.clone(),
         authority: parts.uri.authority().map(|a| a.to_string()),
         remote_ip,
         remote_port: addr.as_ref().map(|a| a.port()),
         trusted_ip,
+        trusted_scheme: Some(trusted_scheme.unwrap_or_else(|| scheme.to_string())),
+        trusted_host: trusted_host.or_else(|| host.clone()),
         headers: parts.headers.clone(),
         uri: parts.uri.clone(),
         path: parts.uri.path().to_string(),
@@ -147,7 +271,7 @@ where
 
     // Built-in route: serve embedded Datastar JS bundle (requires --datastar flag)
     if config.datastar && request.path == DATASTAR_JS_PATH {
-        let use_brotli = compression::accepts_brotli(&parts.headers);
+        let coding = compression::negotiate(&parts.headers);
         let mut header_map = hyper::header::HeaderMap::new();
         header_map.insert(
             hyper::header::CONTENT_TYPE,
@@ -155,25 +279,21 @@ where
         );
         header_map.insert(
             hyper::header::CACHE_CONTROL,
-            hyper::header::HeaderValue::from_static("public, max-age=31536000, immutable"),
+            hyper::header::HeaderValue::from_static(crate::static_files::IMMUTABLE),
         );
-        let body = if use_brotli {
+        header_map.insert(
+            hyper::header::VARY,
+            hyper::header::HeaderValue::from_static("accept-encoding"),
+        );
+        if let Some(coding) = coding {
             header_map.insert(
                 hyper::header::CONTENT_ENCODING,
-                hyper::header::HeaderValue::from_static("br"),
+                hyper::header::HeaderValue::from_static(coding.as_str()),
             );
-            header_map.insert(
-                hyper::header::VARY,
-                hyper::header::HeaderValue::from_static("accept-encoding"),
-            );
-            Full::new(Bytes::from_static(DATASTAR_JS_BROTLI))
-                .map_err(|never| match never {})
-                .boxed()
-        } else {
-            Full::new(Bytes::from_static(DATASTAR_JS))
-                .map_err(|never| match never {})
-                .boxed()
-        };
+        }
+        let body = Full::new(datastar_bundle(coding, &config.compression)?)
+            .map_err(|never| match never {})
+            .boxed();
         log_response(request_id, 200, &header_map, start_time);
         let logging_body = LoggingBody::new(body, guard);
         let mut response = hyper::Response::builder()
@@ -184,25 +304,42 @@ where
     }
 
     let sse_cancel_token = engine.sse_cancel_token.clone();
-    let (meta_rx, bridged_body) = spawn_eval_thread(engine, request, stream);
-
-    // Wait for both:
-    // 1. Special response (from .static or .reverse-proxy) - None if normal response
-    // 2. Body pipeline result (includes http.response metadata for normal responses)
-    let (special_response, body_result): (Option<Response>, Result<PipelineResult, BoxError>) =
-        tokio::join!(async { meta_rx.await.ok() }, async {
-            bridged_body.await.map_err(|e| e.into())
-        });
+    let upstreams = engine.upstreams.clone();
+    let (meta_rx, bridged_body) =
+        spawn_eval_thread(engine, request, stream, body_limits.clone(), config.dev);
+
+    // Wait for the special response (from .static, .reverse-proxy or
+    // .websocket) first - None if the closure finished without one. Special
+    // responses are served without waiting on the pipeline result: the
+    // closure behind `.websocket` keeps running for the connection lifetime.
+    let special_response: Option<Response> = meta_rx.await.ok();
+
+    // A special response built from an over-limit body (e.g. a proxied
+    // request) is replaced outright
+    if special_response.is_some() {
+        if let Some(status) = body_limits.exceeded() {
+            return body_limit_response(status, guard, start_time);
+        }
+    }
 
-    let use_brotli = compression::accepts_brotli(&parts.headers);
+    let accepted_coding = compression::negotiate(&parts.headers);
 
-    // Check if we got a special response (.static or .reverse-proxy)
-    match special_response.as_ref().map(|r| &r.body_type) {
+    // Check if we got a special response (.static, .reverse-proxy or .websocket)
+    match special_response.map(|r| r.body_type) {
         Some(ResponseBodyType::Normal) | None => {
             // Normal response - use metadata from pipeline
+            let body_result: Result<PipelineResult, BoxError> =
+                bridged_body.await.map_err(|e| e.into());
+            // The closure failed reading an over-limit body: report that
+            // rather than the resulting script error
+            if let Some(status) = body_limits.exceeded() {
+                return body_limit_response(status, guard, start_time);
+            }
             build_normal_response(
                 body_result?,
-                use_brotli,
+                &parts,
+                accepted_coding,
+                &config,
                 guard,
                 start_time,
                 sse_cancel_token,
@@ -213,22 +350,47 @@ where
             root,
             path,
             fallback,
+            cache_control,
+            headers,
         }) => {
-            let mut static_req = hyper::Request::new(Empty::<Bytes>::new());
-            *static_req.uri_mut() = format!("/{path}").parse().unwrap();
-            *static_req.method_mut() = parts.method.clone();
-            *static_req.headers_mut() = parts.headers.clone();
-
-            let res = if let Some(fallback) = fallback {
-                let fp = root.join(fallback);
-                ServeDir::new(root)
-                    .fallback(ServeFile::new(fp))
-                    .call(static_req)
-                    .await?
-            } else {
-                ServeDir::new(root).call(static_req).await?
-            };
-            let (res_parts, body) = res.into_parts();
+            let res = crate::static_files::serve(
+                &root,
+                &path,
+                fallback.as_deref(),
+                &parts.method,
+                &parts.headers,
+                &config.compression,
+            )
+            .await?;
+            let (mut res_parts, body) = res.into_parts();
+
+            // Errors aren't cached like the file would be
+            if let Some(cache_control) = cache_control {
+                if res_parts.status.is_success() || res_parts.status == 304 {
+                    res_parts.headers.insert(
+                        hyper::header::CACHE_CONTROL,
+                        hyper::header::HeaderValue::from_str(&cache_control)?,
+                    );
+                }
+            }
+            for (k, v) in &headers {
+                let name = hyper::header::HeaderName::from_bytes(k.as_bytes())?;
+                match v {
+                    crate::response::HeaderValue::Single(s) => {
+                        res_parts
+                            .headers
+                            .insert(name, hyper::header::HeaderValue::from_str(s)?);
+                    }
+                    crate::response::HeaderValue::Multiple(values) => {
+                        for value in values {
+                            res_parts
+                                .headers
+                                .append(name.clone(), hyper::header::HeaderValue::from_str(value)?);
+                        }
+                    }
+                }
+            }
+
             log_response(
                 request_id,
                 res_parts.status.as_u16(),
@@ -236,21 +398,46 @@ where
                 start_time,
             );
 
-            let bytes = body.collect().await?.to_bytes();
-            let inner_body = Full::new(bytes).map_err(|e| match e {}).boxed();
-            let logging_body = LoggingBody::new(inner_body, guard);
+            // Streamed straight from disk
+            let logging_body = LoggingBody::new(body, guard);
             let res = hyper::Response::from_parts(res_parts, logging_body.boxed());
             Ok(res)
         }
         Some(ResponseBodyType::ReverseProxy {
-            target_url,
+            targets,
+            balance,
             headers,
             preserve_host,
+            forwarded,
             strip_prefix,
             request_body,
+            request_body_len,
             query,
+            timeout,
+            on_response,
         }) => {
-            let body = Full::new(Bytes::from(request_body.clone()));
+            // A balanced upstream stays in flight while its lease is held
+            let mut lease = balance.map(|balance| {
+                upstreams
+                    .pool(&targets, &balance, &config.proxy, &sse_cancel_token)
+                    .pick(&parts.headers)
+            });
+            let target_url = match &lease {
+                Some(lease) => lease.url().to_string(),
+                None => targets[0].clone(),
+            };
+
+            let body = if request_body_len == Some(0) {
+                // Keeps bodiless requests from going out chunked
+                Empty::new().map_err(|never| match never {}).boxed()
+            } else {
+                StreamBody::new(
+                    ReceiverStream::new(request_body)
+                        .map(|chunk| chunk.map(|bytes| Frame::data(Bytes::from(bytes)))),
+                )
+                .map_err(|err| Box::new(err) as BoxError)
+                .boxed()
+            };
             let mut proxy_req = hyper::Request::new(body);
 
             // Handle strip_prefix
@@ -258,7 +445,7 @@ where
                 parts
                     .uri
                     .path()
-                    .strip_prefix(prefix)
+                    .strip_prefix(prefix.as_str())
                     .unwrap_or(parts.uri.path())
             } else {
                 parts.uri.path()
@@ -273,9 +460,9 @@ where
                 format!("/{path}")
             };
 
-            // Build target URI
-            let target_uri = {
-                let query_string = if let Some(custom_query) = query {
+            // Build target path and query
+            let path_and_query = {
+                let query_string = if let Some(custom_query) = &query {
                     // Use custom query - convert HashMap to query string
                     url::form_urlencoded::Serializer::new(String::new())
                         .extend_pairs(custom_query.iter())
@@ -288,28 +475,43 @@ where
                 };
 
                 if query_string.is_empty() {
-                    format!("{target_url}{path}")
+                    path
                 } else {
-                    format!("{target_url}{path}?{query_string}")
+                    format!("{path}?{query_string}")
                 }
             };
 
-            *proxy_req.uri_mut() = target_uri.parse().map_err(|e| Box::new(e) as BoxError)?;
+            *proxy_req.uri_mut() = crate::proxy::upstream_uri(&target_url, &path_and_query)?;
             *proxy_req.method_mut() = parts.method.clone();
 
             // Copy original headers
             let mut header_map = parts.headers.clone();
 
-            // Update Content-Length to match the new body
-            if !request_body.is_empty() || header_map.contains_key(hyper::header::CONTENT_LENGTH) {
-                header_map.insert(
-                    hyper::header::CONTENT_LENGTH,
-                    hyper::header::HeaderValue::from_str(&request_body.len().to_string())?,
+            // Update Content-Length to match the new body; a body of unknown
+            // length is sent chunked
+            header_map.remove(hyper::header::TRANSFER_ENCODING);
+            match request_body_len {
+                Some(len) if len > 0 || header_map.contains_key(hyper::header::CONTENT_LENGTH) => {
+                    header_map.insert(hyper::header::CONTENT_LENGTH, len.into());
+                }
+                Some(_) => {}
+                None => {
+                    header_map.remove(hyper::header::CONTENT_LENGTH);
+                }
+            }
+
+            if forwarded {
+                crate::proxy::set_forwarded_headers(
+                    &mut header_map,
+                    remote_ip,
+                    is_trusted_peer(remote_ip, &config.trusted_proxies),
+                    scheme,
+                    host.as_deref(),
                 );
             }
 
             // Add custom headers
-            for (k, v) in headers {
+            for (k, v) in &headers {
                 let header_name = hyper::header::HeaderName::from_bytes(k.as_bytes())?;
 
                 match v {
@@ -327,27 +529,92 @@ where
                 }
             }
 
+            // `on_response` works on the plain body; it's re-encoded for the
+            // client like any other response
+            if on_response.is_some() {
+                header_map.insert(
+                    hyper::header::ACCEPT_ENCODING,
+                    hyper::header::HeaderValue::from_static("identity"),
+                );
+            }
+
             // Handle preserve_host
             if !preserve_host {
-                if let Ok(target_uri) = target_url.parse::<hyper::Uri>() {
-                    if let Some(authority) = target_uri.authority() {
-                        header_map.insert(
-                            hyper::header::HOST,
-                            hyper::header::HeaderValue::from_str(authority.as_ref())?,
-                        );
-                    }
+                if let Some(host) = crate::proxy::upstream_host(&target_url) {
+                    header_map.insert(
+                        hyper::header::HOST,
+                        hyper::header::HeaderValue::from_str(&host)?,
+                    );
                 }
             }
 
             *proxy_req.headers_mut() = header_map;
 
-            // Create a simple HTTP client and forward the request
-            let client =
-                hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
-                    .build_http();
+            // Only an HTTP/1.1 client connection can be handed over
+            let client_upgrade = on_upgrade.filter(|_| is_upgrade_request(&parts.headers));
 
-            match client.request(proxy_req).await {
-                Ok(response) => {
+            match config.proxy.request(proxy_req, timeout).await {
+                Ok(mut response) => {
+                    if let Some(lease) = &lease {
+                        lease.report(!response.status().is_server_error());
+                    }
+                    if let Some(on_response) = on_response
+                        .filter(|_| response.status() != hyper::StatusCode::SWITCHING_PROTOCOLS)
+                    {
+                        let (res_parts, mut body) = response.into_parts();
+                        let (body_tx, body_rx) = tokio::sync::mpsc::channel(32);
+                        tokio::task::spawn(async move {
+                            // In flight until the upstream body is read
+                            let _lease = lease;
+                            while let Some(frame) = body.frame().await {
+                                let chunk = match frame {
+                                    Ok(frame) => match frame.into_data() {
+                                        Ok(data) => Ok(data.to_vec()),
+                                        Err(_) => continue, // trailers
+                                    },
+                                    Err(err) => Err(std::io::Error::other(err)),
+                                };
+                                let failed = chunk.is_err();
+                                if body_tx.send(chunk).await.is_err() || failed {
+                                    break;
+                                }
+                            }
+                        });
+                        let _ = on_response.send(UpstreamResponse {
+                            status: res_parts.status.as_u16(),
+                            headers: res_parts.headers,
+                            body: body_rx,
+                        });
+
+                        // The closure's output is served like a normal response
+                        let body_result: Result<PipelineResult, BoxError> =
+                            bridged_body.await.map_err(|e| e.into());
+                        return build_normal_response(
+                            body_result?,
+                            &parts,
+                            accepted_coding,
+                            &config,
+                            guard,
+                            start_time,
+                            sse_cancel_token,
+                        )
+                        .await;
+                    }
+                    if response.status() == hyper::StatusCode::SWITCHING_PROTOCOLS {
+                        if let Some(client_upgrade) = client_upgrade {
+                            let upstream_upgrade = hyper::upgrade::on(&mut response);
+                            let lease = lease.take();
+                            tokio::task::spawn(async move {
+                                crate::proxy::splice(
+                                    client_upgrade,
+                                    upstream_upgrade,
+                                    sse_cancel_token,
+                                )
+                                .await;
+                                drop(lease);
+                            });
+                        }
+                    }
                     let (res_parts, body) = response.into_parts();
                     log_response(
                         request_id,
@@ -356,44 +623,198 @@ where
                         start_time,
                     );
 
-                    let inner_body = body.map_err(|e| e.into()).boxed();
+                    let inner_body = body
+                        .map_err(|e| e.into())
+                        .map_frame(move |frame| {
+                            // Released when the body is done or dropped
+                            let _ = &lease;
+                            frame
+                        })
+                        .boxed();
                     let logging_body = LoggingBody::new(inner_body, guard);
                     let res = hyper::Response::from_parts(res_parts, logging_body.boxed());
                     Ok(res)
                 }
-                Err(_e) => {
+                Err(err) => {
+                    // The upload was cut short by `.body-limit`
+                    if let Some(status) = body_limits.exceeded() {
+                        return body_limit_response(status, guard, start_time);
+                    }
+                    if let Some(lease) = &lease {
+                        lease.report(false);
+                    }
+                    let (status, reason) = match err {
+                        ProxyError::Timeout => (504, "Gateway Timeout"),
+                        ProxyError::Upstream(err) => {
+                            log_error(&format!("Reverse proxy error: {err}"));
+                            (502, "Bad Gateway")
+                        }
+                    };
                     let empty_headers = hyper::header::HeaderMap::new();
-                    log_response(request_id, 502, &empty_headers, start_time);
+                    log_response(request_id, status, &empty_headers, start_time);
 
-                    let inner_body = Full::new("Bad Gateway".into())
+                    let inner_body = Full::new(reason.into())
                         .map_err(|never| match never {})
                         .boxed();
                     let logging_body = LoggingBody::new(inner_body, guard);
                     let response = hyper::Response::builder()
-                        .status(502)
+                        .status(status)
                         .body(logging_body.boxed())?;
                     Ok(response)
                 }
             }
         }
+        Some(ResponseBodyType::WebSocket { inbound, outbound }) => {
+            let (Some(accept), Some(on_upgrade)) =
+                (crate::websocket::handshake_accept(&parts), on_upgrade)
+            else {
+                // Not a WebSocket handshake: dropping the channels ends the closure
+                let mut header_map = hyper::header::HeaderMap::new();
+                header_map.insert(
+                    hyper::header::UPGRADE,
+                    hyper::header::HeaderValue::from_static("websocket"),
+                );
+                log_response(request_id, 426, &header_map, start_time);
+                let inner_body = Full::new("Upgrade Required".into())
+                    .map_err(|never| match never {})
+                    .boxed();
+                let logging_body = LoggingBody::new(inner_body, guard);
+                let mut response = hyper::Response::builder()
+                    .status(426)
+                    .body(logging_body.boxed())?;
+                *response.headers_mut() = header_map;
+                return Ok(response);
+            };
+
+            tokio::task::spawn(async move {
+                match on_upgrade.await {
+                    Ok(upgraded) => {
+                        crate::websocket::run(
+                            hyper_util::rt::TokioIo::new(upgraded),
+                            inbound,
+                            outbound,
+                            sse_cancel_token,
+                        )
+                        .await
+                    }
+                    Err(err) => log_error(&format!("WebSocket upgrade failed: {err}")),
+                }
+            });
+
+            let mut header_map = hyper::header::HeaderMap::new();
+            header_map.insert(
+                hyper::header::CONNECTION,
+                hyper::header::HeaderValue::from_static("upgrade"),
+            );
+            header_map.insert(
+                hyper::header::UPGRADE,
+                hyper::header::HeaderValue::from_static("websocket"),
+            );
+            header_map.insert(hyper::header::SEC_WEBSOCKET_ACCEPT, accept);
+            log_response(request_id, 101, &header_map, start_time);
+
+            let inner_body = Empty::<Bytes>::new()
+                .map_err(|never| match never {})
+                .boxed();
+            let logging_body = LoggingBody::new(inner_body, guard);
+            let mut response = hyper::Response::builder()
+                .status(101)
+                .body(logging_body.boxed())?;
+            *response.headers_mut() = header_map;
+            Ok(response)
+        }
     }
 }
 
+/// The embedded Datastar bundle in `coding`. Brotli is built in; zstd and
+/// gzip are compressed on first use and kept.
+fn datastar_bundle(
+    coding: Option<compression::ContentCoding>,
+    config: &compression::CompressionConfig,
+) -> Result<Bytes, BoxError> {
+    static ZSTD: std::sync::OnceLock<Bytes> = std::sync::OnceLock::new();
+    static GZIP: std::sync::OnceLock<Bytes> = std::sync::OnceLock::new();
+    let (cache, coding) = match coding {
+        None => return Ok(Bytes::from_static(DATASTAR_JS)),
+        Some(compression::ContentCoding::Brotli) => {
+            return Ok(Bytes::from_static(DATASTAR_JS_BROTLI))
+        }
+        Some(coding @ compression::ContentCoding::Zstd) => (&ZSTD, coding),
+        Some(coding @ compression::ContentCoding::Gzip) => (&GZIP, coding),
+    };
+    if let Some(bytes) = cache.get() {
+        return Ok(bytes.clone());
+    }
+    let bytes = Bytes::from(compression::compress_full(DATASTAR_JS, coding, config)?);
+    Ok(cache.get_or_init(|| bytes).clone())
+}
+
+/// Plain-text 413/408 for a request body that broke its limits.
+fn body_limit_response(status: u16, guard: RequestGuard, start_time: Instant) -> HTTPResult {
+    let status = hyper::StatusCode::from_u16(status)?;
+    let mut header_map = hyper::header::HeaderMap::new();
+    header_map.insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    log_response(guard.request_id(), status.as_u16(), &header_map, start_time);
+
+    let reason = status.canonical_reason().unwrap_or_default();
+    let inner_body = Full::new(Bytes::from(reason))
+        .map_err(|never| match never {})
+        .boxed();
+    let logging_body = LoggingBody::new(inner_body, guard);
+    let mut response = hyper::Response::builder()
+        .status(status)
+        .body(logging_body.boxed())?;
+    *response.headers_mut() = header_map;
+    Ok(response)
+}
+
+/// Strong ETag for a buffered body: xxh3 of the uncompressed bytes, tagged
+/// with the content coding so each representation gets its own validator.
+fn full_body_etag(
+    bytes: &[u8],
+    coding: Option<compression::ContentCoding>,
+) -> hyper::header::HeaderValue {
+    let hash = xxhash_rust::xxh3::xxh3_128(bytes);
+    let etag = match coding {
+        Some(coding) => format!("\"{hash:032x}-{}\"", coding.as_str()),
+        None => format!("\"{hash:032x}\""),
+    };
+    hyper::header::HeaderValue::from_str(&etag).expect("hex etag is a valid header value")
+}
+
+/// If-None-Match uses the weak comparison (RFC 9110 13.1.2): `W/` prefixes
+/// are ignored and `*` matches any current representation.
+fn if_none_match_matches(req_headers: &hyper::header::HeaderMap, etag: &[u8]) -> bool {
+    let strip_weak = |tag: &[u8]| tag.strip_prefix(b"W/").unwrap_or(tag).to_vec();
+    let etag = strip_weak(etag);
+    req_headers
+        .get_all(hyper::header::IF_NONE_MATCH)
+        .iter()
+        .flat_map(|v| v.as_bytes().split(|b| *b == b','))
+        .map(|tag| tag.trim_ascii())
+        .any(|tag| tag == b"*" || strip_weak(tag) == etag)
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn build_normal_response(
     pipeline_result: PipelineResult,
-    use_brotli: bool,
+    req_parts: &http::request::Parts,
+    accepted_coding: Option<compression::ContentCoding>,
+    config: &AppConfig,
     guard: RequestGuard,
     start_time: Instant,
     sse_cancel_token: CancellationToken,
 ) -> HTTPResult {
     let request_id = guard.request_id();
     let (inferred_content_type, http_meta, body) = pipeline_result;
-    let status = match (http_meta.status, &body) {
+    let mut status = match (http_meta.status, &body) {
         (Some(s), _) => s,
         (None, ResponseTransport::Empty) => 204,
         (None, _) => 200,
     };
-    let mut builder = hyper::Response::builder().status(status);
     let mut header_map = hyper::header::HeaderMap::new();
 
     // Content-type precedence:
@@ -425,12 +846,26 @@ async fn build_normal_response(
         );
     }
 
-    // Add compression headers if using brotli
-    if use_brotli {
+    // Compress text-like bodies the client accepts, unless the script
+    // already encoded the body itself. Buffered bodies under the minimum size
+    // aren't worth it; streams are compressed regardless.
+    let compressible = config.compression.compresses(content_type.as_deref())
+        && !http_meta
+            .headers
+            .keys()
+            .any(|k| k.eq_ignore_ascii_case("content-encoding"));
+    let coding = match &body {
+        ResponseTransport::Empty => None,
+        ResponseTransport::Full(bytes) if bytes.len() < config.compression.min_size => None,
+        _ => accepted_coding.filter(|_| compressible),
+    };
+    if let Some(coding) = coding {
         header_map.insert(
             hyper::header::CONTENT_ENCODING,
-            hyper::header::HeaderValue::from_static("br"),
+            hyper::header::HeaderValue::from_static(coding.as_str()),
         );
+    }
+    if compressible && !matches!(body, ResponseTransport::Empty) {
         header_map.insert(
             hyper::header::VARY,
             hyper::header::HeaderValue::from_static("accept-encoding"),
@@ -470,21 +905,53 @@ async fn build_normal_response(
         }
     }
 
+    // Conditional GET/HEAD for buffered bodies. A script-supplied ETag is
+    // honoured as-is; --etag fills one in when the script didn't set one.
+    let is_get_or_head = matches!(req_parts.method, http::Method::GET | http::Method::HEAD);
+    let mut not_modified = false;
+    if let ResponseTransport::Full(bytes) = &body {
+        if status == 200 {
+            if config.etag && !header_map.contains_key(hyper::header::ETAG) {
+                header_map.insert(hyper::header::ETAG, full_body_etag(bytes, coding));
+            }
+            if let Some(current) = header_map.get(hyper::header::ETAG) {
+                not_modified =
+                    is_get_or_head && if_none_match_matches(&req_parts.headers, current.as_bytes());
+            }
+        }
+    }
+    if not_modified {
+        status = 304;
+        header_map.remove(hyper::header::CONTENT_ENCODING);
+    }
+
     log_response(request_id, status, &header_map, start_time);
+    let mut builder = hyper::Response::builder().status(status);
     *builder.headers_mut().unwrap() = header_map;
 
     let inner_body = match body {
         ResponseTransport::Empty => Empty::<Bytes>::new()
             .map_err(|never| match never {})
             .boxed(),
+        ResponseTransport::Full(_) if not_modified => Empty::<Bytes>::new()
+            .map_err(|never| match never {})
+            .boxed(),
         ResponseTransport::Full(bytes) => {
-            if use_brotli {
-                let compressed = compression::compress_full(&bytes)?;
-                Full::new(Bytes::from(compressed))
+            let bytes = match coding {
+                Some(coding) => compression::compress_full(&bytes, coding, &config.compression)?,
+                None => bytes,
+            };
+            if req_parts.method == http::Method::HEAD {
+                // Same headers as GET, including the length, but no body
+                builder.headers_mut().unwrap().insert(
+                    hyper::header::CONTENT_LENGTH,
+                    hyper::header::HeaderValue::from(bytes.len()),
+                );
+                Empty::<Bytes>::new()
                     .map_err(|never| match never {})
                     .boxed()
             } else {
-                Full::new(bytes.into())
+                Full::new(Bytes::from(bytes))
                     .map_err(|never| match never {})
                     .boxed()
             }
@@ -498,7 +965,7 @@ async fn build_normal_response(
                 // "finished" and the client goes silent.
                 //
                 // The same Stream<Item = Result<Vec<u8>, BoxError>> drives
-                // both the plain and brotli'd paths -- BrotliStream now
+                // both the plain and compressed paths -- CompressStream
                 // propagates Err through, deliberately omitting the FINISH op
                 // so the client sees a truncated/decoder-error body and
                 // retries.
@@ -517,17 +984,16 @@ async fn build_normal_response(
                             }
                         },
                     ));
-                if use_brotli {
-                    let brotli = compression::BrotliStream::new(inner);
-                    BodyExt::boxed(StreamBody::new(brotli))
+                if let Some(coding) = coding {
+                    let compressed =
+                        compression::CompressStream::new(inner, coding, &config.compression)?;
+                    BodyExt::boxed(StreamBody::new(compressed))
                 } else {
                     let stream = inner.map(|res| res.map(|d| Frame::data(Bytes::from(d))));
                     BodyExt::boxed(StreamBody::new(stream))
                 }
-            } else if use_brotli {
-                let byte_stream = ReceiverStream::new(rx).map(Ok::<Vec<u8>, BoxError>);
-                let brotli = compression::BrotliStream::new(byte_stream);
-                BodyExt::boxed(StreamBody::new(brotli))
+            } else if let Some(coding) = coding {
+                compression::compress_stream(rx, coding, &config.compression)?
             } else {
                 let stream = ReceiverStream::new(rx).map(|data| Ok(Frame::data(Bytes::from(data))));
                 BodyExt::boxed(StreamBody::new(stream))
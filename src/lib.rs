@@ -1,15 +1,23 @@
 #![allow(clippy::result_large_err)]
+pub mod archive;
 pub mod bus;
 pub mod commands;
 pub mod compression;
+pub mod dev_cert;
 pub mod engine;
+pub mod error_page;
 pub mod handler;
 pub mod listener;
 pub mod logging;
+pub mod multipart;
+pub mod proxy;
 pub mod request;
 pub mod response;
+pub mod static_files;
 pub mod stdlib;
 pub mod store;
+pub mod upstream;
+pub mod websocket;
 pub mod worker;
 
 #[cfg(test)]
@@ -1,14 +1,25 @@
-use std::io::{self, Seek};
-use std::path::PathBuf;
-use std::sync::Arc;
-
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use notify::{RecursiveMode, Watcher};
+use rustls::pki_types::ServerName;
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{ClientHello, ParsedCertificate, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
 use rustls::ServerConfig;
+use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
 #[cfg(unix)]
 use tokio::net::UnixListener;
 use tokio_rustls::TlsAcceptor;
 
+use crate::logging::{log_error, log_print};
+
 #[cfg(windows)]
 mod win_uds_compat {
     use std::io;
@@ -91,61 +102,383 @@ impl<T: AsyncRead + AsyncWrite> AsyncReadWrite for T {}
 
 pub type AsyncReadWriteBox = Box<dyn AsyncReadWrite + Unpin + Send>;
 
-pub struct TlsConfig {
-    pub config: Arc<ServerConfig>,
-    acceptor: TlsAcceptor,
+/// Connect to a Unix domain socket (AF_UNIX on Windows)
+pub async fn connect_unix(path: &std::path::Path) -> io::Result<AsyncReadWriteBox> {
+    #[cfg(unix)]
+    let stream = tokio::net::UnixStream::connect(path).await?;
+    #[cfg(windows)]
+    let stream = win_uds_compat::WinUnixStream::connect(path).await?;
+    Ok(Box::new(stream))
 }
 
-impl TlsConfig {
-    pub fn from_pem(pem_path: PathBuf) -> io::Result<Self> {
-        let pem = std::fs::File::open(&pem_path).map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("Failed to open PEM file {}: {}", pem_path.display(), e),
-            )
-        })?;
-        let mut pem = std::io::BufReader::new(pem);
+/// A certificate chain and private key to serve. A combined PEM is both.
+#[derive(Clone, Debug)]
+pub struct CertSource {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
 
-        let certs = rustls_pemfile::certs(&mut pem)
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|e| {
+impl CertSource {
+    /// One PEM file holding the chain and the key
+    pub fn pem(path: PathBuf) -> Self {
+        Self {
+            cert: path.clone(),
+            key: path,
+        }
+    }
+
+    /// The PEM text of the chain and of the key
+    fn read(&self) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let read = |path: &PathBuf| {
+            std::fs::read(path).map_err(|e| {
                 io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Invalid certificate: {e}"),
+                    io::ErrorKind::NotFound,
+                    format!("Failed to open PEM file {}: {}", path.display(), e),
                 )
-            })?;
+            })
+        };
+        Ok((read(&self.cert)?, read(&self.key)?))
+    }
 
-        if certs.is_empty() {
-            return Err(io::Error::new(
+    fn certified_key(&self, cert_pem: &[u8], key_pem: &[u8]) -> io::Result<CertifiedKey> {
+        let invalid = |path: &PathBuf, msg: String| {
+            io::Error::new(
                 io::ErrorKind::InvalidData,
-                "No certificates found",
+                format!("{}: {msg}", path.display()),
+            )
+        };
+
+        let certs = rustls_pemfile::certs(&mut &cert_pem[..])
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| invalid(&self.cert, format!("Invalid certificate: {e}")))?;
+        if certs.is_empty() {
+            return Err(invalid(&self.cert, "No certificates found".into()));
+        }
+
+        let key = rustls_pemfile::private_key(&mut &key_pem[..])
+            .map_err(|e| invalid(&self.key, format!("Invalid private key: {e}")))?
+            .ok_or_else(|| invalid(&self.key, "No private key found".into()))?;
+        let key = rustls::crypto::aws_lc_rs::sign::any_supported_type(&key)
+            .map_err(|e| invalid(&self.key, format!("Invalid private key: {e}")))?;
+
+        let certified = CertifiedKey::new(certs, key);
+        if let Err(rustls::Error::InconsistentKeys(rustls::InconsistentKeys::KeyMismatch)) =
+            certified.keys_match()
+        {
+            return Err(invalid(
+                &self.key,
+                format!("Private key doesn't match {}", self.cert.display()),
             ));
         }
+        Ok(certified)
+    }
+}
+
+/// Picks the certificate whose names match the client's SNI, else the first.
+/// [`CertResolver::watch`] reloads them when their files change, so renewed
+/// certificates are served without dropping open connections.
+#[derive(Debug)]
+pub struct CertResolver {
+    sources: Vec<CertSource>,
+    /// The PEM text the current keys came from
+    loaded: Mutex<Vec<(Vec<u8>, Vec<u8>)>>,
+    keys: ArcSwap<Vec<Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    pub fn load(sources: Vec<CertSource>) -> io::Result<Self> {
+        let resolver = Self {
+            sources,
+            loaded: Mutex::new(Vec::new()),
+            keys: ArcSwap::from_pointee(Vec::new()),
+        };
+        resolver.reload()?;
+        Ok(resolver)
+    }
+
+    /// Re-read every certificate and key. Nothing changes unless they all
+    /// load. Returns whether any of them had changed.
+    pub fn reload(&self) -> io::Result<bool> {
+        let pems = self
+            .sources
+            .iter()
+            .map(CertSource::read)
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut loaded = self.loaded.lock().expect("certificate lock poisoned");
+        if *loaded == pems {
+            return Ok(false);
+        }
+        let keys = self
+            .sources
+            .iter()
+            .zip(&pems)
+            .map(|(source, (cert, key))| source.certified_key(cert, key).map(Arc::new))
+            .collect::<io::Result<Vec<_>>>()?;
+        self.keys.store(Arc::new(keys));
+        *loaded = pems;
+        Ok(true)
+    }
 
-        pem.seek(std::io::SeekFrom::Start(0))?;
+    /// The certificate for `server_name`: the first valid for it, or the
+    /// first of all when none is or the client didn't say
+    fn pick(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let keys = self.keys.load();
+        let name = server_name.and_then(|name| ServerName::try_from(name).ok());
+        name.and_then(|name| {
+            keys.iter()
+                .find(|key| {
+                    key.end_entity_cert()
+                        .and_then(ParsedCertificate::try_from)
+                        .and_then(|cert| rustls::client::verify_server_name(&cert, &name))
+                        .is_ok()
+                })
+                .cloned()
+        })
+        .or_else(|| keys.first().cloned())
+    }
 
-        let key = rustls_pemfile::private_key(&mut pem)
-            .map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Invalid private key: {e}"),
-                )
-            })?
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No private key found"))?;
+    /// Reload on changes to the directories holding the certificates and
+    /// keys, for as long as the process runs
+    pub fn watch(self: &Arc<Self>) {
+        let resolver = self.clone();
+        std::thread::spawn(move || {
+            let (raw_tx, raw_rx) = std::sync::mpsc::channel();
+            let mut watcher = match notify::recommended_watcher(raw_tx) {
+                Ok(watcher) => watcher,
+                Err(e) => {
+                    log_error(&format!("Failed to watch TLS certificates: {e}"));
+                    return;
+                }
+            };
+            let mut dirs: Vec<&Path> = resolver
+                .sources
+                .iter()
+                .flat_map(|source| [&source.cert, &source.key])
+                .map(|path| match path.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir,
+                    _ => Path::new("."),
+                })
+                .collect();
+            dirs.sort();
+            dirs.dedup();
+            for dir in dirs {
+                if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                    log_error(&format!("Failed to watch {}: {e}", dir.display()));
+                }
+            }
+
+            // Renewals tend to write several files in a row: wait for them
+            // to settle
+            let debounce = Duration::from_millis(100);
+            let mut pending_reload = false;
+            loop {
+                let timeout = if pending_reload {
+                    debounce
+                } else {
+                    Duration::from_secs(86400)
+                };
+                match raw_rx.recv_timeout(timeout) {
+                    Ok(Ok(event)) => {
+                        use notify::EventKind;
+                        if matches!(
+                            event.kind,
+                            EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                        ) {
+                            pending_reload = true;
+                        }
+                    }
+                    Ok(Err(e)) => log_error(&format!("Watch error: {e:?}")),
+                    Err(RecvTimeoutError::Timeout) => {
+                        if pending_reload {
+                            pending_reload = false;
+                            match resolver.reload() {
+                                Ok(true) => log_print("Reloaded TLS certificates"),
+                                Ok(false) => {}
+                                Err(e) => log_error(&format!(
+                                    "Failed to reload TLS certificates, keeping the old ones: {e}"
+                                )),
+                            }
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.pick(client_hello.server_name())
+    }
+}
+
+/// `--tls-client-ca`: ask clients for a certificate issued by one of these
+/// CAs
+#[derive(Clone, Debug)]
+pub struct ClientAuth {
+    pub ca: PathBuf,
+    /// Refuse the handshake without one, rather than carrying on anonymously
+    pub required: bool,
+}
+
+impl ClientAuth {
+    fn verifier(&self) -> io::Result<Arc<dyn ClientCertVerifier>> {
+        let invalid = |msg: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {msg}", self.ca.display()),
+            )
+        };
+        let pem = std::fs::read(&self.ca).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Failed to open PEM file {}: {}", self.ca.display(), e),
+            )
+        })?;
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut &pem[..]) {
+            let cert = cert.map_err(|e| invalid(format!("Invalid certificate: {e}")))?;
+            roots
+                .add(cert)
+                .map_err(|e| invalid(format!("Invalid CA certificate: {e}")))?;
+        }
+        if roots.is_empty() {
+            return Err(invalid("No certificates found".into()));
+        }
+
+        let builder = WebPkiClientVerifier::builder(Arc::new(roots));
+        let builder = if self.required {
+            builder
+        } else {
+            builder.allow_unauthenticated()
+        };
+        builder
+            .build()
+            .map_err(|e| invalid(format!("TLS config error: {e}")))
+    }
+}
+
+pub struct TlsConfig {
+    pub config: Arc<ServerConfig>,
+    pub resolver: Arc<CertResolver>,
+    acceptor: TlsAcceptor,
+}
 
-        let mut config = rustls::ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(certs, key)
-            .map_err(|e| {
-                io::Error::new(io::ErrorKind::InvalidData, format!("TLS config error: {e}"))
-            })?;
+impl TlsConfig {
+    pub fn new(sources: Vec<CertSource>, client_auth: Option<&ClientAuth>) -> io::Result<Self> {
+        let resolver = Arc::new(CertResolver::load(sources)?);
+        let builder = rustls::ServerConfig::builder();
+        let builder = match client_auth {
+            Some(client_auth) => builder.with_client_cert_verifier(client_auth.verifier()?),
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_cert_resolver(resolver.clone());
 
         // Enable HTTP/2 via ALPN (advertise h2 first, then http/1.1)
         config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
 
         let config = Arc::new(config);
         let acceptor = TlsAcceptor::from(config.clone());
-        Ok(Self { config, acceptor })
+        Ok(Self {
+            config,
+            resolver,
+            acceptor,
+        })
+    }
+
+    pub fn from_pem(pem_path: PathBuf) -> io::Result<Self> {
+        Self::new(vec![CertSource::pem(pem_path)], None)
+    }
+}
+
+/// Request extension for requests that arrived over TLS, shown as `$req.tls`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TlsInfo {
+    /// e.g. `TLSv1_3`
+    pub version: Option<String>,
+    /// The hostname the client asked for (SNI)
+    pub server_name: Option<String>,
+    /// The client's certificate, verified against `--tls-client-ca`
+    pub client_cert: Option<ClientCert>,
+}
+
+impl TlsInfo {
+    fn new(conn: &rustls::ServerConnection) -> Self {
+        Self {
+            version: conn.protocol_version().map(|v| format!("{v:?}")),
+            server_name: conn.server_name().map(str::to_string),
+            client_cert: conn
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| ClientCert::from_der(cert)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientCert {
+    /// Distinguished name, e.g. `CN=billing, O=Acme`
+    pub subject: String,
+    pub issuer: String,
+    pub sans: SubjectAltNames,
+    /// Hex, colon-separated
+    pub serial: String,
+    /// SHA-256 of the DER certificate, lowercase hex
+    pub fingerprint: String,
+    pub not_before: chrono::DateTime<chrono::Utc>,
+    pub not_after: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SubjectAltNames {
+    pub dns: Vec<String>,
+    pub uri: Vec<String>,
+    pub ip: Vec<String>,
+    pub email: Vec<String>,
+}
+
+impl ClientCert {
+    fn from_der(der: &[u8]) -> Option<Self> {
+        use sha2::Digest;
+        use x509_parser::extensions::GeneralName;
+
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+        let mut sans = SubjectAltNames::default();
+        let names = cert.subject_alternative_name().ok().flatten();
+        for name in names.iter().flat_map(|ext| &ext.value.general_names) {
+            match name {
+                GeneralName::DNSName(name) => sans.dns.push(name.to_string()),
+                GeneralName::URI(uri) => sans.uri.push(uri.to_string()),
+                GeneralName::RFC822Name(email) => sans.email.push(email.to_string()),
+                GeneralName::IPAddress(bytes) => {
+                    let ip = match bytes.len() {
+                        4 => <[u8; 4]>::try_from(*bytes).ok().map(std::net::IpAddr::from),
+                        16 => <[u8; 16]>::try_from(*bytes)
+                            .ok()
+                            .map(std::net::IpAddr::from),
+                        _ => None,
+                    };
+                    sans.ip.extend(ip.map(|ip| ip.to_string()));
+                }
+                _ => {}
+            }
+        }
+        let time = |t: x509_parser::time::ASN1Time| {
+            chrono::DateTime::from_timestamp(t.timestamp(), 0).unwrap_or_default()
+        };
+        Some(Self {
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            sans,
+            serial: cert.raw_serial_as_string(),
+            fingerprint: sha2::Sha256::digest(der)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect(),
+            not_before: time(cert.validity().not_before),
+            not_after: time(cert.validity().not_after),
+        })
     }
 }
 
@@ -161,9 +494,34 @@ pub enum Listener {
 }
 
 impl Listener {
+    /// Whether connections are over TLS
+    pub fn is_tls(&self) -> bool {
+        matches!(
+            self,
+            Listener::Tcp {
+                tls_config: Some(_),
+                ..
+            }
+        )
+    }
+
+    /// The bound port, for TCP
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            Listener::Tcp { listener, .. } => listener.local_addr().ok().map(|addr| addr.port()),
+            _ => None,
+        }
+    }
+
+    /// The next connection, with the client's address for TCP and its TLS
+    /// details if it's over TLS
     pub async fn accept(
         &mut self,
-    ) -> io::Result<(AsyncReadWriteBox, Option<std::net::SocketAddr>)> {
+    ) -> io::Result<(
+        AsyncReadWriteBox,
+        Option<std::net::SocketAddr>,
+        Option<TlsInfo>,
+    )> {
         match self {
             Listener::Tcp {
                 listener,
@@ -171,10 +529,13 @@ impl Listener {
             } => {
                 let (stream, addr) = listener.accept().await?;
 
-                let stream = if let Some(tls) = tls_config {
+                let (stream, tls_info) = if let Some(tls) = tls_config {
                     // Handle TLS connection
                     match tls.acceptor.accept(stream).await {
-                        Ok(tls_stream) => Box::new(tls_stream) as AsyncReadWriteBox,
+                        Ok(tls_stream) => {
+                            let info = TlsInfo::new(tls_stream.get_ref().1);
+                            (Box::new(tls_stream) as AsyncReadWriteBox, Some(info))
+                        }
                         Err(e) => {
                             return Err(io::Error::new(
                                 io::ErrorKind::ConnectionAborted,
@@ -184,42 +545,28 @@ impl Listener {
                     }
                 } else {
                     // Handle plain TCP connection
-                    Box::new(stream)
+                    (Box::new(stream) as AsyncReadWriteBox, None)
                 };
 
-                Ok((stream, Some(addr)))
+                Ok((stream, Some(addr), tls_info))
             }
             #[cfg(unix)]
             Listener::Unix(listener) => {
                 let (stream, _) = listener.accept().await?;
-                Ok((Box::new(stream), None))
+                Ok((Box::new(stream), None, None))
             }
             #[cfg(windows)]
             Listener::Unix(listener) => {
                 let (stream, _) = listener.accept().await?;
-                Ok((Box::new(stream), None))
+                Ok((Box::new(stream), None, None))
             }
         }
     }
 
     pub async fn bind(addr: &str, tls_config: Option<TlsConfig>) -> io::Result<Self> {
-        // Check if address looks like a Unix socket path
-        fn is_unix_path(addr: &str) -> bool {
-            addr.starts_with('/') || addr.starts_with('.')
-        }
-
-        #[cfg(windows)]
-        fn is_windows_path(s: &str) -> bool {
-            let bytes = s.as_bytes();
-            bytes.len() >= 3
-                && bytes[0].is_ascii_alphabetic()
-                && bytes[1] == b':'
-                && (bytes[2] == b'\\' || bytes[2] == b'/')
-        }
-
         #[cfg(windows)]
         {
-            if is_unix_path(addr) || is_windows_path(addr) {
+            if is_socket_path(addr) {
                 if tls_config.is_some() {
                     return Err(io::Error::new(
                         io::ErrorKind::InvalidInput,
@@ -244,7 +591,7 @@ impl Listener {
 
         #[cfg(unix)]
         {
-            if is_unix_path(addr) {
+            if is_socket_path(addr) {
                 if tls_config.is_some() {
                     return Err(io::Error::new(
                         io::ErrorKind::InvalidInput,
@@ -269,6 +616,24 @@ impl Listener {
     }
 }
 
+/// Whether `addr` names a Unix domain socket rather than a TCP address
+pub fn is_socket_path(addr: &str) -> bool {
+    #[cfg(windows)]
+    fn is_windows_path(s: &str) -> bool {
+        let bytes = s.as_bytes();
+        bytes.len() >= 3
+            && bytes[0].is_ascii_alphabetic()
+            && bytes[1] == b':'
+            && (bytes[2] == b'\\' || bytes[2] == b'/')
+    }
+
+    #[cfg(windows)]
+    if is_windows_path(addr) {
+        return true;
+    }
+    addr.starts_with('/') || addr.starts_with('.')
+}
+
 impl Clone for Listener {
     fn clone(&self) -> Self {
         match self {
@@ -295,6 +660,7 @@ impl Clone for TlsConfig {
     fn clone(&self) -> Self {
         TlsConfig {
             config: self.config.clone(),
+            resolver: self.resolver.clone(),
             acceptor: TlsAcceptor::from(self.config.clone()),
         }
     }
@@ -374,7 +740,7 @@ mod tests {
             Ok(Box::new(stream) as AsyncReadWriteBox)
         });
 
-        let (mut serve, _) = listener.accept().await.unwrap();
+        let (mut serve, _, _) = listener.accept().await.unwrap();
         let want = b"Hello from server!";
         serve.write_all(want).await.unwrap();
         drop(serve);
@@ -385,6 +751,56 @@ mod tests {
         assert_eq!(want.to_vec(), got);
     }
 
+    /// A self-signed certificate for `names`, written to `dir` as
+    /// `<stem>.crt` and `<stem>.key`
+    fn write_cert(dir: &Path, stem: &str, names: &[&str]) -> (CertSource, Vec<u8>) {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let generated = rcgen::generate_simple_self_signed(names).unwrap();
+        let source = CertSource {
+            cert: dir.join(format!("{stem}.crt")),
+            key: dir.join(format!("{stem}.key")),
+        };
+        std::fs::write(&source.cert, generated.cert.pem()).unwrap();
+        std::fs::write(&source.key, generated.signing_key.serialize_pem()).unwrap();
+        (source, generated.cert.der().to_vec())
+    }
+
+    #[test]
+    fn test_cert_resolver() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, a_der) = write_cert(dir.path(), "a", &["a.test"]);
+        let (b, b_der) = write_cert(dir.path(), "b", &["b.test", "*.b.test"]);
+        let resolver = CertResolver::load(vec![a.clone(), b.clone()]).unwrap();
+        let served = |name: Option<&str>| resolver.pick(name).unwrap().cert[0].to_vec();
+
+        assert_eq!(served(Some("a.test")), a_der);
+        assert_eq!(served(Some("b.test")), b_der);
+        assert_eq!(served(Some("www.b.test")), b_der);
+        // No match, or no SNI: the first
+        assert_eq!(served(Some("c.test")), a_der);
+        assert_eq!(served(None), a_der);
+
+        assert!(!resolver.reload().unwrap());
+        let (_, renewed) = write_cert(dir.path(), "b", &["b.test"]);
+        assert!(resolver.reload().unwrap());
+        assert_eq!(served(Some("b.test")), renewed);
+
+        // A half-written renewal leaves the loaded certificates in place
+        std::fs::write(&a.key, "").unwrap();
+        let err = resolver.reload().unwrap_err();
+        assert!(err.to_string().contains("No private key found"), "{err}");
+        assert_eq!(served(Some("a.test")), a_der);
+
+        // A key from another pair is refused
+        let (c, _) = write_cert(dir.path(), "c", &["c.test"]);
+        let mismatched = CertSource {
+            cert: b.cert.clone(),
+            key: c.key,
+        };
+        let err = CertResolver::load(vec![mismatched]).unwrap_err();
+        assert!(err.to_string().contains("doesn't match"), "{err}");
+    }
+
     #[tokio::test]
     async fn test_bind_tcp() {
         exercise_listener("127.0.0.1:0").await;
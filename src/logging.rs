@@ -29,6 +29,10 @@ pub struct StartupOptions {
     pub expose: Option<String>,
     pub services: bool,
     pub datastar: bool,
+    /// Addresses listened on besides the first, as URLs or socket paths
+    pub listen: Vec<String>,
+    /// Whether the plain TCP ones among `listen` redirect to HTTPS
+    pub redirect_https: bool,
 }
 
 // --- Token bucket rate limiter ---
@@ -346,6 +350,8 @@ pub fn run_jsonl_handler(rx: broadcast::Receiver<Event>) -> std::thread::JoinHan
                         "startup_ms": startup_ms,
                         "watch": options.watch,
                         "tls": options.tls,
+                        "listen": options.listen,
+                        "redirect_https": options.redirect_https,
                         "store": options.store,
                         "topic": options.topic,
                         "expose": options.expose,
@@ -557,6 +563,13 @@ pub fn run_human_handler(rx: broadcast::Receiver<Event>) -> std::thread::JoinHan
 
                     // Build options line: [http-nu opts] │ xs [store] [expose] [services]
                     let mut http_opts = Vec::new();
+                    for addr in &options.listen {
+                        if options.redirect_https && !addr.starts_with('/') {
+                            http_opts.push(format!("{addr}→https"));
+                        } else {
+                            http_opts.push(addr.clone());
+                        }
+                    }
                     if options.watch {
                         http_opts.push("watch".to_string());
                     }
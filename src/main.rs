@@ -8,17 +8,22 @@ use std::time::Duration;
 
 use arc_swap::ArcSwap;
 use clap::Parser;
+use http_body_util::combinators::BoxBody;
 use http_nu::{
+    compression::CompressionConfig,
+    dev_cert::{self, DevCert},
     engine::{script_to_engine, HttpNuOptions},
-    handler::{handle, AppConfig},
-    listener::TlsConfig,
+    handler::{handle, redirect_to_https, AppConfig},
+    listener::{self, CertSource, ClientAuth, TlsConfig},
     logging::{
         init_broadcast, log_reloaded, log_started, log_stop_timed_out, log_stopped, log_stopping,
         run_human_handler, run_jsonl_handler, shutdown, StartupOptions,
     },
+    proxy::{ProxyClient, ProxyOptions},
     store::Store,
     Engine, Listener,
 };
+use hyper::body::{Bytes, Incoming};
 use hyper::service::service_fn;
 use hyper_util::rt::{TokioExecutor, TokioIo};
 use hyper_util::server::conn::auto::Builder as HttpConnectionBuilder;
@@ -37,9 +42,46 @@ struct Args {
     #[clap(value_parser)]
     addr: Option<String>,
 
-    /// Path to PEM file containing certificate and private key
-    #[clap(short, long)]
-    tls: Option<PathBuf>,
+    /// Also listen on this address, over plain HTTP: [HOST]:PORT or <PATH> (can be repeated)
+    #[clap(long, value_name = "ADDR")]
+    listen: Vec<String>,
+
+    /// Also listen on this address over TLS, with ADDR's certificates: [HOST]:PORT (can be repeated)
+    #[clap(long, value_name = "ADDR")]
+    listen_tls: Vec<String>,
+
+    /// Answer requests to --listen TCP addresses with a redirect to HTTPS on ADDR
+    #[clap(long, requires = "listen")]
+    redirect_https: bool,
+
+    /// Path to PEM file containing certificate and private key (can be repeated)
+    #[clap(short, long, value_name = "PEM")]
+    tls: Vec<PathBuf>,
+
+    /// Certificate chain (PEM), paired with the --tls-key in the same position (can be repeated)
+    #[clap(long, value_name = "PATH", requires = "tls_key")]
+    tls_cert: Vec<PathBuf>,
+
+    /// Private key (PEM) for the --tls-cert in the same position (can be repeated)
+    #[clap(long, value_name = "PATH", requires = "tls_cert")]
+    tls_key: Vec<PathBuf>,
+
+    /// Ask clients for a certificate issued by a CA in this bundle (PEM), exposed as $req.tls.client_cert
+    #[clap(long, value_name = "PEM")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// Whether clients must present a certificate, under --tls-client-ca
+    #[clap(
+        long,
+        value_name = "MODE",
+        default_value = "required",
+        requires = "tls_client_ca"
+    )]
+    tls_client_auth: ClientAuthMode,
+
+    /// Serve a localhost certificate from a local development CA, created on first use (see `http-nu cert generate`)
+    #[clap(long, conflicts_with_all = ["tls", "tls_cert"])]
+    tls_self_signed: bool,
 
     /// Load a Nushell plugin from the specified path (can be used multiple times)
     #[clap(long = "plugin", global = true, value_parser)]
@@ -102,15 +144,72 @@ struct Args {
     #[clap(long)]
     datastar: bool,
 
-    /// Trust proxies from these CIDR ranges for X-Forwarded-For parsing
+    /// Add a strong ETag to buffered responses and answer If-None-Match with 304
+    #[clap(long)]
+    etag: bool,
+
+    /// Don't compress buffered responses smaller than this
+    #[clap(long, value_name = "SIZE", default_value = "1kb", value_parser = http_nu::request::parse_byte_size, help_heading = "Compression")]
+    compress_min_size: u64,
+
+    /// Brotli quality (0-11)
+    #[clap(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(0..=11), help_heading = "Compression")]
+    brotli_quality: u32,
+
+    /// Zstandard level (1-22)
+    #[clap(long, default_value_t = 3, value_parser = clap::value_parser!(i32).range(1..=22), help_heading = "Compression")]
+    zstd_level: i32,
+
+    /// Gzip level (0-9)
+    #[clap(long, default_value_t = 6, value_parser = clap::value_parser!(u32).range(0..=9), help_heading = "Compression")]
+    gzip_level: u32,
+
+    /// Compress responses of this content type, replacing the built-in text-like list (can be repeated; `text/*` and `*/*+json` patterns work)
+    #[clap(
+        long = "compress-type",
+        value_name = "MIME",
+        help_heading = "Compression"
+    )]
+    compress_types: Vec<String>,
+
+    /// Trust proxies from these CIDR ranges for Forwarded/X-Forwarded-* parsing
     #[clap(long = "trust-proxy", value_name = "CIDR")]
     trust_proxies: Vec<ipnet::IpNet>,
 
+    /// Reject request bodies larger than this with 413 (e.g. 10mb, 512KiB)
+    #[clap(long, value_name = "SIZE", value_parser = http_nu::request::parse_byte_size)]
+    max_body_size: Option<u64>,
+
+    /// Answer 408 if the request body takes longer than this to arrive (e.g. 30s)
+    #[clap(long, value_name = "DURATION", value_parser = http_nu::request::parse_duration)]
+    body_timeout: Option<Duration>,
+
+    /// Extra CA bundle (PEM) to trust for https:// upstreams of .reverse-proxy
+    #[clap(long, value_name = "PATH", help_heading = "Reverse proxy")]
+    proxy_ca: Option<PathBuf>,
+
+    /// Answer 504 if connecting to an upstream takes longer than this
+    #[clap(long, value_name = "DURATION", default_value = "10s", value_parser = http_nu::request::parse_duration, help_heading = "Reverse proxy")]
+    proxy_connect_timeout: Duration,
+
+    /// Answer 504 if an upstream takes longer than this to send response headers
+    #[clap(long, value_name = "DURATION", default_value = "60s", value_parser = http_nu::request::parse_duration, help_heading = "Reverse proxy")]
+    proxy_timeout: Duration,
+
     /// Set NU_LIB_DIRS for module resolution (can be repeated)
     #[clap(short = 'I', long = "include-path", global = true, value_name = "PATH")]
     include_paths: Vec<PathBuf>,
 }
 
+#[derive(Clone, Debug, Default, PartialEq, clap::ValueEnum)]
+enum ClientAuthMode {
+    /// Refuse clients without a valid certificate
+    #[default]
+    Required,
+    /// Verify a certificate if one is sent, but let clients without one in
+    Optional,
+}
+
 #[derive(Clone, Debug, Default, clap::ValueEnum)]
 enum LogFormat {
     #[default]
@@ -144,6 +243,31 @@ enum Command {
         #[clap(long)]
         datastar: bool,
     },
+    /// Manage the development certificate served by --tls-self-signed
+    Cert {
+        #[command(subcommand)]
+        command: CertCommand,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum CertCommand {
+    /// Create the local CA and localhost certificate, or reuse them, and print their paths
+    Generate {
+        /// Directory to keep them in [default: $XDG_STATE_HOME/http-nu/certs]
+        #[clap(long, value_name = "DIR")]
+        dir: Option<PathBuf>,
+    },
+}
+
+/// `dir`, or where development certificates are kept by default
+fn dev_cert_dir(dir: Option<PathBuf>) -> PathBuf {
+    dir.or_else(dev_cert::default_dir).unwrap_or_else(|| {
+        eprintln!(
+            "Error: no state directory for the development certificate: set HOME or XDG_STATE_HOME"
+        );
+        std::process::exit(1);
+    })
 }
 
 /// Creates and configures the base engine with all commands, signals, and ctrlc handler.
@@ -321,13 +445,13 @@ async fn stdin_source(watch: bool, base_engine: Engine, tx: mpsc::Sender<Engine>
 }
 
 async fn serve(
-    addr: String,
-    tls: Option<PathBuf>,
+    listen: Vec<(String, Option<TlsConfig>)>,
+    redirect_https: bool,
     mut rx: mpsc::Receiver<Engine>,
     interrupt: Arc<AtomicBool>,
     config: AppConfig,
     start_time: std::time::Instant,
-    startup_options: StartupOptions,
+    mut startup_options: StartupOptions,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let shutdown = shutdown_signal(interrupt.clone());
     tokio::pin!(shutdown);
@@ -356,33 +480,47 @@ async fn serve(
         }
     });
 
-    // Configure TLS if enabled
-    let tls_config = if let Some(pem_path) = tls {
-        Some(TlsConfig::from_pem(pem_path)?)
-    } else {
-        None
-    };
-
-    let tls_enabled = tls_config.is_some();
-    let mut listener = Listener::bind(&addr, tls_config).await?;
+    let mut listeners = Vec::new();
+    for (addr, tls_config) in listen {
+        listeners.push(Listener::bind(&addr, tls_config).await?);
+    }
     let startup_ms = start_time.elapsed().as_millis();
-    let addr_display = {
-        let raw = format!("{listener}");
-        // Format TCP addresses as clickable URLs, leave Unix sockets as-is
-        if raw.starts_with('/') {
-            raw
-        } else {
-            // Strip " (TLS)" suffix from Listener's Display
-            let addr = raw.strip_suffix(" (TLS)").unwrap_or(&raw);
-            if tls_enabled {
-                format!("https://{addr}")
-            } else {
-                format!("http://{addr}")
-            }
-        }
-    };
+    let mut urls = listeners.iter().map(listener_url);
+    let addr_display = urls.next().unwrap_or_default();
+    startup_options.listen = urls.collect();
+    startup_options.redirect_https = redirect_https;
     log_started(&addr_display, startup_ms, startup_options);
 
+    // Under --redirect-https, plain TCP listeners send everything to this port
+    let https_port = listeners
+        .iter()
+        .find(|listener| listener.is_tls())
+        .and_then(Listener::port)
+        .filter(|_| redirect_https);
+
+    // Each listener accepts on its own task; connections are all served below
+    let (conn_tx, mut conn_rx) = mpsc::channel(64);
+    let accepting: Vec<_> = listeners
+        .into_iter()
+        .map(|mut listener| {
+            let redirect = https_port.filter(|_| !listener.is_tls() && listener.port().is_some());
+            let conn_tx = conn_tx.clone();
+            tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok(conn) => {
+                            if conn_tx.send((conn, redirect)).await.is_err() {
+                                break;
+                            }
+                        }
+                        Err(err) => eprintln!("Error accepting connection: {err}"),
+                    }
+                }
+            })
+        })
+        .collect();
+    drop(conn_tx);
+
     // HTTP/1 + HTTP/2 auto-detection builder
     let http_builder = HttpConnectionBuilder::new(TokioExecutor::new());
 
@@ -394,48 +532,46 @@ async fn serve(
 
     loop {
         tokio::select! {
-            result = listener.accept() => {
-                match result {
-                    Ok((stream, remote_addr)) => {
-                        let io = TokioIo::new(stream);
-                        let engine = engine.clone();
-                        let config = config.clone();
-
-                        let service = service_fn(move |req| {
-                            handle(engine.clone(), remote_addr, config.clone(), req)
-                        });
-
-                        // serve_connection_with_upgrades supports HTTP/1 and HTTP/2
-                        let conn = http_builder.serve_connection_with_upgrades(io, service);
-
-                        // Watch this connection for graceful shutdown
-                        let conn = graceful.watch(conn.into_owned());
-
-                        tokio::task::spawn(async move {
-                            if let Err(err) = conn.await {
-                                // Suppress errors normal for client disconnect
-                                if let Some(hyper_err) = err.downcast_ref::<hyper::Error>() {
-                                    if hyper_err.is_incomplete_message()
-                                        || hyper_err.is_body_write_aborted()
-                                    {
-                                        return;
-                                    }
-                                }
-                                eprintln!("Connection error: {err}");
-                            }
-                        });
+            Some(((stream, remote_addr, tls_info), redirect)) = conn_rx.recv() => {
+                let io = TokioIo::new(stream);
+                let engine = engine.clone();
+                let config = config.clone();
+
+                let service = service_fn(move |mut req: hyper::Request<Incoming>| {
+                    if let Some(tls_info) = &tls_info {
+                        req.extensions_mut().insert(tls_info.clone());
                     }
-                    Err(err) => {
-                        eprintln!("Error accepting connection: {err}");
-                        continue;
+                    serve_request(engine.clone(), remote_addr, config.clone(), redirect, req)
+                });
+
+                // serve_connection_with_upgrades supports HTTP/1 and HTTP/2
+                let conn = http_builder.serve_connection_with_upgrades(io, service);
+
+                // Watch this connection for graceful shutdown
+                let conn = graceful.watch(conn.into_owned());
+
+                tokio::task::spawn(async move {
+                    if let Err(err) = conn.await {
+                        // Suppress errors normal for client disconnect
+                        if let Some(hyper_err) = err.downcast_ref::<hyper::Error>() {
+                            if hyper_err.is_incomplete_message()
+                                || hyper_err.is_body_write_aborted()
+                            {
+                                return;
+                            }
+                        }
+                        eprintln!("Connection error: {err}");
                     }
-                }
+                });
             }
             _ = &mut shutdown => {
                 break;
             }
         }
     }
+    for task in accepting {
+        task.abort();
+    }
 
     // Cancel SSE streams so they don't hold connections open.
     // New connections are no longer accepted (broke out of accept loop above),
@@ -466,6 +602,35 @@ async fn serve(
     Ok(())
 }
 
+/// A request to a listener: handled by the script, or redirected to HTTPS on
+/// the port in `redirect`
+async fn serve_request(
+    engine: Arc<ArcSwap<Engine>>,
+    remote_addr: Option<std::net::SocketAddr>,
+    config: Arc<AppConfig>,
+    redirect: Option<u16>,
+    req: hyper::Request<Incoming>,
+) -> Result<hyper::Response<BoxBody<Bytes, http_nu::Error>>, http_nu::Error> {
+    match redirect {
+        Some(port) => redirect_to_https(&req, port),
+        None => handle(engine, remote_addr, config, req).await,
+    }
+}
+
+/// How a listener is shown at startup: a URL for TCP, the path for a Unix
+/// socket
+fn listener_url(listener: &Listener) -> String {
+    let raw = format!("{listener}");
+    if listener.port().is_none() {
+        raw
+    } else if listener.is_tls() {
+        // Strip " (TLS)" suffix from Listener's Display
+        format!("https://{}", raw.strip_suffix(" (TLS)").unwrap_or(&raw))
+    } else {
+        format!("http://{raw}")
+    }
+}
+
 async fn shutdown_signal(interrupt: Arc<AtomicBool>) {
     use tokio::time::{interval, Duration};
 
@@ -557,6 +722,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let _stor_db = nu_command::open_connection_in_memory_custom()?;
 
     // Handle subcommands
+    if let Some(Command::Cert {
+        command: CertCommand::Generate { dir },
+    }) = args.command
+    {
+        let cert = DevCert::ensure(&dev_cert_dir(dir))?;
+        println!("CA (trust this): {}", cert.ca.display());
+        println!("Certificate:     {}", cert.source.cert.display());
+        println!("Key:             {}", cert.source.key.display());
+        shutdown();
+        log_handle.join().ok();
+        return Ok(());
+    }
+
     if let Some(Command::Eval {
         file,
         commands,
@@ -655,6 +833,72 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         std::process::exit(1);
     };
 
+    // Certificates: combined PEMs, then --tls-cert/--tls-key pairs. The first
+    // is served to clients whose SNI matches none of them.
+    if args.tls_cert.len() != args.tls_key.len() {
+        eprintln!("Error: each --tls-cert needs a matching --tls-key");
+        std::process::exit(1);
+    }
+    let dev_cert = if args.tls_self_signed {
+        let cert = DevCert::ensure(&dev_cert_dir(None))?;
+        eprintln!(
+            "Serving a development certificate for {}; trust {} to avoid warnings",
+            dev_cert::NAMES.join(", "),
+            cert.ca.display()
+        );
+        Some(cert.source)
+    } else {
+        None
+    };
+    let tls: Vec<CertSource> = args
+        .tls
+        .iter()
+        .cloned()
+        .map(CertSource::pem)
+        .chain(
+            args.tls_cert
+                .iter()
+                .zip(&args.tls_key)
+                .map(|(cert, key)| CertSource {
+                    cert: cert.clone(),
+                    key: key.clone(),
+                }),
+        )
+        .chain(dev_cert)
+        .collect();
+    if args.tls_client_ca.is_some() && tls.is_empty() {
+        eprintln!("Error: --tls-client-ca needs a certificate: --tls, --tls-cert/--tls-key or --tls-self-signed");
+        std::process::exit(1);
+    }
+    if !args.listen_tls.is_empty() && tls.is_empty() {
+        eprintln!("Error: --listen-tls needs a certificate: --tls, --tls-cert/--tls-key or --tls-self-signed");
+        std::process::exit(1);
+    }
+    if args
+        .listen_tls
+        .iter()
+        .any(|addr| listener::is_socket_path(addr))
+    {
+        eprintln!(
+            "Error: --listen-tls needs a TCP address, TLS is not supported with Unix sockets"
+        );
+        std::process::exit(1);
+    }
+    if args.redirect_https && tls.is_empty() {
+        eprintln!("Error: --redirect-https needs TLS on ADDR: --tls, --tls-cert/--tls-key or --tls-self-signed");
+        std::process::exit(1);
+    }
+    if args.redirect_https && listener::is_socket_path(&addr) {
+        eprintln!("Error: --redirect-https needs ADDR to be a TCP address to redirect to, not a Unix socket");
+        std::process::exit(1);
+    }
+    let tls_display = (!tls.is_empty()).then(|| {
+        tls.iter()
+            .map(|source| source.cert.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    });
+
     // Create channel for engines
     let (tx, rx) = mpsc::channel::<Engine>(1);
 
@@ -680,7 +924,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         dev: args.dev,
         datastar: args.datastar,
         watch: args.watch,
-        tls: args.tls.as_ref().map(|p| p.display().to_string()),
+        tls: tls_display.clone(),
         #[cfg(feature = "cross-stream")]
         store: args.store.as_ref().map(|p| p.display().to_string()),
         #[cfg(not(feature = "cross-stream"))]
@@ -757,9 +1001,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         }
     }
 
+    let proxy = ProxyClient::new(&ProxyOptions {
+        ca_file: args.proxy_ca.clone(),
+        connect_timeout: Some(args.proxy_connect_timeout),
+        response_timeout: Some(args.proxy_timeout),
+    })?;
+
     let startup_options = StartupOptions {
         watch: args.watch,
-        tls: args.tls.as_ref().map(|p| p.display().to_string()),
+        tls: tls_display,
         #[cfg(feature = "cross-stream")]
         store: args.store.as_ref().map(|p| p.display().to_string()),
         #[cfg(not(feature = "cross-stream"))]
@@ -777,17 +1027,57 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         #[cfg(not(feature = "cross-stream"))]
         services: false,
         datastar: args.datastar,
+        listen: Vec::new(),
+        redirect_https: false,
     };
 
+    // Configure TLS if enabled, picking up renewed certificates as they land
+    let tls_config = if tls.is_empty() {
+        None
+    } else {
+        let client_auth = args.tls_client_ca.clone().map(|ca| ClientAuth {
+            ca,
+            required: args.tls_client_auth == ClientAuthMode::Required,
+        });
+        let tls_config = TlsConfig::new(tls, client_auth.as_ref())?;
+        tls_config.resolver.watch();
+        Some(tls_config)
+    };
+
+    // --listen-tls addresses share ADDR's certificates, resolver and reloads
+    let listen_tls = args
+        .listen_tls
+        .iter()
+        .map(|addr| (addr.clone(), tls_config.clone()))
+        .collect::<Vec<_>>();
+    let listen = std::iter::once((addr, tls_config))
+        .chain(args.listen.iter().map(|addr| (addr.clone(), None)))
+        .chain(listen_tls)
+        .collect();
     serve(
-        addr,
-        args.tls,
+        listen,
+        args.redirect_https,
         rx,
         interrupt,
         AppConfig {
             trusted_proxies: args.trust_proxies,
             datastar: args.datastar,
             dev: args.dev,
+            max_body_size: args.max_body_size,
+            body_timeout: args.body_timeout,
+            etag: args.etag,
+            compression: CompressionConfig {
+                min_size: args.compress_min_size as usize,
+                brotli_quality: args.brotli_quality,
+                zstd_level: args.zstd_level,
+                gzip_level: args.gzip_level,
+                types: if args.compress_types.is_empty() {
+                    CompressionConfig::default().types
+                } else {
+                    args.compress_types
+                },
+            },
+            proxy,
         },
         start_time,
         startup_options,
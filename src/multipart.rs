@@ -0,0 +1,385 @@
+//! Blocking, streaming multipart/form-data parser (RFC 7578) used by
+//! `from multipart`. Part bodies are copied straight into a caller-supplied
+//! writer, so nothing larger than a read buffer is held in memory unless the
+//! caller chooses to.
+
+use std::io::{self, Read, Write};
+
+const READ_CHUNK: usize = 64 * 1024;
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+
+/// Headers of a single part, parsed once its header block has been read.
+#[derive(Debug, Default)]
+pub struct PartHeaders {
+    /// `name` from Content-Disposition
+    pub name: Option<String>,
+    /// `filename` from Content-Disposition; set for file uploads
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    /// All headers, names lowercased, in order
+    pub headers: Vec<(String, String)>,
+}
+
+enum State {
+    /// Before the first delimiter
+    Preamble,
+    /// Positioned at the start of a part's header block
+    Headers,
+    /// Positioned inside a part's body
+    Body,
+    Done,
+}
+
+pub struct MultipartReader<R> {
+    reader: R,
+    /// `\r\n--boundary`, or `None` until sniffed from the first line
+    delimiter: Option<Vec<u8>>,
+    buf: Vec<u8>,
+    eof: bool,
+    state: State,
+}
+
+/// Extract the boundary from a Content-Type value, or accept a bare boundary.
+pub fn boundary_from(value: &str) -> String {
+    match value.find("boundary=") {
+        Some(idx) => {
+            let rest = &value[idx + "boundary=".len()..];
+            let rest = rest.split(';').next().unwrap_or_default().trim();
+            rest.trim_matches('"').to_string()
+        }
+        None => value.trim().to_string(),
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+impl<R: Read> MultipartReader<R> {
+    /// Without a boundary, it is taken from the body's first line.
+    pub fn new(reader: R, boundary: Option<&str>) -> Self {
+        Self {
+            reader,
+            delimiter: boundary.map(|b| format!("\r\n--{b}").into_bytes()),
+            // A leading CRLF lets the first delimiter match like any other
+            buf: b"\r\n".to_vec(),
+            eof: false,
+            state: State::Preamble,
+        }
+    }
+
+    /// Read more input into `buf`; false at end of input.
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+        let start = self.buf.len();
+        self.buf.resize(start + READ_CHUNK, 0);
+        let n = loop {
+            match self.reader.read(&mut self.buf[start..]) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.buf.truncate(start);
+                    return Err(e);
+                }
+            }
+        };
+        self.buf.truncate(start + n);
+        self.eof = n == 0;
+        Ok(n > 0)
+    }
+
+    fn sniff_boundary(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(end) = find(&self.buf[2..], b"\r\n") {
+                let line = &self.buf[2..2 + end];
+                let boundary = line
+                    .strip_prefix(b"--")
+                    .filter(|b| !b.is_empty())
+                    .ok_or_else(|| invalid("multipart body does not start with a boundary"))?;
+                let mut delimiter = b"\r\n--".to_vec();
+                delimiter.extend_from_slice(boundary);
+                return Ok(delimiter);
+            }
+            if self.buf.len() > MAX_HEADER_BYTES || !self.fill()? {
+                return Err(invalid("multipart body does not start with a boundary"));
+            }
+        }
+    }
+
+    /// Consume everything up to and including the next delimiter, passing the
+    /// skipped bytes to `sink`, then what follows the delimiter up to its CRLF.
+    fn copy_until_delimiter(&mut self, sink: &mut dyn Write) -> io::Result<u64> {
+        let delimiter = match &self.delimiter {
+            Some(d) => d.clone(),
+            None => {
+                let d = self.sniff_boundary()?;
+                self.delimiter = Some(d.clone());
+                d
+            }
+        };
+        let mut written = 0u64;
+        loop {
+            if let Some(idx) = find(&self.buf, &delimiter) {
+                sink.write_all(&self.buf[..idx])?;
+                written += idx as u64;
+                self.buf.drain(..idx + delimiter.len());
+                break;
+            }
+            // Keep a tail that could be the start of a split delimiter
+            let keep = delimiter.len() - 1;
+            if self.buf.len() > keep {
+                let flush = self.buf.len() - keep;
+                sink.write_all(&self.buf[..flush])?;
+                written += flush as u64;
+                self.buf.drain(..flush);
+            }
+            if !self.fill()? {
+                return Err(invalid("multipart body ended before the closing boundary"));
+            }
+        }
+
+        // `--` marks the close delimiter; otherwise skip padding to the CRLF
+        while self.buf.len() < 2 && self.fill()? {}
+        if self.buf.starts_with(b"--") {
+            self.state = State::Done;
+            return Ok(written);
+        }
+        loop {
+            if let Some(end) = find(&self.buf, b"\r\n") {
+                if self.buf[..end].iter().any(|b| *b != b' ' && *b != b'\t') {
+                    return Err(invalid("unexpected data after multipart boundary"));
+                }
+                self.buf.drain(..end + 2);
+                break;
+            }
+            if self.buf.len() > MAX_HEADER_BYTES || !self.fill()? {
+                return Err(invalid("multipart body ended after a boundary"));
+            }
+        }
+        self.state = State::Headers;
+        Ok(written)
+    }
+
+    fn read_headers(&mut self) -> io::Result<PartHeaders> {
+        let end = loop {
+            // An empty header block is just the blank line
+            if self.buf.starts_with(b"\r\n") {
+                break 0;
+            }
+            if let Some(end) = find(&self.buf, b"\r\n\r\n") {
+                break end + 2;
+            }
+            if self.buf.len() > MAX_HEADER_BYTES {
+                return Err(invalid("multipart part headers too large"));
+            }
+            if !self.fill()? {
+                return Err(invalid("multipart body ended inside part headers"));
+            }
+        };
+        let block = String::from_utf8_lossy(&self.buf[..end]).into_owned();
+        self.buf.drain(..end + 2);
+
+        let mut part = PartHeaders::default();
+        for line in block.split("\r\n").filter(|l| !l.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(invalid(format!("malformed multipart header: {line}")));
+            };
+            let name = name.trim().to_ascii_lowercase();
+            let value = value.trim().to_string();
+            match name.as_str() {
+                "content-disposition" => {
+                    for (key, val) in disposition_params(&value) {
+                        match key.as_str() {
+                            "name" => part.name = Some(val),
+                            "filename" => part.filename = Some(val),
+                            _ => {}
+                        }
+                    }
+                }
+                "content-type" => part.content_type = Some(value.clone()),
+                _ => {}
+            }
+            part.headers.push((name, value));
+        }
+        Ok(part)
+    }
+
+    /// Advance to the next part and return its headers, or `None` after the
+    /// last one. An unread body of the previous part is skipped.
+    pub fn next_part(&mut self) -> io::Result<Option<PartHeaders>> {
+        if matches!(self.state, State::Preamble | State::Body) {
+            self.copy_until_delimiter(&mut io::sink())?;
+        }
+        match self.state {
+            State::Done => Ok(None),
+            _ => {
+                let part = self.read_headers()?;
+                self.state = State::Body;
+                Ok(Some(part))
+            }
+        }
+    }
+
+    /// Stream the current part's body into `sink`, returning its length.
+    pub fn copy_body(&mut self, sink: &mut dyn Write) -> io::Result<u64> {
+        match self.state {
+            State::Body => self.copy_until_delimiter(sink),
+            _ => Ok(0),
+        }
+    }
+}
+
+/// Parse `form-data; name="a"; filename="b.txt"` into lowercased key/value pairs.
+fn disposition_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = value.chars().peekable();
+    // Skip the disposition type
+    for c in chars.by_ref() {
+        if c == ';' {
+            break;
+        }
+    }
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ';').is_some() {}
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| *c != '=' && *c != ';') {
+            key.push(c);
+        }
+        if key.is_empty() {
+            break;
+        }
+        let mut val = String::new();
+        if chars.next_if_eq(&'=').is_some() {
+            if chars.next_if_eq(&'"').is_some() {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => val.extend(chars.next()),
+                        c => val.push(c),
+                    }
+                }
+            } else {
+                while let Some(c) = chars.next_if(|c| *c != ';') {
+                    val.push(c);
+                }
+                val = val.trim().to_string();
+            }
+        }
+        params.push((key.trim().to_ascii_lowercase(), val));
+    }
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &str = "preamble\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\
+        \r\n\
+        hello\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"upload\"; filename=\"a \\\"b\\\".txt\"\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        line one\r\nline two\r\n\
+        --XyZ--\r\n";
+
+    fn parse(body: &[u8], boundary: Option<&str>) -> Vec<(PartHeaders, Vec<u8>)> {
+        let mut reader = MultipartReader::new(body, boundary);
+        let mut parts = Vec::new();
+        while let Some(part) = reader.next_part().unwrap() {
+            let mut out = Vec::new();
+            reader.copy_body(&mut out).unwrap();
+            parts.push((part, out));
+        }
+        parts
+    }
+
+    #[test]
+    fn test_parse_fields_and_files() {
+        let parts = parse(BODY.as_bytes(), Some("XyZ"));
+        assert_eq!(parts.len(), 2);
+
+        let (title, body) = &parts[0];
+        assert_eq!(title.name.as_deref(), Some("title"));
+        assert_eq!(title.filename, None);
+        assert_eq!(body, b"hello");
+
+        let (upload, body) = &parts[1];
+        assert_eq!(upload.name.as_deref(), Some("upload"));
+        assert_eq!(upload.filename.as_deref(), Some("a \"b\".txt"));
+        assert_eq!(upload.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(upload.headers.len(), 2);
+        assert_eq!(body, b"line one\r\nline two");
+    }
+
+    #[test]
+    fn test_sniffs_boundary_and_skips_unread_bodies() {
+        let body = BODY.strip_prefix("preamble\r\n").unwrap();
+        let mut reader = MultipartReader::new(body.as_bytes(), None);
+        assert_eq!(
+            reader.next_part().unwrap().unwrap().name.as_deref(),
+            Some("title")
+        );
+        assert_eq!(
+            reader.next_part().unwrap().unwrap().name.as_deref(),
+            Some("upload")
+        );
+        assert!(reader.next_part().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_delimiter_split_across_reads() {
+        // One byte per read exercises every possible split point
+        struct Trickle<'a>(&'a [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.0.len().min(buf.len()).min(1);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+        let mut reader = MultipartReader::new(Trickle(BODY.as_bytes()), Some("XyZ"));
+        let mut bodies = Vec::new();
+        while reader.next_part().unwrap().is_some() {
+            let mut out = Vec::new();
+            reader.copy_body(&mut out).unwrap();
+            bodies.push(out);
+        }
+        assert_eq!(
+            bodies,
+            vec![b"hello".to_vec(), b"line one\r\nline two".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_truncated_body_is_an_error() {
+        let body = &BODY.as_bytes()[..BODY.len() - 12];
+        let mut reader = MultipartReader::new(body, Some("XyZ"));
+        reader.next_part().unwrap();
+        reader.next_part().unwrap();
+        assert!(reader.copy_body(&mut io::sink()).is_err());
+    }
+
+    #[test]
+    fn test_boundary_from_content_type() {
+        assert_eq!(
+            boundary_from("multipart/form-data; boundary=\"abc 123\""),
+            "abc 123"
+        );
+        assert_eq!(
+            boundary_from("multipart/form-data; boundary=xyz; x=y"),
+            "xyz"
+        );
+        assert_eq!(boundary_from("xyz"), "xyz");
+    }
+}
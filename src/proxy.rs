@@ -0,0 +1,565 @@
+use std::future::Future;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use http_body_util::combinators::BoxBody;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::upgrade::OnUpgrade;
+use hyper::Uri;
+use hyper_rustls::{HttpsConnector, MaybeHttpsStream};
+use hyper_util::client::legacy::connect::{Connected, Connection, HttpConnector};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use tokio_util::sync::CancellationToken;
+
+use crate::listener::{connect_unix, AsyncReadWriteBox};
+use crate::logging::log_error;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Request body forwarded to the upstream
+pub type ProxyBody = BoxBody<Bytes, BoxError>;
+
+#[derive(Clone, Debug)]
+pub struct ProxyOptions {
+    /// Extra PEM CA bundle trusted for `https://` upstreams
+    pub ca_file: Option<PathBuf>,
+    /// Time allowed to establish the upstream connection (TCP + TLS)
+    pub connect_timeout: Option<Duration>,
+    /// Time allowed for the upstream to send response headers
+    pub response_timeout: Option<Duration>,
+}
+
+impl Default for ProxyOptions {
+    fn default() -> Self {
+        Self {
+            ca_file: None,
+            connect_timeout: Some(Duration::from_secs(10)),
+            response_timeout: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProxyError {
+    /// Connect or response timeout elapsed (504)
+    Timeout,
+    /// Any other upstream failure (502)
+    Upstream(BoxError),
+}
+
+impl std::fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyError::Timeout => write!(f, "upstream timed out"),
+            ProxyError::Upstream(err) => write!(f, "upstream error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+/// Pooled HTTP/HTTPS client shared by every `.reverse-proxy` call
+#[derive(Clone)]
+pub struct ProxyClient {
+    client: Client<UpstreamConnector, ProxyBody>,
+    /// HTTP/1.1 only: the upgrade handshake doesn't exist in HTTP/2
+    upgrade_client: Client<UpstreamConnector, ProxyBody>,
+    response_timeout: Option<Duration>,
+}
+
+impl ProxyClient {
+    pub fn new(options: &ProxyOptions) -> Result<Self, BoxError> {
+        let mut roots = rustls::RootCertStore::empty();
+        let native = rustls_native_certs::load_native_certs();
+        roots.add_parsable_certificates(native.certs);
+        if roots.is_empty() {
+            // Minimal containers often ship without a system trust store
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        }
+        if let Some(path) = &options.ca_file {
+            let pem = std::fs::read(path)
+                .map_err(|e| format!("failed to read CA file {}: {e}", path.display()))?;
+            let certs = rustls_pemfile::certs(&mut pem.as_slice())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("failed to parse CA file {}: {e}", path.display()))?;
+            if certs.is_empty() {
+                return Err(format!("no certificates found in {}", path.display()).into());
+            }
+            for cert in certs {
+                roots.add(cert)?;
+            }
+        }
+
+        // Explicit provider: the process default isn't installed in tests
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let tls = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        http.set_nodelay(true);
+        http.set_connect_timeout(options.connect_timeout);
+
+        let connector = UpstreamConnector {
+            https: hyper_rustls::HttpsConnectorBuilder::new()
+                .with_tls_config(tls.clone())
+                .https_or_http()
+                .enable_http1()
+                .enable_http2()
+                .wrap_connector(http.clone()),
+            connect_timeout: options.connect_timeout,
+        };
+        let upgrade_connector = UpstreamConnector {
+            https: hyper_rustls::HttpsConnectorBuilder::new()
+                .with_tls_config(tls)
+                .https_or_http()
+                .enable_http1()
+                .wrap_connector(http),
+            connect_timeout: options.connect_timeout,
+        };
+
+        let client = Client::builder(TokioExecutor::new())
+            .pool_idle_timeout(Duration::from_secs(90))
+            .build(connector);
+        let upgrade_client = Client::builder(TokioExecutor::new())
+            .pool_idle_timeout(Duration::from_secs(90))
+            .build(upgrade_connector);
+
+        Ok(Self {
+            client,
+            upgrade_client,
+            response_timeout: options.response_timeout,
+        })
+    }
+
+    /// Send `req` upstream, waiting at most `timeout` (or the configured
+    /// response timeout) for the response headers.
+    pub async fn request(
+        &self,
+        req: hyper::Request<ProxyBody>,
+        timeout: Option<Duration>,
+    ) -> Result<hyper::Response<Incoming>, ProxyError> {
+        let client = if is_upgrade_request(req.headers()) {
+            &self.upgrade_client
+        } else {
+            &self.client
+        };
+        let fut = client.request(req);
+        let result = match timeout.or(self.response_timeout) {
+            Some(timeout) => tokio::time::timeout(timeout, fut)
+                .await
+                .map_err(|_| ProxyError::Timeout)?,
+            None => fut.await,
+        };
+        result.map_err(|err| {
+            if is_timeout(&err) {
+                ProxyError::Timeout
+            } else {
+                ProxyError::Upstream(err.into())
+            }
+        })
+    }
+}
+
+/// Internal scheme for Unix socket upstreams; the authority is the hex-encoded
+/// socket path, so each socket gets its own connection pool.
+const UNIX_SCHEME: &str = "unix";
+
+/// Split `unix:///run/app.sock[:/base]` into the socket path and base path.
+/// The split is at the last `:/`, so the socket path may contain colons.
+fn unix_target(target: &str) -> Option<(&str, &str)> {
+    let rest = target.strip_prefix("unix://")?;
+    Some(match rest.rfind(":/") {
+        Some(i) => (&rest[..i], &rest[i + 1..]),
+        None => (rest, ""),
+    })
+}
+
+/// The URI to request from `target` (`http(s)://host[:port][/base]` or
+/// `unix:///path/to.sock[:/base]`) for `path_and_query`.
+pub fn upstream_uri(target: &str, path_and_query: &str) -> Result<Uri, BoxError> {
+    let uri = match unix_target(target) {
+        Some((socket, base)) => {
+            let host: String = socket.bytes().map(|b| format!("{b:02x}")).collect();
+            format!("{UNIX_SCHEME}://{host}{base}{path_and_query}")
+        }
+        None => format!("{target}{path_and_query}"),
+    };
+    Ok(uri.parse()?)
+}
+
+/// Host header for `target` when the client's isn't preserved
+pub fn upstream_host(target: &str) -> Option<String> {
+    if unix_target(target).is_some() {
+        return Some("localhost".to_string());
+    }
+    let uri: Uri = target.parse().ok()?;
+    Some(uri.authority()?.to_string())
+}
+
+/// Connects `http`/`https` upstreams over TCP (+TLS) and `unix` ones to
+/// their socket.
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    https: HttpsConnector<HttpConnector>,
+    connect_timeout: Option<Duration>,
+}
+
+impl tower::Service<Uri> for UpstreamConnector {
+    type Response = UpstreamStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<UpstreamStream, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.https.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        if uri.scheme_str() != Some(UNIX_SCHEME) {
+            let connecting = self.https.call(uri);
+            return Box::pin(async move { Ok(UpstreamStream::Tcp(Box::new(connecting.await?))) });
+        }
+        let connect_timeout = self.connect_timeout;
+        Box::pin(async move {
+            let host = uri.host().unwrap_or_default();
+            let socket = (0..host.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(host.get(i..i + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .ok_or("invalid unix socket upstream")?;
+            let connecting = connect_unix(std::path::Path::new(&socket));
+            let stream = match connect_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, connecting)
+                    .await
+                    .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??,
+                None => connecting.await?,
+            };
+            Ok(UpstreamStream::Unix(TokioIo::new(stream)))
+        })
+    }
+}
+
+pub enum UpstreamStream {
+    Tcp(Box<MaybeHttpsStream<TokioIo<tokio::net::TcpStream>>>),
+    Unix(TokioIo<AsyncReadWriteBox>),
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        match self {
+            UpstreamStream::Tcp(stream) => stream.connected(),
+            UpstreamStream::Unix(_) => Connected::new(),
+        }
+    }
+}
+
+impl hyper::rt::Read for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: hyper::rt::ReadBufCursor<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl hyper::rt::Write for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// True if the request asks to switch protocols: `Connection: upgrade` plus
+/// an `Upgrade` header (WebSocket, h2c, ...).
+pub fn is_upgrade_request(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::UPGRADE)
+        && headers.get_all(header::CONNECTION).iter().any(|v| {
+            v.to_str()
+                .map(|s| {
+                    s.split(',')
+                        .any(|t| t.trim().eq_ignore_ascii_case("upgrade"))
+                })
+                .unwrap_or(false)
+        })
+}
+
+/// Tell the upstream about the hop from `peer` to us: append it to
+/// `X-Forwarded-For`, add an RFC 7239 `Forwarded` element, and set
+/// `X-Forwarded-Proto`/`X-Forwarded-Host` if no earlier proxy did. Unless
+/// the peer is a trusted proxy, whatever forwarding headers it sent
+/// (including `X-Real-IP`) are dropped first so clients can't spoof them.
+pub fn set_forwarded_headers(
+    headers: &mut HeaderMap,
+    peer: Option<IpAddr>,
+    peer_trusted: bool,
+    proto: &'static str,
+    host: Option<&str>,
+) {
+    const FORWARDED_HEADERS: [&str; 5] = [
+        "forwarded",
+        "x-forwarded-for",
+        "x-forwarded-proto",
+        "x-forwarded-host",
+        "x-real-ip",
+    ];
+    if !peer_trusted {
+        for name in FORWARDED_HEADERS {
+            headers.remove(name);
+        }
+    }
+
+    let append = |headers: &mut HeaderMap, name: &'static str, value: String| {
+        let mut values: Vec<&str> = headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+        values.push(&value);
+        if let Ok(value) = HeaderValue::from_str(&values.join(", ")) {
+            headers.insert(name, value);
+        }
+    };
+
+    if let Some(ip) = peer {
+        append(headers, "x-forwarded-for", ip.to_string());
+    }
+
+    let mut element = vec![match peer {
+        Some(IpAddr::V6(ip)) => format!("for=\"[{ip}]\""),
+        Some(ip) => format!("for={ip}"),
+        None => "for=unknown".to_string(),
+    }];
+    element.push(format!("proto={proto}"));
+    if let Some(host) = host {
+        element.push(format!("host={}", forwarded_value(host)));
+    }
+    append(headers, "forwarded", element.join(";"));
+
+    if !headers.contains_key("x-forwarded-proto") {
+        headers.insert("x-forwarded-proto", HeaderValue::from_static(proto));
+    }
+    if let Some(host) = host {
+        if !headers.contains_key("x-forwarded-host") {
+            if let Ok(value) = HeaderValue::from_str(host) {
+                headers.insert("x-forwarded-host", value);
+            }
+        }
+    }
+}
+
+/// A `Forwarded` parameter value: a bare token when possible, otherwise a
+/// quoted string (hosts with a port, IPv6 literals).
+fn forwarded_value(s: &str) -> String {
+    let is_tchar = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if !s.is_empty() && s.chars().all(is_tchar) {
+        s.to_string()
+    } else {
+        format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// Copy bytes both ways between the upgraded client and upstream connections
+/// until either side closes or `cancel` fires (--watch reload, shutdown).
+pub async fn splice(client: OnUpgrade, upstream: OnUpgrade, cancel: CancellationToken) {
+    let (client, upstream) = match tokio::try_join!(client, upstream) {
+        Ok(pair) => pair,
+        Err(err) => {
+            log_error(&format!("Proxy upgrade failed: {err}"));
+            return;
+        }
+    };
+    let mut client = TokioIo::new(client);
+    let mut upstream = TokioIo::new(upstream);
+    tokio::select! {
+        _ = cancel.cancelled() => {}
+        _ = tokio::io::copy_bidirectional(&mut client, &mut upstream) => {}
+    }
+}
+
+/// The connector reports its connect timeout as an `io::ErrorKind::TimedOut`
+/// somewhere down the source chain.
+fn is_timeout(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(io) = err.downcast_ref::<std::io::Error>() {
+            if io.kind() == std::io::ErrorKind::TimedOut {
+                return true;
+            }
+        }
+        source = err.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, Empty};
+
+    fn empty_body() -> ProxyBody {
+        Empty::new().map_err(|never| match never {}).boxed()
+    }
+
+    #[tokio::test]
+    async fn test_response_timeout() {
+        // Accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut conns = Vec::new();
+            while let Ok((conn, _)) = listener.accept().await {
+                conns.push(conn);
+            }
+        });
+
+        let client = ProxyClient::new(&ProxyOptions {
+            response_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        })
+        .unwrap();
+        let req = hyper::Request::get(format!("http://{addr}/"))
+            .body(empty_body())
+            .unwrap();
+        assert!(matches!(
+            client.request(req, None).await,
+            Err(ProxyError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn test_forwarded_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "6.6.6.6".parse().unwrap());
+        headers.insert("x-forwarded-proto", "https".parse().unwrap());
+        headers.insert("x-real-ip", "6.6.6.6".parse().unwrap());
+        set_forwarded_headers(
+            &mut headers,
+            Some("192.168.1.10".parse().unwrap()),
+            false,
+            "http",
+            Some("example.com:8080"),
+        );
+        // An untrusted peer's forwarding headers are replaced, not extended
+        assert_eq!(headers["x-forwarded-for"], "192.168.1.10");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert_eq!(headers["x-forwarded-host"], "example.com:8080");
+        assert!(!headers.contains_key("x-real-ip"));
+        assert_eq!(
+            headers["forwarded"],
+            "for=192.168.1.10;proto=http;host=\"example.com:8080\""
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.7".parse().unwrap());
+        headers.insert("x-forwarded-proto", "https".parse().unwrap());
+        headers.insert("forwarded", "for=203.0.113.7;proto=https".parse().unwrap());
+        headers.insert("x-real-ip", "203.0.113.7".parse().unwrap());
+        set_forwarded_headers(
+            &mut headers,
+            Some("::1".parse().unwrap()),
+            true,
+            "http",
+            Some("example.com"),
+        );
+        assert_eq!(headers["x-forwarded-for"], "203.0.113.7, ::1");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(headers["x-real-ip"], "203.0.113.7");
+        assert_eq!(
+            headers["forwarded"],
+            "for=203.0.113.7;proto=https, for=\"[::1]\";proto=http;host=example.com"
+        );
+    }
+
+    #[test]
+    fn test_is_upgrade_request() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut map = HeaderMap::new();
+            for (k, v) in pairs {
+                map.append(*k, v.parse().unwrap());
+            }
+            map
+        };
+        assert!(is_upgrade_request(&headers(&[
+            ("connection", "keep-alive, Upgrade"),
+            ("upgrade", "websocket"),
+        ])));
+        assert!(!is_upgrade_request(&headers(&[("upgrade", "websocket")])));
+        assert!(!is_upgrade_request(&headers(&[("connection", "upgrade")])));
+    }
+
+    #[test]
+    fn test_upstream_uri() {
+        assert_eq!(
+            upstream_uri("http://backend:8080", "/users?page=2")
+                .unwrap()
+                .to_string(),
+            "http://backend:8080/users?page=2"
+        );
+        let uri = upstream_uri("unix:///run/app.sock:/api", "/users").unwrap();
+        assert_eq!(uri.scheme_str(), Some("unix"));
+        assert_eq!(uri.path(), "/api/users");
+        let uri = upstream_uri("unix:///run/app.sock", "/").unwrap();
+        assert_eq!(uri.host(), Some("2f72756e2f6170702e736f636b"));
+        assert_eq!(
+            unix_target("unix:///run/app:v2.sock:/api"),
+            Some(("/run/app:v2.sock", "/api"))
+        );
+        assert_eq!(
+            unix_target("unix:///run/app:v2.sock"),
+            Some(("/run/app:v2.sock", ""))
+        );
+
+        assert_eq!(
+            upstream_host("https://api.example.com:8443").as_deref(),
+            Some("api.example.com:8443")
+        );
+        assert_eq!(
+            upstream_host("unix:///run/app.sock").as_deref(),
+            Some("localhost")
+        );
+    }
+
+    #[test]
+    fn test_missing_ca_file() {
+        let err = ProxyClient::new(&ProxyOptions {
+            ca_file: Some("/nonexistent/ca.pem".into()),
+            ..Default::default()
+        })
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("failed to read CA file"));
+    }
+}
@@ -2,57 +2,349 @@ use nu_protocol::{Record, Span, Value};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::time::Duration;
 
-/// Resolve client IP from X-Forwarded-For header using trusted proxy list.
-/// Parses right-to-left, stopping at first untrusted IP.
-/// Falls back to remote_ip if no valid header or all IPs are trusted proxies.
-pub fn resolve_trusted_ip(
-    headers: &http::header::HeaderMap,
-    remote_ip: Option<IpAddr>,
-    trusted_proxies: &[ipnet::IpNet],
-) -> Option<IpAddr> {
-    // If no trusted proxies configured, just use remote_ip
-    if trusted_proxies.is_empty() {
-        return remote_ip;
+/// Request body limits, shared between the handler, the body reader and the
+/// `.body-limit` command (which adjusts them per route from inside the
+/// closure). `u64::MAX` means unlimited.
+#[derive(Debug)]
+pub struct BodyLimits {
+    max_size: AtomicU64,
+    timeout_nanos: AtomicU64,
+    /// Status the closure tripped on while reading the body (413 or 408)
+    exceeded: AtomicU16,
+}
+
+impl BodyLimits {
+    pub fn new(max_size: Option<u64>, timeout: Option<Duration>) -> Self {
+        let limits = Self {
+            max_size: AtomicU64::new(u64::MAX),
+            timeout_nanos: AtomicU64::new(u64::MAX),
+            exceeded: AtomicU16::new(0),
+        };
+        limits.set_max_size(max_size);
+        limits.set_timeout(timeout);
+        limits
     }
 
-    // Check if remote_ip itself is trusted
-    // None (Unix socket) is implicitly trusted when --trust-proxy is configured
-    let remote_is_trusted = remote_ip
-        .map(|ip| trusted_proxies.iter().any(|net| net.contains(&ip)))
-        .unwrap_or(true);
+    pub fn max_size(&self) -> Option<u64> {
+        match self.max_size.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            n => Some(n),
+        }
+    }
 
-    if !remote_is_trusted {
-        return remote_ip;
+    pub fn set_max_size(&self, max_size: Option<u64>) {
+        self.max_size
+            .store(max_size.unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        match self.timeout_nanos.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            nanos => Some(Duration::from_nanos(nanos)),
+        }
+    }
+
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        let nanos = timeout.map_or(u64::MAX, |t| t.as_nanos().min(u64::MAX as u128 - 1) as u64);
+        self.timeout_nanos.store(nanos, Ordering::Relaxed);
+    }
+
+    /// Record that reading the body failed with `status` (first one wins)
+    pub fn trip(&self, status: u16) {
+        let _ = self
+            .exceeded
+            .compare_exchange(0, status, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    /// The status to answer with if a limit was hit while reading the body
+    pub fn exceeded(&self) -> Option<u16> {
+        match self.exceeded.load(Ordering::Relaxed) {
+            0 => None,
+            status => Some(status),
+        }
+    }
+}
+
+/// Error sent through the body channel when `--body-timeout` elapses
+#[derive(Debug)]
+pub struct BodyTimeout;
+
+impl std::fmt::Display for BodyTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "request body read timed out")
+    }
+}
+
+impl std::error::Error for BodyTimeout {}
+
+/// Split `"10mb"` into `(10, "mb")`, lowercasing the unit.
+fn split_unit(s: &str) -> Result<(u64, String), String> {
+    let s = s.trim();
+    let idx = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(idx);
+    let num = num
+        .parse::<u64>()
+        .map_err(|_| format!("invalid number in '{s}'"))?;
+    Ok((num, unit.trim().to_ascii_lowercase()))
+}
+
+/// Parse a byte size for --max-body-size: plain bytes or with a
+/// Nushell-style unit (`512kb`, `10MiB`, `1gb`).
+pub fn parse_byte_size(s: &str) -> Result<u64, String> {
+    let (num, unit) = split_unit(s)?;
+    let scale: u64 = match unit.as_str() {
+        "" | "b" => 1,
+        "kb" => 1_000,
+        "kib" => 1 << 10,
+        "mb" => 1_000_000,
+        "mib" => 1 << 20,
+        "gb" => 1_000_000_000,
+        "gib" => 1 << 30,
+        _ => return Err(format!("unknown size unit '{unit}'")),
+    };
+    num.checked_mul(scale)
+        .ok_or_else(|| format!("size '{s}' is too large"))
+}
+
+/// Parse a duration for --body-timeout: `500ms`, `30s`, `5min`, `1hr`.
+/// A bare number is seconds. Zero is refused: it would time out at once.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (num, unit) = split_unit(s)?;
+    let duration = match unit.as_str() {
+        "ms" => Duration::from_millis(num),
+        "" | "s" | "sec" => Duration::from_secs(num),
+        "m" | "min" => Duration::from_secs(
+            num.checked_mul(60)
+                .ok_or_else(|| format!("duration '{s}' is too large"))?,
+        ),
+        "h" | "hr" => Duration::from_secs(
+            num.checked_mul(3600)
+                .ok_or_else(|| format!("duration '{s}' is too large"))?,
+        ),
+        _ => return Err(format!("unknown duration unit '{unit}'")),
+    };
+    if duration.is_zero() {
+        return Err(format!("duration '{s}' must be greater than zero"));
+    }
+    Ok(duration)
+}
+
+/// Whether the connecting peer is a trusted proxy, so its forwarding headers
+/// can be believed. None (Unix socket) is implicitly trusted when
+/// --trust-proxy is configured.
+pub fn is_trusted_peer(remote_ip: Option<IpAddr>, trusted_proxies: &[ipnet::IpNet]) -> bool {
+    if trusted_proxies.is_empty() {
+        return false;
     }
+    remote_ip
+        .map(|ip| trusted_proxies.iter().any(|net| net.contains(&ip)))
+        .unwrap_or(true)
+}
+
+/// One hop reported by a proxy: an RFC 7239 `Forwarded` element, or a single
+/// `X-Forwarded-For` / `X-Real-IP` entry (which carry no proto or host)
+#[derive(Debug, Default, PartialEq)]
+struct Hop {
+    ip: Option<IpAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
 
-    // Get X-Forwarded-For header
-    let xff = match headers.get("x-forwarded-for") {
-        Some(v) => v.to_str().ok()?,
-        None => return remote_ip,
+/// The forwarding chain, oldest hop first. `Forwarded` wins over
+/// `X-Forwarded-For`, which wins over `X-Real-IP`.
+fn forwarded_chain(headers: &http::header::HeaderMap) -> Vec<Hop> {
+    let joined = |name: &str| -> Option<String> {
+        let values: Vec<&str> = headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+        (!values.is_empty()).then(|| values.join(","))
     };
 
-    // Parse IPs from right to left
-    let ips: Vec<&str> = xff.split(',').map(|s| s.trim()).collect();
+    if let Some(forwarded) = joined("forwarded") {
+        return split_quoted(&forwarded, ',')
+            .into_iter()
+            .map(|element| {
+                let mut hop = Hop::default();
+                for pair in split_quoted(element, ';') {
+                    let Some((key, value)) = pair.split_once('=') else {
+                        continue;
+                    };
+                    let value = unquote(value.trim());
+                    match key.trim().to_ascii_lowercase().as_str() {
+                        "for" => hop.ip = parse_node(&value),
+                        "proto" => hop.proto = Some(value),
+                        "host" => hop.host = Some(value),
+                        _ => {}
+                    }
+                }
+                hop
+            })
+            .collect();
+    }
+
+    let ips = joined("x-forwarded-for").or_else(|| joined("x-real-ip"));
+    ips.map(|ips| {
+        ips.split(',')
+            .map(|ip| Hop {
+                ip: ip.trim().parse().ok(),
+                ..Default::default()
+            })
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// Split on `sep`, ignoring separators inside quoted strings
+fn split_quoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == sep && !quoted => {
+                parts.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(s[start..].trim());
+    parts.retain(|p| !p.is_empty());
+    parts
+}
+
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) => {
+            let mut out = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                out.push(if c == '\\' {
+                    chars.next().unwrap_or(c)
+                } else {
+                    c
+                });
+            }
+            out
+        }
+        None => value.to_string(),
+    }
+}
+
+/// A `Forwarded` node: `192.0.2.1`, `192.0.2.1:8080`, `[2001:db8::1]` or
+/// `[2001:db8::1]:8080`. `unknown` and obfuscated identifiers yield None.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse()
+        .ok()
+        .or_else(|| node.rsplit_once(':')?.0.parse().ok())
+}
 
-    let mut leftmost_ip = None;
-    for ip_str in ips.into_iter().rev() {
-        if let Ok(ip) = ip_str.parse::<IpAddr>() {
-            leftmost_ip = Some(ip);
-            // If this IP is not in trusted proxies, it's the client
+/// Walk the chain right-to-left, stopping at the first untrusted IP: that's
+/// the client. If every IP is a trusted proxy, the leftmost one is. Returns
+/// the hop's index.
+fn client_hop(chain: &[Hop], trusted_proxies: &[ipnet::IpNet]) -> Option<usize> {
+    let mut leftmost = None;
+    for (i, hop) in chain.iter().enumerate().rev() {
+        if let Some(ip) = hop.ip {
+            leftmost = Some(i);
             if !trusted_proxies.iter().any(|net| net.contains(&ip)) {
-                return Some(ip);
+                return Some(i);
             }
         }
     }
+    leftmost
+}
+
+/// Resolve client IP from the `Forwarded`, `X-Forwarded-For` or `X-Real-IP`
+/// header using trusted proxy list. Parses right-to-left, stopping at first
+/// untrusted IP. Falls back to remote_ip if no valid header or the peer
+/// isn't a trusted proxy.
+pub fn resolve_trusted_ip(
+    headers: &http::header::HeaderMap,
+    remote_ip: Option<IpAddr>,
+    trusted_proxies: &[ipnet::IpNet],
+) -> Option<IpAddr> {
+    if !is_trusted_peer(remote_ip, trusted_proxies) {
+        return remote_ip;
+    }
+    let chain = forwarded_chain(headers);
+    client_hop(&chain, trusted_proxies)
+        .and_then(|i| chain[i].ip)
+        .or(remote_ip)
+}
+
+/// Resolve the scheme and host the client originally asked for, as reported
+/// by trusted proxies. They're taken from the same hop as the client IP: its
+/// `Forwarded` element, or else the `X-Forwarded-Proto` / `X-Forwarded-Host`
+/// value at that hop's position, counting from the right. Load balancers
+/// often set those once rather than appending per hop, so with fewer values
+/// than that the leftmost is used: the one from the trusted hop nearest the
+/// client. With no chain at all, the peer's own value is used. Each is None
+/// unless the peer is a trusted proxy.
+pub fn resolve_trusted_origin(
+    headers: &http::header::HeaderMap,
+    remote_ip: Option<IpAddr>,
+    trusted_proxies: &[ipnet::IpNet],
+) -> (Option<String>, Option<String>) {
+    if !is_trusted_peer(remote_ip, trusted_proxies) {
+        return (None, None);
+    }
+    let chain = forwarded_chain(headers);
+    let client = client_hop(&chain, trusted_proxies);
+    let hop = client.and_then(|i| chain.get(i));
+    // How many hops were appended after the client's
+    let from_right = client.map_or(0, |i| chain.len() - 1 - i);
+    let at_hop = |name: &str| -> Option<String> {
+        let values: Vec<&str> = headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .collect();
+        let value = *values.iter().rev().nth(from_right).or(values.first())?;
+        (!value.is_empty()).then(|| value.to_string())
+    };
+    let forwarded = headers.contains_key("forwarded");
+    let reported = |value: Option<&String>, name: &str| {
+        if forwarded {
+            value.cloned()
+        } else {
+            at_hop(name)
+        }
+    };
 
-    // All IPs were trusted proxies - use leftmost IP from XFF, or fall back to remote_ip
-    leftmost_ip.or(remote_ip)
+    let scheme = reported(hop.and_then(|hop| hop.proto.as_ref()), "x-forwarded-proto")
+        .map(|scheme| scheme.to_ascii_lowercase())
+        .filter(|scheme| {
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+        });
+    let host = reported(hop.and_then(|hop| hop.host.as_ref()), "x-forwarded-host").filter(|host| {
+        host.parse::<http::uri::Authority>()
+            .is_ok_and(|authority| !authority.as_str().contains('@'))
+    });
+    (scheme, host)
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Request {
     pub proto: String,
+    /// Set when the connection is over TLS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<crate::listener::TlsInfo>,
     #[serde(with = "http_serde::method")]
     pub method: http::method::Method,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -64,6 +356,12 @@ pub struct Request {
     /// Client IP resolved from X-Forwarded-For using trusted proxy list, or remote_ip as fallback
     #[serde(skip_serializing_if = "Option::is_none")]
     pub trusted_ip: Option<std::net::IpAddr>,
+    /// Scheme the client used, per trusted proxies, or this connection's own
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trusted_scheme: Option<String>,
+    /// Host the client asked for, per trusted proxies, or the Host header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trusted_host: Option<String>,
     #[serde(with = "http_serde::header_map")]
     pub headers: http::header::HeaderMap,
     #[serde(with = "http_serde::uri")]
@@ -72,10 +370,57 @@ pub struct Request {
     pub query: HashMap<String, String>,
 }
 
+/// `$req.tls`: `{version, server_name, client_cert}`, with `client_cert`
+/// null unless the client sent one
+fn tls_to_value(tls: &crate::listener::TlsInfo, span: Span) -> Value {
+    let opt_str = |s: &Option<String>| {
+        s.as_ref()
+            .map_or(Value::nothing(span), |s| Value::string(s, span))
+    };
+    let strings =
+        |vals: &[String]| Value::list(vals.iter().map(|v| Value::string(v, span)).collect(), span);
+    let client_cert = tls
+        .client_cert
+        .as_ref()
+        .map_or(Value::nothing(span), |cert| {
+            Value::record(
+                nu_protocol::record! {
+                    "subject" => Value::string(&cert.subject, span),
+                    "issuer" => Value::string(&cert.issuer, span),
+                    "sans" => Value::record(
+                        nu_protocol::record! {
+                            "dns" => strings(&cert.sans.dns),
+                            "uri" => strings(&cert.sans.uri),
+                            "ip" => strings(&cert.sans.ip),
+                            "email" => strings(&cert.sans.email),
+                        },
+                        span,
+                    ),
+                    "serial" => Value::string(&cert.serial, span),
+                    "fingerprint" => Value::string(&cert.fingerprint, span),
+                    "not_before" => Value::date(cert.not_before.into(), span),
+                    "not_after" => Value::date(cert.not_after.into(), span),
+                },
+                span,
+            )
+        });
+    Value::record(
+        nu_protocol::record! {
+            "version" => opt_str(&tls.version),
+            "server_name" => opt_str(&tls.server_name),
+            "client_cert" => client_cert,
+        },
+        span,
+    )
+}
+
 pub fn request_to_value(request: &Request, span: Span) -> Value {
     let mut record = Record::new();
 
     record.push("proto", Value::string(request.proto.clone(), span));
+    if let Some(tls) = &request.tls {
+        record.push("tls", tls_to_value(tls, span));
+    }
     record.push("method", Value::string(request.method.to_string(), span));
     record.push("uri", Value::string(request.uri.to_string(), span));
     record.push("path", Value::string(request.path.clone(), span));
@@ -96,22 +441,62 @@ pub fn request_to_value(request: &Request, span: Span) -> Value {
         record.push("trusted_ip", Value::string(trusted_ip.to_string(), span));
     }
 
-    // Convert headers to a record
+    if let Some(trusted_scheme) = &request.trusted_scheme {
+        record.push(
+            "trusted_scheme",
+            Value::string(trusted_scheme.clone(), span),
+        );
+    }
+
+    if let Some(trusted_host) = &request.trusted_host {
+        record.push("trusted_host", Value::string(trusted_host.clone(), span));
+    }
+
+    // Repeated headers are folded into `headers` (`cookie` with "; ", others
+    // with ", " per RFC 9110); `headers_all` keeps every value separately
     let mut headers_record = Record::new();
-    for (key, value) in request.headers.iter() {
-        headers_record.push(
-            key.to_string(),
-            Value::string(value.to_str().unwrap_or_default().to_string(), span),
+    let mut headers_all = Record::new();
+    for key in request.headers.keys() {
+        let values: Vec<String> = request
+            .headers
+            .get_all(key)
+            .iter()
+            .map(|v| v.to_str().unwrap_or_default().to_string())
+            .collect();
+        let separator = if key == http::header::COOKIE {
+            "; "
+        } else {
+            ", "
+        };
+        headers_record.push(key.as_str(), Value::string(values.join(separator), span));
+        headers_all.push(
+            key.as_str(),
+            Value::list(
+                values.into_iter().map(|v| Value::string(v, span)).collect(),
+                span,
+            ),
         );
     }
     record.push("headers", Value::record(headers_record, span));
+    record.push("headers_all", Value::record(headers_all, span));
 
-    // Convert query parameters to a record
+    // Query parameters in URL order: `query` keeps the last value of a
+    // repeated key, `query_all` keeps them all
     let mut query_record = Record::new();
-    for (key, value) in &request.query {
-        query_record.push(key.clone(), Value::string(value.clone(), span));
+    let mut query_all = Record::new();
+    let pairs = request
+        .uri
+        .query()
+        .map(|q| url::form_urlencoded::parse(q.as_bytes()).into_owned());
+    for (key, value) in pairs.into_iter().flatten() {
+        query_record.insert(key.clone(), Value::string(value.clone(), span));
+        match query_all.get_mut(&key) {
+            Some(Value::List { vals, .. }) => vals.push(Value::string(value, span)),
+            _ => query_all.push(key, Value::list(vec![Value::string(value, span)], span)),
+        }
     }
     record.push("query", Value::record(query_record, span));
+    record.push("query_all", Value::record(query_all, span));
 
     Value::record(record, span)
 }
@@ -225,6 +610,232 @@ mod tests {
         assert_eq!(result, None);
     }
 
+    #[test]
+    fn test_forwarded_header() {
+        // RFC 7239 form, with a quoted IPv6 node and a port
+        let mut headers = http::header::HeaderMap::new();
+        headers.insert(
+            "forwarded",
+            r#"for="[2001:db8::1]:4711";proto=https, for=10.0.0.1"#
+                .parse()
+                .unwrap(),
+        );
+        headers.insert("x-forwarded-for", "9.9.9.9".parse().unwrap());
+        let remote: IpAddr = "10.0.0.2".parse().unwrap();
+        let trusted = vec![parse_cidr("10.0.0.0/8")];
+        let result = resolve_trusted_ip(&headers, Some(remote), &trusted);
+        assert_eq!(result, Some("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_forwarded_skips_unknown_nodes() {
+        let mut headers = http::header::HeaderMap::new();
+        headers.insert(
+            "forwarded",
+            "for=5.6.7.8, for=unknown, for=_hidden".parse().unwrap(),
+        );
+        let trusted = vec![parse_cidr("10.0.0.0/8")];
+        let result = resolve_trusted_ip(&headers, None, &trusted);
+        assert_eq!(result, Some("5.6.7.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_x_real_ip() {
+        let mut headers = http::header::HeaderMap::new();
+        headers.insert("x-real-ip", "5.6.7.8".parse().unwrap());
+        let remote: IpAddr = "10.0.0.2".parse().unwrap();
+        let trusted = vec![parse_cidr("10.0.0.0/8")];
+        let result = resolve_trusted_ip(&headers, Some(remote), &trusted);
+        assert_eq!(result, Some("5.6.7.8".parse().unwrap()));
+        // Ignored from an untrusted peer
+        let result = resolve_trusted_ip(&headers, Some(remote), &[]);
+        assert_eq!(result, Some(remote));
+    }
+
+    #[test]
+    fn test_trusted_origin() {
+        let remote: IpAddr = "10.0.0.2".parse().unwrap();
+        let trusted = vec![parse_cidr("10.0.0.0/8")];
+
+        let mut headers = http::header::HeaderMap::new();
+        headers.insert("x-forwarded-for", "5.6.7.8, 10.0.0.1".parse().unwrap());
+        headers.insert("x-forwarded-proto", "HTTPS, http".parse().unwrap());
+        headers.insert("x-forwarded-host", "example.com, internal".parse().unwrap());
+        assert_eq!(
+            resolve_trusted_origin(&headers, Some(remote), &trusted),
+            (Some("https".into()), Some("example.com".into()))
+        );
+        // Not believed from an untrusted peer
+        assert_eq!(
+            resolve_trusted_origin(&headers, Some(remote), &[]),
+            (None, None)
+        );
+
+        // Values are matched to the client's hop from the right, never taken
+        // from further left than it
+        headers.insert("x-forwarded-for", "1.1.1.1, 5.6.7.8".parse().unwrap());
+        headers.insert("x-forwarded-proto", "http, https".parse().unwrap());
+        headers.insert("x-forwarded-host", "spoofed, example.com".parse().unwrap());
+        assert_eq!(
+            resolve_trusted_origin(&headers, Some(remote), &trusted),
+            (Some("https".into()), Some("example.com".into()))
+        );
+        // A load balancer that sets them once, not per hop
+        headers.insert("x-forwarded-for", "5.6.7.8, 10.0.0.1".parse().unwrap());
+        headers.insert("x-forwarded-proto", "https".parse().unwrap());
+        headers.insert("x-forwarded-host", "example.com".parse().unwrap());
+        assert_eq!(
+            resolve_trusted_origin(&headers, Some(remote), &trusted),
+            (Some("https".into()), Some("example.com".into()))
+        );
+        // Fewer values than hops: the trusted hop nearest the client
+        headers.insert(
+            "x-forwarded-for",
+            "5.6.7.8, 10.0.0.1, 10.0.0.3".parse().unwrap(),
+        );
+        headers.insert("x-forwarded-proto", "https, http".parse().unwrap());
+        assert_eq!(
+            resolve_trusted_origin(&headers, Some(remote), &trusted).0,
+            Some("https".into())
+        );
+        // No chain: the peer's own report
+        headers.remove("x-forwarded-for");
+        headers.insert("x-forwarded-proto", "https".parse().unwrap());
+        assert_eq!(
+            resolve_trusted_origin(&headers, Some(remote), &trusted),
+            (Some("https".into()), Some("example.com".into()))
+        );
+        // Hosts must be a valid authority
+        for host in ["evil.com/path", "user@evil.com", "a b"] {
+            headers.insert("x-real-ip", "5.6.7.8".parse().unwrap());
+            headers.insert("x-forwarded-host", host.parse().unwrap());
+            assert_eq!(
+                resolve_trusted_origin(&headers, Some(remote), &trusted).1,
+                None
+            );
+        }
+
+        // The client's own `Forwarded` element wins over X-Forwarded-*
+        headers.insert(
+            "forwarded",
+            r#"for=1.1.1.1;proto=http;host=spoofed, for=5.6.7.8;proto=https;host="app.example.com:8443", for=10.0.0.1;proto=http"#
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            resolve_trusted_origin(&headers, Some(remote), &trusted),
+            (Some("https".into()), Some("app.example.com:8443".into()))
+        );
+        // ...and what it leaves out isn't filled in from them
+        headers.insert(
+            "forwarded",
+            "for=5.6.7.8, for=10.0.0.1;proto=http".parse().unwrap(),
+        );
+        assert_eq!(
+            resolve_trusted_origin(&headers, Some(remote), &trusted),
+            (None, None)
+        );
+    }
+
+    #[test]
+    fn test_body_limits_unlimited_by_default() {
+        let limits = BodyLimits::new(None, None);
+        assert_eq!(limits.max_size(), None);
+        assert_eq!(limits.timeout(), None);
+        assert_eq!(limits.exceeded(), None);
+    }
+
+    #[test]
+    fn test_request_to_value_keeps_repeated_headers_and_query() {
+        let mut headers = http::header::HeaderMap::new();
+        headers.append("accept", "text/html".parse().unwrap());
+        headers.append("accept", "application/json".parse().unwrap());
+        headers.append("cookie", "a=1".parse().unwrap());
+        headers.append("cookie", "b=2".parse().unwrap());
+        let uri: http::Uri = "/search?tag=a&q=x&tag=b".parse().unwrap();
+        let request = Request {
+            proto: "HTTP/1.1".into(),
+            tls: None,
+            method: http::Method::GET,
+            authority: None,
+            remote_ip: None,
+            remote_port: None,
+            trusted_ip: None,
+            trusted_scheme: None,
+            trusted_host: None,
+            headers,
+            path: uri.path().to_string(),
+            query: HashMap::new(),
+            uri,
+        };
+        let value = request_to_value(&request, Span::test_data());
+        let get = |path: &str| -> Value {
+            path.split('.').fold(value.clone(), |v, key| {
+                v.as_record().unwrap().get(key).unwrap().clone()
+            })
+        };
+        let string = |path: &str| get(path).into_string().unwrap();
+        let strings = |path: &str| -> Vec<String> {
+            get(path)
+                .into_list()
+                .unwrap()
+                .into_iter()
+                .map(|v| v.into_string().unwrap())
+                .collect()
+        };
+
+        assert_eq!(string("headers.accept"), "text/html, application/json");
+        assert_eq!(string("headers.cookie"), "a=1; b=2");
+        assert_eq!(
+            strings("headers_all.accept"),
+            ["text/html", "application/json"]
+        );
+        assert_eq!(string("query.tag"), "b");
+        assert_eq!(strings("query_all.tag"), ["a", "b"]);
+        assert_eq!(strings("query_all.q"), ["x"]);
+        assert_eq!(
+            get("query")
+                .as_record()
+                .unwrap()
+                .columns()
+                .collect::<Vec<_>>(),
+            ["tag", "q"]
+        );
+    }
+
+    #[test]
+    fn test_parse_byte_size_and_duration() {
+        assert_eq!(parse_byte_size("2048").unwrap(), 2048);
+        assert_eq!(parse_byte_size("10mb").unwrap(), 10_000_000);
+        assert_eq!(parse_byte_size("512KiB").unwrap(), 512 * 1024);
+        assert!(parse_byte_size("10 parsecs").is_err());
+
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("5min").unwrap(), Duration::from_secs(300));
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration(&format!("{}h", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn test_body_limits_keep_sub_millisecond_timeouts() {
+        let limits = BodyLimits::new(None, Some(Duration::from_micros(500)));
+        assert_eq!(limits.timeout(), Some(Duration::from_micros(500)));
+        limits.set_timeout(None);
+        assert_eq!(limits.timeout(), None);
+    }
+
+    #[test]
+    fn test_body_limits_first_trip_wins() {
+        let limits = BodyLimits::new(Some(1024), Some(Duration::from_secs(5)));
+        assert_eq!(limits.max_size(), Some(1024));
+        assert_eq!(limits.timeout(), Some(Duration::from_secs(5)));
+        limits.trip(413);
+        limits.trip(408);
+        assert_eq!(limits.exceeded(), Some(413));
+    }
+
     #[test]
     fn test_trust_all_uses_leftmost_xff() {
         // When trusting 0.0.0.0/0, all IPs are "trusted" but we should still
@@ -17,31 +17,65 @@ pub struct HttpResponseMeta {
 }
 
 /// Special response types that bypass normal body handling
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct Response {
     pub status: u16,
     pub headers: HashMap<String, HeaderValue>,
     pub body_type: ResponseBodyType,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub enum ResponseBodyType {
     Normal,
     Static {
         root: PathBuf,
         path: String,
         fallback: Option<String>,
+        /// From the first `cache` glob matching the served file
+        cache_control: Option<String>,
+        headers: HashMap<String, HeaderValue>,
     },
     ReverseProxy {
-        target_url: String,
+        /// One target, or several to balance across
+        targets: Vec<String>,
+        /// Set when several targets (or balancing options) were given
+        balance: Option<Box<crate::upstream::Balance>>,
         headers: HashMap<String, HeaderValue>,
         preserve_host: bool,
+        /// Describe the client to the upstream with `Forwarded`/`X-Forwarded-*`
+        forwarded: bool,
         strip_prefix: Option<String>,
-        request_body: Vec<u8>,
+        /// Request body chunks, fed from the eval thread as they're read
+        request_body: tokio::sync::mpsc::Receiver<Result<Vec<u8>, std::io::Error>>,
+        /// Body length when known up front; otherwise it's sent chunked
+        request_body_len: Option<u64>,
         query: Option<HashMap<String, String>>,
+        /// Overrides the default upstream response timeout
+        timeout: Option<std::time::Duration>,
+        /// Set when an `on_response` closure wants the upstream response
+        on_response: Option<tokio::sync::oneshot::Sender<UpstreamResponse>>,
+    },
+    WebSocket {
+        inbound: tokio::sync::mpsc::Sender<WebSocketMessage>,
+        outbound: tokio::sync::mpsc::Receiver<WebSocketMessage>,
     },
 }
 
+/// An upstream response handed back to `.reverse-proxy` for `on_response`
+#[derive(Debug)]
+pub struct UpstreamResponse {
+    pub status: u16,
+    pub headers: http::HeaderMap,
+    pub body: tokio::sync::mpsc::Receiver<Result<Vec<u8>, std::io::Error>>,
+}
+
+/// A WebSocket data frame exchanged between the connection and `.websocket`
+#[derive(Debug)]
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
 #[derive(Debug)]
 pub enum ResponseTransport {
     Empty,
@@ -68,9 +102,11 @@ pub fn value_to_json(value: &Value) -> serde_json::Value {
             }
             serde_json::Value::Object(map)
         }
-        // Types without a direct JSON analogue (dates, durations, filesizes,
-        // binary, closures, ...) fall back to their string rendering rather
-        // than panicking.
+        // As `to json` renders them
+        Value::Filesize { val, .. } => serde_json::Value::Number(val.get().into()),
+        Value::Date { val, .. } => serde_json::Value::String(val.to_rfc3339()),
+        // Types without a direct JSON analogue (durations, binary, closures,
+        // ...) fall back to their string rendering rather than panicking.
         other => serde_json::Value::String(
             other.to_expanded_string(", ", &nu_protocol::Config::default()),
         ),
@@ -93,6 +129,11 @@ pub fn extract_http_response_meta(
         return HttpResponseMeta::default();
     };
 
+    http_response_meta(record)
+}
+
+/// `{status, headers}`, as in `http.response` metadata
+pub fn http_response_meta(record: &nu_protocol::Record) -> HttpResponseMeta {
     let status = record
         .get("status")
         .and_then(|v| v.as_int().ok())
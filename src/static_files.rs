@@ -0,0 +1,810 @@
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+use futures_util::StreamExt;
+use headers::HeaderMapExt;
+use http_body_util::{combinators::BoxBody, BodyExt, Empty, StreamBody};
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Method, StatusCode};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tower::Service;
+use tower_http::services::{ServeDir, ServeFile};
+
+use crate::archive::Archive;
+use crate::compression::{self, CompressionConfig};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Cache-Control for content that never changes at its URL
+pub const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// Characters escaped in a path segment: everything but RFC 3986 unreserved
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Listing {
+    Html,
+    Json,
+}
+
+/// What `.static` does with a request path, decided before the response is
+/// handed to `ServeDir`
+#[derive(Debug, PartialEq)]
+pub enum Resolved {
+    /// Serve this path; `file` is the root-relative file it names, if any,
+    /// for matching cache rules. `immutable` is set for a fingerprinted URL
+    /// whose hash matches the file.
+    File {
+        path: String,
+        file: Option<String>,
+        immutable: bool,
+    },
+    /// A directory asked for without its trailing slash
+    Redirect(String),
+    /// A directory with no index file, and listings are enabled. `rel` is
+    /// its root-relative path.
+    Listing { rel: String },
+}
+
+/// What `.static` serves from: a directory, or a `.tar`, `.tar.gz`/`.tgz`
+/// or `.zip` archive indexed in memory
+pub enum Root {
+    Dir(PathBuf),
+    Archive(Arc<Archive>),
+}
+
+impl Root {
+    pub fn open(path: &Path) -> std::io::Result<Root> {
+        if crate::archive::is_archive(path) {
+            Ok(Root::Archive(Archive::open(path)?))
+        } else {
+            Ok(Root::Dir(path.to_path_buf()))
+        }
+    }
+
+    fn is_file(&self, rel: &str) -> bool {
+        match self {
+            Root::Dir(dir) => dir.join(rel).is_file(),
+            Root::Archive(archive) => archive.file(rel).is_some(),
+        }
+    }
+
+    fn is_dir(&self, rel: &str) -> bool {
+        match self {
+            Root::Dir(dir) => dir.join(rel).is_dir(),
+            Root::Archive(archive) => archive.is_dir(rel),
+        }
+    }
+
+    /// Content hash of the file at `rel`, as used in fingerprinted URLs
+    pub fn fingerprint(&self, rel: &str) -> std::io::Result<String> {
+        match self {
+            Root::Dir(dir) => fingerprint(&dir.join(rel)),
+            Root::Archive(archive) => {
+                let file = archive.file(rel).ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::NotFound, format!("{rel} not found"))
+                })?;
+                archive.fingerprint(file)
+            }
+        }
+    }
+
+    /// Entries of the directory at `rel`, directories first, then by name.
+    /// Dotfiles are left out.
+    pub fn read_listing(&self, rel: &str) -> std::io::Result<Vec<Entry>> {
+        let mut entries = match self {
+            Root::Dir(dir) => read_listing(&dir.join(rel))?,
+            Root::Archive(archive) => archive
+                .children(rel)
+                .into_iter()
+                .map(|(name, file)| Entry {
+                    name: name.to_string(),
+                    is_dir: file.is_none(),
+                    size: file.map_or(0, |f| f.size),
+                    modified: file.and_then(|f| f.modified),
+                })
+                .collect(),
+        };
+        entries.retain(|entry| !entry.name.starts_with('.'));
+        entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+        Ok(entries)
+    }
+}
+
+/// Resolve `path` under `root`. Directories get their first existing
+/// `index` file, else a listing if `listing` is set. Paths that escape the
+/// root are passed through to be rejected when served.
+pub fn resolve(
+    root: &Root,
+    path: &str,
+    fallback: Option<&str>,
+    index: &[String],
+    listing: bool,
+) -> Resolved {
+    let decoded = percent_decode_str(path).decode_utf8_lossy();
+    let rel = Path::new(decoded.trim_start_matches('/'));
+    let passthrough = |file: Option<String>| Resolved::File {
+        path: path.to_string(),
+        file,
+        immutable: false,
+    };
+    if !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+        return passthrough(None);
+    }
+    // Normalized, `/`-separated on every platform: no trailing slash
+    let rel = rel
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    if root.is_file(&rel) {
+        return passthrough(Some(rel));
+    }
+    if !root.is_dir(&rel) {
+        // `app.3f2a9c1b.css` from `asset-url` names `app.css`
+        let (dir, name) = rel.rsplit_once('/').unwrap_or(("", &rel));
+        if let Some((name, hash)) = strip_fingerprint(name) {
+            let real = if dir.is_empty() {
+                name.clone()
+            } else {
+                format!("{dir}/{name}")
+            };
+            if root.is_file(&real) {
+                let url_dir_len = path.rfind('/').map_or(0, |i| i + 1);
+                return Resolved::File {
+                    path: format!(
+                        "{}{}",
+                        &path[..url_dir_len],
+                        utf8_percent_encode(&name, SEGMENT)
+                    ),
+                    // A stale hash still gets the file, just not cached forever
+                    immutable: root.fingerprint(&real).is_ok_and(|current| current == hash),
+                    file: Some(real),
+                };
+            }
+        }
+        return passthrough(fallback.map(str::to_string));
+    }
+    if !path.is_empty() && !path.ends_with('/') {
+        // Relative, so it holds wherever the closure mounted us
+        let name = rel.rsplit('/').next().unwrap_or_default();
+        return Resolved::Redirect(format!("{}/", utf8_percent_encode(name, SEGMENT)));
+    }
+    for name in index {
+        let file = if rel.is_empty() {
+            name.clone()
+        } else {
+            format!("{rel}/{name}")
+        };
+        if root.is_file(&file) {
+            return Resolved::File {
+                path: format!("{path}{}", utf8_percent_encode(name, SEGMENT)),
+                file: Some(file),
+                immutable: false,
+            };
+        }
+    }
+    if listing {
+        return Resolved::Listing { rel };
+    }
+    // No index: a 404, or the fallback
+    passthrough(fallback.map(str::to_string))
+}
+
+/// Hex digits of the content hash in a fingerprinted file name
+const FINGERPRINT_LEN: usize = 8;
+
+struct Fingerprint {
+    modified: Option<SystemTime>,
+    len: u64,
+    hash: String,
+}
+
+static FINGERPRINTS: OnceLock<Mutex<HashMap<PathBuf, Fingerprint>>> = OnceLock::new();
+
+/// xxh3 hash of a file's contents, cached until its size or mtime changes
+pub fn fingerprint(file: &Path) -> std::io::Result<String> {
+    let meta = std::fs::metadata(file)?;
+    let modified = meta.modified().ok();
+    let cache = FINGERPRINTS.get_or_init(Default::default);
+    if let Some(cached) = cache.lock().expect("fingerprint cache poisoned").get(file) {
+        if cached.modified == modified && cached.len == meta.len() {
+            return Ok(cached.hash.clone());
+        }
+    }
+
+    let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+    let mut reader = std::fs::File::open(file)?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = std::io::Read::read(&mut reader, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    let hash = short_hash(hasher.digest());
+    cache.lock().expect("fingerprint cache poisoned").insert(
+        file.to_path_buf(),
+        Fingerprint {
+            modified,
+            len: meta.len(),
+            hash: hash.clone(),
+        },
+    );
+    Ok(hash)
+}
+
+/// The leading hex digits of an xxh3 digest used in fingerprinted names
+pub fn short_hash(digest: u64) -> String {
+    format!("{digest:016x}")[..FINGERPRINT_LEN].to_string()
+}
+
+/// Put `hash` into the file name of a URL path, before the extension:
+/// `/assets/app.css` becomes `/assets/app.3f2a9c1b.css`
+pub fn fingerprinted(path: &str, hash: &str) -> String {
+    let (dir, name) = path
+        .rsplit_once('/')
+        .map_or(("", path), |(dir, name)| (&path[..dir.len() + 1], name));
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{dir}{stem}.{hash}.{ext}"),
+        _ => format!("{dir}{name}.{hash}"),
+    }
+}
+
+/// Undo `fingerprinted` for a file name: the real name and the hash
+fn strip_fingerprint(name: &str) -> Option<(String, &str)> {
+    let is_hash = |s: &str| {
+        s.len() == FINGERPRINT_LEN && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    // `stem.hash.ext` or, for names without an extension, `stem.hash`
+    if let Some((rest, ext)) = name.rsplit_once('.') {
+        if let Some((stem, hash)) = rest.rsplit_once('.') {
+            if !stem.is_empty() && is_hash(hash) {
+                return Some((format!("{stem}.{ext}"), hash));
+            }
+        }
+        if !rest.is_empty() && is_hash(ext) {
+            return Some((rest.to_string(), ext));
+        }
+    }
+    None
+}
+
+/// First `Cache-Control` rule whose glob matches `file`. A glob without a
+/// `/` matches the file name in any directory; one with a `/` matches the
+/// whole root-relative path.
+pub fn cache_control<'a>(rules: &'a [(nu_glob::Pattern, String)], file: &str) -> Option<&'a str> {
+    let name = file.rsplit('/').next().unwrap_or(file);
+    let options = nu_glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+    rules.iter().find_map(|(glob, value)| {
+        let subject = if glob.as_str().contains('/') {
+            file
+        } else {
+            name
+        };
+        glob.matches_with(subject, options)
+            .then_some(value.as_str())
+    })
+}
+
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+fn read_listing(dir: &Path) -> std::io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // Follows symlinks, as serving does
+        let Ok(meta) = std::fs::metadata(entry.path()) else {
+            continue;
+        };
+        entries.push(Entry {
+            name,
+            is_dir: meta.is_dir(),
+            size: if meta.is_dir() { 0 } else { meta.len() },
+            modified: meta.modified().ok(),
+        });
+    }
+    Ok(entries)
+}
+
+/// A plain HTML index page for `entries`, titled with the request path
+pub fn listing_html(title: &str, entries: &[Entry]) -> String {
+    use std::fmt::Write;
+
+    let parent = title != "/";
+    let title = v_htmlescape::escape(title).to_string();
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Index of {title}</title>\n</head>\n<body>\n<h1>Index of {title}</h1>\n<table>\n<tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n"
+    );
+    if parent {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir {
+            String::new()
+        } else {
+            entry.size.to_string()
+        };
+        let modified = entry
+            .modified
+            .map(|t| {
+                chrono::DateTime::<chrono::Utc>::from(t)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_default();
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{href}{slash}\">{name}{slash}</a></td><td>{size}</td><td>{modified}</td></tr>",
+            href = utf8_percent_encode(&entry.name, SEGMENT),
+            name = v_htmlescape::escape(&entry.name),
+        );
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+/// Serve `path` under `root`. From a directory the file is streamed from
+/// disk, never buffered, and `ServeDir` takes care of `Range` (206/416),
+/// conditional requests, `HEAD` and `.br`/`.zst`/`.gz` sidecars; archives
+/// get the same from `serve_archive`, minus sidecars. `If-Range` and
+/// on-the-fly compression are handled here.
+pub async fn serve(
+    root: &Path,
+    path: &str,
+    fallback: Option<&str>,
+    method: &Method,
+    headers: &HeaderMap,
+    compression: &CompressionConfig,
+) -> Result<hyper::Response<BoxBody<Bytes, BoxError>>, BoxError> {
+    // Opening an archive may index it: blocking reads, off the runtime.
+    // Directories need no opening, so skip the hop to the blocking pool.
+    let root = if crate::archive::is_archive(root) {
+        let root = root.to_path_buf();
+        Root::Archive(tokio::task::spawn_blocking(move || Archive::open(&root)).await??)
+    } else {
+        Root::Dir(root.to_path_buf())
+    };
+    let mut headers = headers.clone();
+    let mut res = serve_root(&root, path, fallback, method, headers.clone()).await?;
+
+    // A stale `If-Range` validator means the client's partial copy is out of
+    // date: send the whole file instead
+    let ranged = matches!(
+        res.status(),
+        StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE
+    );
+    if let Some(if_range) = headers.get(header::IF_RANGE) {
+        if ranged && !if_range_matches(if_range, res.headers().get(header::LAST_MODIFIED)) {
+            headers.remove(header::RANGE);
+            res = serve_root(&root, path, fallback, method, headers.clone()).await?;
+        }
+    }
+    compress(res, method, &headers, compression)
+}
+
+/// Compress text-like files on the fly when no sidecar was found, with the
+/// same negotiation as script responses. Only whole files are: a byte range
+/// of a stream compressed on the fly isn't stable across requests. `HEAD`
+/// gets the same headers as `GET` would, without running the encoder.
+fn compress(
+    mut res: hyper::Response<BoxBody<Bytes, BoxError>>,
+    method: &Method,
+    request_headers: &HeaderMap,
+    config: &CompressionConfig,
+) -> Result<hyper::Response<BoxBody<Bytes, BoxError>>, BoxError> {
+    let content_type = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    let compressible = config.compresses(content_type);
+    let encoded = res.headers().contains_key(header::CONTENT_ENCODING);
+    if compressible || encoded {
+        res.headers_mut()
+            .insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+
+    let too_small = res
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<u64>().ok())
+        .is_some_and(|len| len < config.min_size as u64);
+    if !compressible || encoded || too_small || res.status() != StatusCode::OK {
+        return Ok(res);
+    }
+    let Some(coding) = compression::negotiate(request_headers) else {
+        return Ok(res);
+    };
+
+    let (mut parts, body) = res.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.remove(header::ACCEPT_RANGES);
+    parts.headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(coding.as_str()),
+    );
+    if method == Method::HEAD {
+        let body = Empty::new().map_err(|never| match never {}).boxed();
+        return Ok(hyper::Response::from_parts(parts, body));
+    }
+    let chunks = body
+        .into_data_stream()
+        .map(|chunk| chunk.map(|bytes| bytes.to_vec()));
+    let body = StreamBody::new(compression::CompressStream::new(chunks, coding, config)?);
+    Ok(hyper::Response::from_parts(parts, BodyExt::boxed(body)))
+}
+
+async fn serve_root(
+    root: &Root,
+    path: &str,
+    fallback: Option<&str>,
+    method: &Method,
+    headers: HeaderMap,
+) -> Result<hyper::Response<BoxBody<Bytes, BoxError>>, BoxError> {
+    match root {
+        Root::Dir(dir) => serve_dir(dir, path, fallback, method, headers).await,
+        Root::Archive(archive) => serve_archive(archive, path, fallback, method, &headers).await,
+    }
+}
+
+async fn serve_dir(
+    root: &Path,
+    path: &str,
+    fallback: Option<&str>,
+    method: &Method,
+    headers: HeaderMap,
+) -> Result<hyper::Response<BoxBody<Bytes, BoxError>>, BoxError> {
+    let mut req = hyper::Request::new(Empty::<Bytes>::new());
+    *req.uri_mut() = format!("/{path}").parse()?;
+    *req.method_mut() = method.clone();
+    *req.headers_mut() = headers;
+
+    // Index files and the trailing-slash redirect are settled in `resolve`
+    let mut dir = ServeDir::new(root)
+        .append_index_html_on_directories(false)
+        .precompressed_br()
+        .precompressed_zstd()
+        .precompressed_gzip();
+    let res = if let Some(fallback) = fallback {
+        let file = ServeFile::new(root.join(fallback))
+            .precompressed_br()
+            .precompressed_zstd()
+            .precompressed_gzip();
+        dir.fallback(file).call(req).await?
+    } else {
+        dir.call(req).await?
+    };
+    Ok(res.map(|body| {
+        SyncBody(Mutex::new(body))
+            .map_err(|e| Box::new(e) as BoxError)
+            .boxed()
+    }))
+}
+
+/// `ServeDir` for an archive: a single `Range`, `If-Modified-Since` and
+/// `If-Unmodified-Since` against the entry's mtime, and `HEAD`
+async fn serve_archive(
+    archive: &Arc<Archive>,
+    path: &str,
+    fallback: Option<&str>,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Result<hyper::Response<BoxBody<Bytes, BoxError>>, BoxError> {
+    let empty = || Empty::new().map_err(|never| match never {}).boxed();
+    let status = |status: StatusCode| {
+        let mut res = hyper::Response::new(empty());
+        *res.status_mut() = status;
+        res
+    };
+    if method != Method::GET && method != Method::HEAD {
+        let mut res = status(StatusCode::METHOD_NOT_ALLOWED);
+        res.headers_mut()
+            .insert(header::ALLOW, HeaderValue::from_static("GET,HEAD"));
+        return Ok(res);
+    }
+
+    let decoded = percent_decode_str(path).decode_utf8_lossy();
+    let rel = Path::new(decoded.trim_start_matches('/'));
+    let rel = rel
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+        .then(|| {
+            rel.components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/")
+        });
+    let found = rel
+        .and_then(|rel| Some((archive.file(&rel)?, rel)))
+        .or_else(|| {
+            let fallback = fallback?;
+            Some((archive.file(fallback)?, fallback.to_string()))
+        });
+    let Some((file, name)) = found else {
+        return Ok(status(StatusCode::NOT_FOUND));
+    };
+
+    let mut res = status(StatusCode::OK);
+    if let Some(modified) = file.modified {
+        if let Some(since) = headers.typed_get::<headers::IfUnmodifiedSince>() {
+            if !since.precondition_passes(modified) {
+                return Ok(status(StatusCode::PRECONDITION_FAILED));
+            }
+        }
+        res.headers_mut()
+            .typed_insert(headers::LastModified::from(modified));
+        if let Some(since) = headers.typed_get::<headers::IfModifiedSince>() {
+            if !since.is_modified(modified) {
+                *res.status_mut() = StatusCode::NOT_MODIFIED;
+                return Ok(res);
+            }
+        }
+    }
+    let content_type = mime_guess::from_path(&name)
+        .first_raw()
+        .unwrap_or("application/octet-stream");
+    res.headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    res.headers_mut()
+        .insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    let mut range = 0..file.size;
+    if let Some(requested) = headers.get(header::RANGE) {
+        let ranges = requested
+            .to_str()
+            .ok()
+            .and_then(|r| http_range_header::parse_range_header(r).ok())
+            .and_then(|r| r.validate(file.size).ok());
+        match ranges.as_deref() {
+            Some([only]) => {
+                range = *only.start()..*only.end() + 1;
+                *res.status_mut() = StatusCode::PARTIAL_CONTENT;
+                res.headers_mut().insert(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", only.start(), only.end(), file.size).parse()?,
+                );
+            }
+            // Like `ServeDir`: no multipart ranges
+            _ => {
+                let mut res = status(StatusCode::RANGE_NOT_SATISFIABLE);
+                res.headers_mut().insert(
+                    header::CONTENT_RANGE,
+                    format!("bytes */{}", file.size).parse()?,
+                );
+                return Ok(res);
+            }
+        }
+    }
+    res.headers_mut()
+        .insert(header::CONTENT_LENGTH, (range.end - range.start).into());
+    if method == Method::GET {
+        *res.body_mut() = archive.read(file, range).await?;
+    }
+    Ok(res)
+}
+
+/// `ServeDir`'s body is `Send` but not `Sync`, which `BoxBody` requires. The
+/// mutex is never contended: polling goes through `&mut self`.
+struct SyncBody<B>(Mutex<B>);
+
+impl<B: Body + Unpin> Body for SyncBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let inner = self.get_mut().0.get_mut().expect("body mutex poisoned");
+        Pin::new(inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.0.lock().map(|b| b.is_end_stream()).unwrap_or(true)
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.0.lock().map(|b| b.size_hint()).unwrap_or_default()
+    }
+}
+
+/// RFC 9110 13.1.5: a date validator must exactly match `Last-Modified`.
+/// Static files carry no ETag, so an entity-tag validator never matches.
+fn if_range_matches(if_range: &HeaderValue, last_modified: Option<&HeaderValue>) -> bool {
+    let validator = if_range.as_bytes();
+    if validator.starts_with(b"\"") || validator.starts_with(b"W/") {
+        return false;
+    }
+    last_modified.is_some_and(|lm| lm.as_bytes() == validator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tmp.path().join("docs/api")).unwrap();
+        std::fs::write(tmp.path().join("docs/readme.md"), "").unwrap();
+        std::fs::write(tmp.path().join("docs/api/main.html"), "").unwrap();
+        let root = &Root::Dir(tmp.path().to_path_buf());
+        let index = ["index.html".to_string(), "main.html".to_string()];
+
+        let file = |path: &str, file: Option<&str>| Resolved::File {
+            path: path.into(),
+            file: file.map(Into::into),
+            immutable: false,
+        };
+        assert_eq!(
+            resolve(root, "/docs/readme.md", None, &index, false),
+            file("/docs/readme.md", Some("docs/readme.md"))
+        );
+        assert_eq!(
+            resolve(root, "/docs/api", None, &index, false),
+            Resolved::Redirect("api/".into())
+        );
+        assert_eq!(
+            resolve(root, "/docs/api/", None, &index, false),
+            file("/docs/api/main.html", Some("docs/api/main.html"))
+        );
+        assert_eq!(
+            resolve(root, "/docs/", None, &index, true),
+            Resolved::Listing { rel: "docs".into() }
+        );
+        assert_eq!(
+            resolve(root, "/docs/", Some("app.html"), &index, false),
+            file("/docs/", Some("app.html"))
+        );
+        // Left for `ServeDir` to refuse
+        assert_eq!(
+            resolve(root, "/docs/../../etc/", None, &index, true),
+            file("/docs/../../etc/", None)
+        );
+    }
+
+    #[test]
+    fn test_resolve_archive() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("site.tar");
+        let mut tar = tar::Builder::new(std::fs::File::create(&path).unwrap());
+        for (name, body) in [("./docs/index.html", "<h1>docs</h1>"), ("./.env", "")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(body.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, name, body.as_bytes()).unwrap();
+        }
+        tar.finish().unwrap();
+        drop(tar);
+
+        let root = &Root::open(&path).unwrap();
+        let index = ["index.html".to_string()];
+        assert_eq!(
+            resolve(root, "/docs", None, &index, false),
+            Resolved::Redirect("docs/".into())
+        );
+        assert_eq!(
+            resolve(root, "/docs/", None, &index, false),
+            Resolved::File {
+                path: "/docs/index.html".into(),
+                file: Some("docs/index.html".into()),
+                immutable: false,
+            }
+        );
+        let hash = root.fingerprint("docs/index.html").unwrap();
+        assert_eq!(
+            resolve(
+                root,
+                &format!("/docs/index.{hash}.html"),
+                None,
+                &index,
+                false
+            ),
+            Resolved::File {
+                path: "/docs/index.html".into(),
+                file: Some("docs/index.html".into()),
+                immutable: true,
+            }
+        );
+
+        // The implied directory is listed; the dotfile isn't
+        let names: Vec<_> = root
+            .read_listing("")
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.name, entry.is_dir))
+            .collect();
+        assert_eq!(names, [("docs".to_string(), true)]);
+    }
+
+    #[test]
+    fn test_fingerprinted_round_trip() {
+        assert_eq!(
+            fingerprinted("/assets/app.css", "3f2a9c1b"),
+            "/assets/app.3f2a9c1b.css"
+        );
+        assert_eq!(
+            fingerprinted("/vendor/lib.min.js", "3f2a9c1b"),
+            "/vendor/lib.min.3f2a9c1b.js"
+        );
+        assert_eq!(fingerprinted("LICENSE", "3f2a9c1b"), "LICENSE.3f2a9c1b");
+        assert_eq!(fingerprinted("/.env", "3f2a9c1b"), "/.env.3f2a9c1b");
+
+        assert_eq!(
+            strip_fingerprint("lib.min.3f2a9c1b.js"),
+            Some(("lib.min.js".into(), "3f2a9c1b"))
+        );
+        assert_eq!(
+            strip_fingerprint("LICENSE.3f2a9c1b"),
+            Some(("LICENSE".into(), "3f2a9c1b"))
+        );
+        assert_eq!(strip_fingerprint("app.css"), None);
+        assert_eq!(strip_fingerprint("app.3F2A9C1B.css"), None);
+        assert_eq!(strip_fingerprint(".3f2a9c1b"), None);
+    }
+
+    #[test]
+    fn test_fingerprint_tracks_changes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("app.css");
+        std::fs::write(&file, "a").unwrap();
+        let first = fingerprint(&file).unwrap();
+        assert_eq!(first.len(), FINGERPRINT_LEN);
+        assert_eq!(fingerprint(&file).unwrap(), first);
+        // Different size, so no mtime granularity worries
+        std::fs::write(&file, "bb").unwrap();
+        assert_ne!(fingerprint(&file).unwrap(), first);
+    }
+
+    #[test]
+    fn test_cache_control() {
+        let rules: Vec<(nu_glob::Pattern, String)> =
+            [("assets/*.js", "immutable"), ("*.html", "no-cache")]
+                .into_iter()
+                .map(|(glob, value)| (nu_glob::Pattern::new(glob).unwrap(), value.to_string()))
+                .collect();
+        assert_eq!(cache_control(&rules, "assets/app.js"), Some("immutable"));
+        assert_eq!(cache_control(&rules, "assets/vendor/lib.js"), None);
+        assert_eq!(
+            cache_control(&rules, "blog/post/index.html"),
+            Some("no-cache")
+        );
+        assert_eq!(cache_control(&rules, "style.css"), None);
+    }
+
+    #[test]
+    fn test_if_range_matches() {
+        let lm = HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT");
+        assert!(if_range_matches(&lm, Some(&lm)));
+        assert!(!if_range_matches(
+            &HeaderValue::from_static("Thu, 22 Oct 2015 07:28:00 GMT"),
+            Some(&lm)
+        ));
+        assert!(!if_range_matches(&lm, None));
+        assert!(!if_range_matches(
+            &HeaderValue::from_static("\"abc\""),
+            Some(&lm)
+        ));
+    }
+}
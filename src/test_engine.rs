@@ -43,6 +43,35 @@ fn test_closure_no_args() {
         .contains("Closure must accept exactly one request argument, found 0"));
 }
 
+#[test]
+fn test_closure_record() {
+    let mut engine = Engine::new().unwrap();
+    engine
+        .parse_closure(
+            r#"{handler: {|req| "hi" }, on_error: {|err| $err.phase }}"#,
+            None,
+        )
+        .unwrap();
+    assert!(engine.closure.is_some());
+    assert!(engine.error_handler.is_some());
+
+    let parse = |script: &str| {
+        Engine::new()
+            .unwrap()
+            .parse_closure(script, None)
+            .unwrap_err()
+            .to_string()
+    };
+    assert!(parse(r#"{on_error: {|err| 1 }}"#).contains("needs a handler closure"));
+    assert!(
+        parse(r#"{handler: {|req| 1 }, on_eror: {|err| 1 }}"#).contains("Unknown field 'on_eror'")
+    );
+    assert!(parse(r#"{handler: {|req| 1 }, on_error: "oops"}"#)
+        .contains("'on_error' must be a closure"));
+    assert!(parse(r#"{handler: {|req| 1 }, on_error: {|| 1 }}"#)
+        .contains("on_error must accept exactly one argument, found 0"));
+}
+
 #[test]
 fn test_mj_compile_inline() {
     let mut engine = eval_engine();
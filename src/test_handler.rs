@@ -2,18 +2,29 @@ use std::sync::Arc;
 use std::time::Instant;
 
 use arc_swap::ArcSwap;
-use http_body_util::{BodyExt, Empty, Full};
-use hyper::{body::Bytes, Request};
+use http_body_util::{BodyExt, Empty, Full, StreamBody};
+use hyper::body::{Bytes, Frame};
+use hyper::Request;
 use tokio::time::Duration;
+use tokio_stream::wrappers::ReceiverStream;
 
-use crate::commands::{MjCommand, PrintCommand, StaticCommand, ToSse};
+use crate::commands::{
+    AssetUrlCommand, BodyLimitCommand, ErrorMakeCommand, FromMultipartCommand, MjCommand,
+    PrintCommand, StaticCommand, ToSse,
+};
 use crate::handler::{handle, AppConfig};
+use crate::proxy::ProxyClient;
 
 fn default_config() -> Arc<AppConfig> {
     Arc::new(AppConfig {
         trusted_proxies: vec![],
         datastar: false,
         dev: false,
+        max_body_size: None,
+        body_timeout: None,
+        etag: false,
+        compression: Default::default(),
+        proxy: ProxyClient::new(&Default::default()).unwrap(),
     })
 }
 
@@ -106,6 +117,331 @@ async fn test_handle_post() {
     assert_eq!(String::from_utf8(resp_body.to_vec()).unwrap(), body);
 }
 
+#[tokio::test]
+async fn test_handle_body_limits() {
+    let config = Arc::new(AppConfig {
+        max_body_size: Some(8),
+        ..Arc::unwrap_or_clone(default_config())
+    });
+    let post = || {
+        Request::builder()
+            .method("POST")
+            .uri("/")
+            .body(Full::new(Bytes::from("more than eight bytes")))
+            .unwrap()
+    };
+
+    // Over the global limit
+    let engine = Arc::new(ArcSwap::from_pointee(test_engine(r#"{|req| $in }"#)));
+    let resp = handle(engine, None, config.clone(), post()).await.unwrap();
+    assert_eq!(resp.status(), 413);
+
+    // Raised by the closure
+    let engine = Arc::new(ArcSwap::from_pointee(test_engine(
+        r#"{|req| .body-limit --max-size 1kb }"#,
+    )));
+    let resp = handle(engine, None, config, post()).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "more than eight bytes");
+}
+
+#[tokio::test]
+async fn test_handle_body_timeout() {
+    let config = Arc::new(AppConfig {
+        body_timeout: Some(Duration::from_millis(100)),
+        ..Arc::unwrap_or_clone(default_config())
+    });
+    let engine = Arc::new(ArcSwap::from_pointee(test_engine(r#"{|req| $in }"#)));
+
+    // A client that sends one chunk and then stalls
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<Frame<Bytes>, std::convert::Infallible>>(1);
+    tx.send(Ok(Frame::data(Bytes::from("partial"))))
+        .await
+        .unwrap();
+    let req = Request::builder()
+        .method("POST")
+        .uri("/")
+        .body(StreamBody::new(ReceiverStream::new(rx)))
+        .unwrap();
+
+    let resp = handle(engine, None, config, req).await.unwrap();
+    assert_eq!(resp.status(), 408);
+    drop(tx);
+}
+
+const MULTIPART_BODY: &str = "--XyZ\r\n\
+    Content-Disposition: form-data; name=\"title\"\r\n\
+    \r\n\
+    hello\r\n\
+    --XyZ\r\n\
+    Content-Disposition: form-data; name=\"upload\"; filename=\"big.txt\"\r\n\
+    Content-Type: text/plain\r\n\
+    \r\n\
+    more than eight bytes\r\n\
+    --XyZ--\r\n";
+
+fn multipart_request() -> Request<Full<Bytes>> {
+    Request::builder()
+        .method("POST")
+        .uri("/")
+        .header("content-type", "multipart/form-data; boundary=XyZ")
+        .body(Full::new(Bytes::from(MULTIPART_BODY)))
+        .unwrap()
+}
+
+/// Spilled files are removed once the response is done, which is just after
+/// its body ends: wait for that
+async fn spill_dir_entries(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let entries = || -> Vec<_> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect()
+    };
+    for _ in 0..50 {
+        if entries().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    entries()
+}
+
+#[tokio::test]
+async fn test_handle_multipart() {
+    let tmp = tempfile::tempdir().unwrap();
+    let spill = tmp.path().join("spill");
+    let kept = tmp.path().join("kept");
+    std::fs::create_dir_all(&spill).unwrap();
+    std::fs::create_dir_all(&kept).unwrap();
+    let script = format!(
+        r#"{{|req|
+            from multipart --boundary $req.headers.content-type --spill-over 8b --spill-dir '{}'
+            | each {{|p|
+                if ($p.path? | is-not-empty) {{ mv $p.path ('{}' | path join $p.filename) }}
+                {{name: $p.name, filename: $p.filename, body: $p.body, spilled: ($p.path? | is-not-empty)}}
+            }}
+        }}"#,
+        spill.display(),
+        kept.display()
+    );
+    let engine = Arc::new(ArcSwap::from_pointee(test_engine(&script)));
+    let req = multipart_request();
+
+    let resp = handle(engine, None, default_config(), req).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    // Parts stream out as they are parsed: one JSON line each
+    let parts: Vec<serde_json::Value> = std::str::from_utf8(&body)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(
+        parts,
+        vec![
+            serde_json::json!({"name": "title", "filename": null, "body": "hello", "spilled": false}),
+            serde_json::json!({"name": "upload", "filename": "big.txt", "body": null, "spilled": true}),
+        ]
+    );
+
+    // The spilled part the handler moved is kept; nothing is left behind
+    assert_eq!(
+        std::fs::read(kept.join("big.txt")).unwrap(),
+        b"more than eight bytes"
+    );
+    assert!(spill_dir_entries(&spill).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_handle_multipart_error_removes_spilled_parts() {
+    let tmp = tempfile::tempdir().unwrap();
+    let script = format!(
+        r#"{{|req|
+            from multipart --boundary $req.headers.content-type --spill-over 8b --spill-dir '{}'
+            | each {{|p| if ($p.path? | is-not-empty) {{ error make {{msg: "rejected"}} }} else {{ $p.name }} }}
+            | to json
+        }}"#,
+        tmp.path().display()
+    );
+    let engine = Arc::new(ArcSwap::from_pointee(test_engine(&script)));
+
+    let resp = handle(engine, None, default_config(), multipart_request())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 500);
+    resp.into_body().collect().await.unwrap();
+
+    assert!(spill_dir_entries(tmp.path()).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_handle_etag_conditional_get_and_head() {
+    let config = Arc::new(AppConfig {
+        etag: true,
+        ..Arc::unwrap_or_clone(default_config())
+    });
+    let engine = Arc::new(ArcSwap::from_pointee(test_engine(
+        r#"{|req| "hello world" }"#,
+    )));
+    let request = |method: &str, if_none_match: Option<&str>| {
+        let mut builder = Request::builder().method(method).uri("/");
+        if let Some(tag) = if_none_match {
+            builder = builder.header("if-none-match", tag);
+        }
+        builder.body(Empty::<Bytes>::new()).unwrap()
+    };
+
+    let resp = handle(engine.clone(), None, config.clone(), request("GET", None))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let etag = resp.headers()["etag"].to_str().unwrap().to_string();
+    assert!(etag.starts_with('"') && etag.ends_with('"'));
+
+    // Matching validator (weak comparison) -> 304 without a body
+    let weak = format!("\"other\", W/{etag}");
+    let resp = handle(
+        engine.clone(),
+        None,
+        config.clone(),
+        request("GET", Some(&weak)),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), 304);
+    assert_eq!(resp.headers()["etag"], etag.as_str());
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    assert!(body.is_empty());
+
+    // Stale validator -> full response
+    let resp = handle(
+        engine.clone(),
+        None,
+        config.clone(),
+        request("GET", Some("\"stale\"")),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    // HEAD keeps the length but drops the body
+    let resp = handle(engine, None, config, request("HEAD", None))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-length"], "11");
+    assert_eq!(resp.headers()["etag"], etag.as_str());
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    assert!(body.is_empty());
+}
+
+#[tokio::test]
+async fn test_handle_script_etag_without_flag() {
+    let engine = Arc::new(ArcSwap::from_pointee(test_engine(
+        r#"{|req| "v1" | metadata set { merge {'http.response': {headers: {ETag: '"v1"'}}} } }"#,
+    )));
+    let req = Request::builder()
+        .method("GET")
+        .uri("/")
+        .header("if-none-match", "\"v1\"")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let resp = handle(engine, None, default_config(), req).await.unwrap();
+    assert_eq!(resp.status(), 304);
+}
+
+#[tokio::test]
+async fn test_handle_compression_negotiation_and_thresholds() {
+    let engine = Arc::new(ArcSwap::from_pointee(test_engine(
+        r#"{|req|
+            match $req.path {
+                "/small" => { "hi" }
+                "/binary" => { "x" | fill -c "x" -w 4096 | into binary }
+                _ => { "x" | fill -c "x" -w 4096 }
+            }
+        }"#,
+    )));
+    let get = |path: &str, accept: &str| {
+        Request::builder()
+            .uri(path)
+            .header("accept-encoding", accept)
+            .body(Empty::<Bytes>::new())
+            .unwrap()
+    };
+
+    for (accept, expected) in [("gzip", "gzip"), ("zstd, gzip", "zstd"), ("gzip, br", "br")] {
+        let resp = handle(engine.clone(), None, default_config(), get("/", accept))
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()["content-encoding"], expected);
+        assert_eq!(resp.headers()["vary"], "accept-encoding");
+    }
+
+    let resp = handle(engine.clone(), None, default_config(), get("/", "gzip"))
+        .await
+        .unwrap();
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    let mut decoded = String::new();
+    std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&body[..]), &mut decoded)
+        .unwrap();
+    assert_eq!(decoded, "x".repeat(4096));
+
+    // Below --compress-min-size, and non-text content types, go out as-is
+    for path in ["/small", "/binary"] {
+        let resp = handle(
+            engine.clone(),
+            None,
+            default_config(),
+            get(path, "gzip, br"),
+        )
+        .await
+        .unwrap();
+        assert!(resp.headers().get("content-encoding").is_none(), "{path}");
+    }
+}
+
+#[tokio::test]
+async fn test_handle_datastar_bundle_negotiation() {
+    let engine = Arc::new(ArcSwap::from_pointee(test_engine(r#"{|req| "script"}"#)));
+    let config = Arc::new(AppConfig {
+        datastar: true,
+        ..Arc::unwrap_or_clone(default_config())
+    });
+    for (accept, expected) in [
+        ("gzip", Some("gzip")),
+        ("zstd", Some("zstd")),
+        ("br", Some("br")),
+        ("identity", None),
+    ] {
+        let req = Request::builder()
+            .uri("/datastar@1.0.2.js")
+            .header("accept-encoding", accept)
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let resp = handle(engine.clone(), None, config.clone(), req)
+            .await
+            .unwrap();
+        assert_eq!(
+            resp.headers()
+                .get("content-encoding")
+                .map(|v| v.to_str().unwrap()),
+            expected
+        );
+        if expected == Some("gzip") {
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            let mut decoded = String::new();
+            std::io::Read::read_to_string(
+                &mut flate2::read::GzDecoder::new(&body[..]),
+                &mut decoded,
+            )
+            .unwrap();
+            assert!(decoded.len() > 1000);
+        }
+    }
+}
+
 #[tokio::test]
 async fn test_handle_streaming() {
     let engine = Arc::new(ArcSwap::from_pointee(test_engine(
@@ -205,7 +541,7 @@ async fn test_sse_cancel_signals_error_to_client() {
 }
 
 /// Same contract for brotli-encoded SSE: cancellation must surface as a
-/// stream error, not a clean end. BrotliStream propagates the inner Err
+/// stream error, not a clean end. CompressStream propagates the inner Err
 /// through (skipping FINISH); the client sees a truncated/decoder-error
 /// body and retries.
 #[tokio::test]
@@ -446,6 +782,336 @@ async fn test_handle_static() {
     assert_eq!(String::from_utf8(body.to_vec()).unwrap(), css);
 }
 
+#[tokio::test]
+async fn test_handle_static_range() {
+    let tmp = tempfile::tempdir().unwrap();
+    std::fs::write(tmp.path().join("data.txt"), "0123456789").unwrap();
+
+    let engine = Arc::new(ArcSwap::from_pointee(test_engine(&format!(
+        r#"{{|req| .static '{}' $req.path }}"#,
+        tmp.path().to_str().unwrap()
+    ))));
+    let get = |headers: Vec<(&'static str, String)>| {
+        let engine = engine.clone();
+        async move {
+            let mut req = Request::builder().uri("/data.txt");
+            for (k, v) in headers {
+                req = req.header(k, v);
+            }
+            let req = req.body(Empty::<Bytes>::new()).unwrap();
+            handle(engine, None, default_config(), req).await.unwrap()
+        }
+    };
+
+    let resp = get(vec![]).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["accept-ranges"], "bytes");
+    let last_modified = resp.headers()["last-modified"]
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let resp = get(vec![("range", "bytes=2-5".into())]).await;
+    assert_eq!(resp.status(), 206);
+    assert_eq!(resp.headers()["content-range"], "bytes 2-5/10");
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], b"2345");
+
+    // A matching If-Range keeps the range...
+    let resp = get(vec![
+        ("range", "bytes=-3".into()),
+        ("if-range", last_modified),
+    ])
+    .await;
+    assert_eq!(resp.status(), 206);
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], b"789");
+
+    // ...a stale one gets the whole file
+    let resp = get(vec![
+        ("range", "bytes=-3".into()),
+        ("if-range", "Wed, 21 Oct 2015 07:28:00 GMT".into()),
+    ])
+    .await;
+    assert_eq!(resp.status(), 200);
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], b"0123456789");
+
+    let resp = get(vec![("range", "bytes=20-30".into())]).await;
+    assert_eq!(resp.status(), 416);
+    assert_eq!(resp.headers()["content-range"], "bytes */10");
+}
+
+#[tokio::test]
+async fn test_handle_static_archive() {
+    use std::io::Write;
+
+    let files = [
+        ("docs/index.html", "<h1>docs</h1>"),
+        ("data.txt", "0123456789"),
+    ];
+    let tmp = tempfile::tempdir().unwrap();
+
+    let zip_path = tmp.path().join("site.zip");
+    let mut zip = zip::ZipWriter::new(std::fs::File::create(&zip_path).unwrap());
+    for (name, body) in files {
+        // One entry of each kind: stored is read in place, deflated inflated
+        let method = if name.ends_with(".txt") {
+            zip::CompressionMethod::Stored
+        } else {
+            zip::CompressionMethod::Deflated
+        };
+        let options = zip::write::SimpleFileOptions::default().compression_method(method);
+        zip.start_file(name, options).unwrap();
+        zip.write_all(body.as_bytes()).unwrap();
+    }
+    zip.finish().unwrap();
+
+    let tgz_path = tmp.path().join("site.tar.gz");
+    let gz = flate2::write::GzEncoder::new(
+        std::fs::File::create(&tgz_path).unwrap(),
+        flate2::Compression::default(),
+    );
+    let mut tar = tar::Builder::new(gz);
+    for (name, body) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(body.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(1_700_000_000);
+        tar.append_data(&mut header, name, body.as_bytes()).unwrap();
+    }
+    tar.into_inner().unwrap().finish().unwrap();
+
+    for archive in [zip_path, tgz_path] {
+        let engine = Arc::new(ArcSwap::from_pointee(test_engine(&format!(
+            r#"{{|req| .static '{}' $req.path }}"#,
+            archive.to_str().unwrap()
+        ))));
+        let get = |uri: &'static str, headers: Vec<(&'static str, String)>| {
+            let engine = engine.clone();
+            async move {
+                let mut req = Request::builder().uri(uri);
+                for (k, v) in headers {
+                    req = req.header(k, v);
+                }
+                let req = req.body(Empty::<Bytes>::new()).unwrap();
+                handle(engine, None, default_config(), req).await.unwrap()
+            }
+        };
+
+        let resp = get("/docs/", vec![]).await;
+        assert_eq!(resp.status(), 200, "{archive:?}");
+        assert_eq!(resp.headers()["content-type"], "text/html");
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"<h1>docs</h1>");
+
+        let resp = get("/data.txt", vec![("range", "bytes=2-5".into())]).await;
+        assert_eq!(resp.status(), 206);
+        assert_eq!(resp.headers()["content-range"], "bytes 2-5/10");
+        let last_modified = resp.headers()["last-modified"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"2345");
+
+        // Deflated zip entries are inflated up to the range, then streamed
+        let resp = get("/docs/index.html", vec![("range", "bytes=4-7".into())]).await;
+        assert_eq!(resp.status(), 206);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"docs");
+
+        let resp = get("/data.txt", vec![("if-modified-since", last_modified)]).await;
+        assert_eq!(resp.status(), 304);
+
+        let resp = get("/data.txt", vec![("range", "bytes=20-30".into())]).await;
+        assert_eq!(resp.status(), 416);
+        assert_eq!(resp.headers()["content-range"], "bytes */10");
+
+        let resp = get("/docs", vec![]).await;
+        assert_eq!(resp.status(), 307);
+        assert_eq!(resp.headers()["location"], "docs/");
+
+        let resp = get("/missing.txt", vec![]).await;
+        assert_eq!(resp.status(), 404);
+    }
+}
+
+#[tokio::test]
+async fn test_handle_static_listing_and_cache() {
+    let tmp = tempfile::tempdir().unwrap();
+    std::fs::create_dir(tmp.path().join("assets")).unwrap();
+    std::fs::write(tmp.path().join("assets/app.3f2a.js"), "js").unwrap();
+
+    let engine = Arc::new(ArcSwap::from_pointee(test_engine(&format!(
+        r#"{{|req| .static '{}' $req.path {{
+            listing: json
+            cache: {{"*.js": "public, max-age=31536000, immutable"}}
+            headers: {{x-frame-options: DENY}}
+        }} }}"#,
+        tmp.path().to_str().unwrap()
+    ))));
+    let get = |uri: &'static str| {
+        let engine = engine.clone();
+        async move {
+            let req = Request::builder()
+                .uri(uri)
+                .body(Empty::<Bytes>::new())
+                .unwrap();
+            handle(engine, None, default_config(), req).await.unwrap()
+        }
+    };
+
+    let resp = get("/assets/app.3f2a.js").await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.headers()["cache-control"],
+        "public, max-age=31536000, immutable"
+    );
+    assert_eq!(resp.headers()["x-frame-options"], "DENY");
+
+    let resp = get("/assets").await;
+    assert_eq!(resp.status(), 307);
+    assert_eq!(resp.headers()["location"], "assets/");
+
+    let resp = get("/assets/").await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "application/json");
+    assert_eq!(resp.headers()["x-frame-options"], "DENY");
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    let listing: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(listing[0]["name"], "app.3f2a.js");
+    assert_eq!(listing[0]["type"], "file");
+    assert_eq!(listing[0]["size"], 2);
+
+    // Missing files don't pick up the cache policy
+    let resp = get("/assets/gone.js").await;
+    assert_eq!(resp.status(), 404);
+    assert!(resp.headers().get("cache-control").is_none());
+}
+
+#[tokio::test]
+async fn test_handle_static_compression() {
+    let tmp = tempfile::tempdir().unwrap();
+    let css = "body { color: red; }\n".repeat(100);
+    std::fs::write(tmp.path().join("app.css"), &css).unwrap();
+    std::fs::write(tmp.path().join("app.css.br"), b"precompressed").unwrap();
+    std::fs::write(tmp.path().join("page.html"), &css).unwrap();
+    std::fs::write(tmp.path().join("photo.png"), "x".repeat(4096)).unwrap();
+
+    let engine = Arc::new(ArcSwap::from_pointee(test_engine(&format!(
+        r#"{{|req| .static '{}' $req.path }}"#,
+        tmp.path().to_str().unwrap()
+    ))));
+    let request = |method: &'static str, path: &'static str, accept: &'static str| {
+        let engine = engine.clone();
+        async move {
+            let req = Request::builder()
+                .method(method)
+                .uri(path)
+                .header("accept-encoding", accept)
+                .body(Empty::<Bytes>::new())
+                .unwrap();
+            handle(engine, None, default_config(), req).await.unwrap()
+        }
+    };
+    let get = |path, accept| request("GET", path, accept);
+
+    // The sidecar is served as-is, with the original's content type
+    let resp = get("/app.css", "gzip, br").await;
+    assert_eq!(resp.headers()["content-encoding"], "br");
+    assert_eq!(resp.headers()["content-type"], "text/css");
+    assert_eq!(resp.headers()["vary"], "accept-encoding");
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], b"precompressed");
+
+    // No gzip sidecar: compressed on the fly
+    let resp = get("/app.css", "gzip").await;
+    assert_eq!(resp.headers()["content-encoding"], "gzip");
+    assert!(resp.headers().get("content-length").is_none());
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    let mut decoded = String::new();
+    std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&body[..]), &mut decoded)
+        .unwrap();
+    assert_eq!(decoded, css);
+
+    // HEAD gets GET's headers, without a body
+    let head = request("HEAD", "/page.html", "gzip").await;
+    let resp = get("/page.html", "gzip").await;
+    for name in [
+        "content-encoding",
+        "content-type",
+        "vary",
+        "etag",
+        "last-modified",
+    ] {
+        assert_eq!(head.headers().get(name), resp.headers().get(name), "{name}");
+    }
+    assert_eq!(head.headers()["content-encoding"], "gzip");
+    assert!(head.headers().get("content-length").is_none());
+    assert!(head.headers().get("accept-ranges").is_none());
+    let body = head.into_body().collect().await.unwrap().to_bytes();
+    assert!(body.is_empty());
+
+    let resp = get("/page.html", "identity").await;
+    assert!(resp.headers().get("content-encoding").is_none());
+    assert_eq!(resp.headers()["vary"], "accept-encoding");
+
+    // Images are already compressed
+    let resp = get("/photo.png", "gzip, br").await;
+    assert!(resp.headers().get("content-encoding").is_none());
+}
+
+#[tokio::test]
+async fn test_handle_static_fingerprinted() {
+    let tmp = tempfile::tempdir().unwrap();
+    std::fs::create_dir(tmp.path().join("assets")).unwrap();
+    std::fs::write(tmp.path().join("assets/app.css"), "body {}").unwrap();
+
+    let root = tmp.path().to_str().unwrap();
+    let engine = Arc::new(ArcSwap::from_pointee(test_engine(&format!(
+        r#"{{|req|
+            if $req.path == "/" {{ asset-url '{root}' /assets/app.css }} else {{ .static '{root}' $req.path }}
+        }}"#
+    ))));
+    let get = |uri: String| {
+        let engine = engine.clone();
+        async move {
+            let req = Request::builder()
+                .uri(uri)
+                .body(Empty::<Bytes>::new())
+                .unwrap();
+            handle(engine, None, default_config(), req).await.unwrap()
+        }
+    };
+
+    let resp = get("/".into()).await;
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    let url = String::from_utf8(body.to_vec()).unwrap();
+    assert!(
+        url.starts_with("/assets/app.") && url.ends_with(".css"),
+        "{url}"
+    );
+
+    let resp = get(url).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "text/css");
+    assert_eq!(
+        resp.headers()["cache-control"],
+        "public, max-age=31536000, immutable"
+    );
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], b"body {}");
+
+    // An outdated hash still finds the file, but isn't cached forever
+    let resp = get("/assets/app.00000000.css".into()).await;
+    assert_eq!(resp.status(), 200);
+    assert!(resp.headers().get("cache-control").is_none());
+
+    let resp = get("/assets/app.css".into()).await;
+    assert!(resp.headers().get("cache-control").is_none());
+}
+
 fn test_engine(script: &str) -> crate::Engine {
     test_engine_with_dev(script, false)
 }
@@ -455,9 +1121,13 @@ fn test_engine_with_dev(script: &str, dev: bool) -> crate::Engine {
     engine
         .add_commands(vec![
             Box::new(StaticCommand::new()),
+            Box::new(AssetUrlCommand::new()),
             Box::new(ToSse {}),
             Box::new(MjCommand::new()),
             Box::new(PrintCommand::new()),
+            Box::new(ErrorMakeCommand::new()),
+            Box::new(BodyLimitCommand::new()),
+            Box::new(FromMultipartCommand::new()),
         ])
         .unwrap();
     engine
@@ -595,6 +1265,248 @@ async fn test_handle_script_runtime_error() {
     assert!(body_str.contains("Script error"));
 }
 
+#[tokio::test]
+async fn test_handle_script_error_pages() {
+    let engine = Arc::new(ArcSwap::from_pointee(test_engine(
+        r#"{|req|
+            let users = {alice: 1}
+            $users | get <bob>
+        }"#,
+    )));
+    let get = |dev: bool, accept: &'static str| {
+        let engine = engine.clone();
+        async move {
+            let config = Arc::new(AppConfig {
+                dev,
+                ..(*default_config()).clone()
+            });
+            let req = Request::builder()
+                .uri("/users?id=bob")
+                .header("accept", accept)
+                .body(Empty::<Bytes>::new())
+                .unwrap();
+            let resp = handle(engine, None, config, req).await.unwrap();
+            assert_eq!(resp.status(), 500);
+            let content_type = resp.headers()["content-type"].to_str().unwrap().to_string();
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            (content_type, String::from_utf8(body.to_vec()).unwrap())
+        }
+    };
+
+    // Production: nothing about the script leaks
+    for accept in ["text/html", "application/json", "*/*"] {
+        let (_, body) = get(false, accept).await;
+        assert!(body.contains("Script error"), "{body}");
+        assert!(!body.contains("bob"), "{body}");
+    }
+
+    let (content_type, body) = get(true, "application/json").await;
+    assert_eq!(content_type, "application/problem+json");
+    let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(json["status"], 500);
+    assert_eq!(json["detail"], "Cannot find column '<bob>'");
+    let frame = &json["frames"][0];
+    assert_eq!(frame["line"], 3);
+    assert_eq!(frame["source"], "            $users | get <bob>");
+    assert_eq!(json["request"]["query"]["id"], "bob");
+
+    let (content_type, body) = get(true, "text/html,*/*;q=0.8").await;
+    assert_eq!(content_type, "text/html; charset=utf-8");
+    assert!(body.contains("$users | get &lt;bob&gt;"), "{body}");
+    assert!(!body.contains("<bob>"), "{body}");
+
+    let (content_type, body) = get(true, "*/*").await;
+    assert_eq!(content_type, "text/plain; charset=utf-8");
+    assert!(body.starts_with("Script error: "), "{body}");
+    assert!(body.contains("Cannot find column '<bob>'"), "{body}");
+}
+
+#[tokio::test]
+async fn test_handle_error_make_http() {
+    let engine = Arc::new(ArcSwap::from_pointee(test_engine(
+        r#"{|req|
+            def find-user [id] {
+                if $id != "alice" {
+                    error make {
+                        msg: $"No user ($id)"
+                        http: {status: 404, headers: {x-reason: missing, x-tag: [a b]}}
+                    }
+                }
+                {name: $id}
+            }
+            # A caught error doesn't leak its status into a later one
+            try { error make {msg: "ignored", http: {status: 418}} }
+            match $req.query.id? {
+                "boom" => { 1 / 0 }
+                "wrapped" => {
+                    try { find-user nobody } catch { error make "Lookup failed" }
+                }
+                "each" => { [bob] | each {|id| find-user $id } }
+                $id => { find-user $id }
+            }
+        }"#,
+    )));
+    let request = |dev: bool, uri: &'static str, accept: &'static str| {
+        let engine = engine.clone();
+        async move {
+            let config = Arc::new(AppConfig {
+                dev,
+                ..(*default_config()).clone()
+            });
+            let req = Request::builder()
+                .uri(uri)
+                .header("accept", accept)
+                .body(Empty::<Bytes>::new())
+                .unwrap();
+            let resp = handle(engine, None, config, req).await.unwrap();
+            let status = resp.status();
+            let headers = resp.headers().clone();
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            (status, headers, String::from_utf8(body.to_vec()).unwrap())
+        }
+    };
+    let get = |uri, accept| request(false, uri, accept);
+
+    let (status, headers, body) = get("/?id=bob", "application/json").await;
+    assert_eq!(status, 404);
+    assert_eq!(headers["content-type"], "application/problem+json");
+    assert_eq!(headers["x-reason"], "missing");
+    let tags: Vec<_> = headers.get_all("x-tag").iter().collect();
+    assert_eq!(tags, ["a", "b"]);
+    let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "type": "about:blank",
+            "title": "Not Found",
+            "status": 404,
+            "detail": "No user bob",
+        })
+    );
+
+    let (status, _, body) = get("/?id=bob", "*/*").await;
+    assert_eq!(status, 404);
+    assert_eq!(body, "No user bob\n");
+
+    let (status, _, body) = get("/?id=bob", "text/html").await;
+    assert_eq!(status, 404);
+    assert!(body.contains("<h1>404 Not Found</h1>"), "{body}");
+
+    // The response travels with the error: through `catch` into the error
+    // it's chained to, and out of `each`
+    let (status, headers, body) = get("/?id=wrapped", "*/*").await;
+    assert_eq!(status, 404);
+    assert_eq!(headers["x-reason"], "missing");
+    assert_eq!(body, "Lookup failed\n");
+    let (status, _, body) = get("/?id=each", "*/*").await;
+    assert_eq!(status, 404);
+    assert_eq!(body, "No user bob\n");
+
+    // The dev report shows the error as written, pointing at `error make`
+    let (_, _, body) = request(true, "/?id=wrapped", "application/json").await;
+    let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let report = json["report"].as_str().unwrap();
+    assert_eq!(report.matches("No user nobody").count(), 1, "{report}");
+    let lines: Vec<_> = json["frames"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|frame| frame["line"].as_i64().unwrap())
+        .collect();
+    assert_eq!(lines, [16, 4], "{report}");
+
+    // Errors without an http status are still a terse 500
+    let (status, _, body) = get("/?id=boom", "application/json").await;
+    assert_eq!(status, 500);
+    let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(json["detail"], "Script error");
+
+    let (status, _, _) = get("/?id=alice", "*/*").await;
+    assert_eq!(status, 200);
+}
+
+#[tokio::test]
+async fn test_handle_on_error() {
+    let get = |script: &'static str, uri: &'static str| async move {
+        let engine = Arc::new(ArcSwap::from_pointee(test_engine(script)));
+        let req = Request::builder()
+            .uri(uri)
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let resp = handle(engine, None, default_config(), req).await.unwrap();
+        let status = resp.status();
+        let content_type = resp
+            .headers()
+            .get("content-type")
+            .map(|v| v.to_str().unwrap().to_string());
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        (
+            status,
+            content_type,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    };
+
+    // on_error sets the status and content type
+    let (status, content_type, body) = get(
+        r#"{
+            handler: {|req| error make {msg: "boom"} }
+            on_error: {|err|
+                {
+                    type: "about:blank"
+                    title: $err.error.message
+                    path: $err.request.path
+                    phase: $err.phase
+                    line: $err.error.frames.0.line
+                }
+                | to json -r
+                | metadata set --content-type "application/problem+json"
+                | metadata set { merge {'http.response': {status: 503}} }
+            }
+        }"#,
+        "/widgets",
+    )
+    .await;
+    assert_eq!(status, 503);
+    assert_eq!(content_type.as_deref(), Some("application/problem+json"));
+    let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(json["title"], "boom");
+    assert_eq!(json["path"], "/widgets");
+    assert_eq!(json["phase"], "eval");
+    assert_eq!(json["line"], 2);
+
+    // Without a status of its own, the response is still a 500
+    let (status, _, body) = get(
+        r#"{handler: {|req| 1 / 0 }, on_error: {|err| "branded: " + $err.error.message }}"#,
+        "/",
+    )
+    .await;
+    assert_eq!(status, 500);
+    assert_eq!(body, "branded: Division by zero.");
+
+    // Once a stream is under way, on_error's output ends it
+    let (status, _, body) = get(
+        r#"{
+            handler: {|req| 1..3 | each {|n| if $n == 3 { error make {msg: "boom"} } else { $"($n)\n" } } }
+            on_error: {|err| $"($err.phase): ($err.error.message)\n" }
+        }"#,
+        "/",
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body, "1\n2\nstream: boom\n");
+
+    // A failing on_error falls back to the built-in page
+    let (status, content_type, body) = get(
+        r#"{handler: {|req| error make {msg: "boom"} }, on_error: {|err| error make {msg: "again"} }}"#,
+        "/",
+    )
+    .await;
+    assert_eq!(status, 500);
+    assert_eq!(content_type.as_deref(), Some("text/plain; charset=utf-8"));
+    assert_eq!(body, "Script error\n");
+}
+
 #[tokio::test]
 async fn test_multi_value_set_cookie_headers() {
     let engine = Arc::new(ArcSwap::from_pointee(test_engine(
@@ -807,3 +1719,83 @@ async fn test_cookie_delete() {
         .iter()
         .any(|c| c.contains("old_token=") && c.contains("Max-Age=0")));
 }
+
+#[tokio::test]
+async fn test_handle_trusted_origin() {
+    let engine = Arc::new(ArcSwap::from_pointee(test_engine(
+        r#"{|req| $"($req.trusted_scheme)://($req.trusted_host)" }"#,
+    )));
+    let config = Arc::new(AppConfig {
+        trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+        ..(*default_config()).clone()
+    });
+    let origin = |peer: &'static str, headers: &'static [(&'static str, &'static str)]| {
+        let (engine, config) = (engine.clone(), config.clone());
+        async move {
+            let mut req = Request::builder().uri("/").header("host", "internal:3001");
+            for (name, value) in headers {
+                req = req.header(*name, *value);
+            }
+            let req = req.body(Empty::<Bytes>::new()).unwrap();
+            let resp = handle(engine, Some(peer.parse().unwrap()), config, req)
+                .await
+                .unwrap();
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            String::from_utf8(body.to_vec()).unwrap()
+        }
+    };
+
+    let forwarded: &[(&str, &str)] = &[
+        ("x-forwarded-for", "1.1.1.1, 5.6.7.8"),
+        ("x-forwarded-proto", "http, https"),
+        ("x-forwarded-host", "spoofed.example, app.example.com"),
+    ];
+    // The value at the client's hop, counting from the right
+    assert_eq!(
+        origin("10.0.0.2:40000", forwarded).await,
+        "https://app.example.com"
+    );
+    // Ignored from an untrusted peer
+    assert_eq!(
+        origin("203.0.113.9:40000", forwarded).await,
+        "http://internal:3001"
+    );
+    // A load balancer that sets them once, for the whole chain
+    assert_eq!(
+        origin(
+            "10.0.0.2:40000",
+            &[
+                ("x-forwarded-for", "5.6.7.8, 10.0.0.1"),
+                ("x-forwarded-proto", "https"),
+                ("x-forwarded-host", "app.example.com"),
+            ]
+        )
+        .await,
+        "https://app.example.com"
+    );
+    // A trusted peer without a forwarding chain
+    assert_eq!(
+        origin(
+            "10.0.0.2:40000",
+            &[
+                ("x-forwarded-proto", "https"),
+                ("x-forwarded-host", "app.example.com"),
+            ]
+        )
+        .await,
+        "https://app.example.com"
+    );
+    // Hosts that aren't a valid authority are dropped
+    assert_eq!(
+        origin(
+            "10.0.0.2:40000",
+            &[
+                ("x-forwarded-for", "5.6.7.8"),
+                ("x-forwarded-proto", "https"),
+                ("x-forwarded-host", "evil.example/path"),
+            ]
+        )
+        .await,
+        "https://internal:3001"
+    );
+}
@@ -0,0 +1,509 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Empty};
+use hyper::header::HeaderMap;
+use tokio::time::Instant;
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+use crate::proxy::ProxyClient;
+
+/// How `.reverse-proxy` picks among several upstreams
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Strategy {
+    RoundRobin,
+    LeastInflight,
+    /// Sticky: the same key always lands on the same upstream while it's up
+    Hash(HashOn),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum HashOn {
+    Header(String),
+    Cookie(String),
+}
+
+impl HashOn {
+    /// Parse `header:<name>` or `cookie:<name>`
+    pub fn parse(s: &str) -> Option<Self> {
+        let (kind, name) = s.split_once(':')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+        match kind.trim().to_ascii_lowercase().as_str() {
+            "header" => Some(HashOn::Header(name.to_ascii_lowercase())),
+            "cookie" => Some(HashOn::Cookie(name.to_string())),
+            _ => None,
+        }
+    }
+
+    fn key<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        match self {
+            HashOn::Header(name) => headers.get(name.as_str())?.to_str().ok(),
+            HashOn::Cookie(name) => headers
+                .get_all(hyper::header::COOKIE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(';'))
+                .find_map(|pair| {
+                    let (k, v) = pair.trim().split_once('=')?;
+                    (k == name).then_some(v)
+                }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HealthCheck {
+    pub path: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Balance {
+    pub strategy: Strategy,
+    /// Consecutive failures (5xx or connect error) before an upstream is ejected
+    pub max_fails: u32,
+    /// How long an ejected upstream sits out
+    pub fail_timeout: Duration,
+    pub health_check: Option<HealthCheck>,
+}
+
+impl Default for Balance {
+    fn default() -> Self {
+        Self {
+            strategy: Strategy::RoundRobin,
+            max_fails: 1,
+            fail_timeout: Duration::from_secs(10),
+            health_check: None,
+        }
+    }
+}
+
+/// A pool's identity: its target list and how it balances across them
+type PoolKey = (Vec<String>, Balance);
+
+struct Cached {
+    pool: Arc<Pool>,
+    last_used: Instant,
+    /// Stops the pool's health checks once it's evicted
+    _health_check: Option<DropGuard>,
+}
+
+/// A pool that hasn't been used for this long is dropped, with its health
+/// checks. Routes that build target lists from request data would otherwise
+/// add pools without bound.
+const IDLE: Duration = Duration::from_secs(300);
+
+/// Idle pools are looked for at most this often, so requests don't all
+/// walk the map
+const SWEEP: Duration = Duration::from_secs(60);
+
+/// At most this many pools are kept; the least recently used goes first
+const MAX_POOLS: usize = 1024;
+
+struct Pools {
+    by_key: HashMap<PoolKey, Cached>,
+    swept: Instant,
+}
+
+impl Default for Pools {
+    fn default() -> Self {
+        Self {
+            by_key: HashMap::new(),
+            swept: Instant::now(),
+        }
+    }
+}
+
+/// Upstream pools, keyed by their target list and balancing config. Lives on
+/// the `Engine`, so state is shared across requests and starts fresh on
+/// reload.
+#[derive(Default)]
+pub struct Upstreams {
+    pools: Mutex<Pools>,
+}
+
+impl Upstreams {
+    /// Get the pool for `targets` under `balance`, creating it (and starting
+    /// its health checks) on first use. Routes that balance the same targets
+    /// differently get separate pools.
+    pub fn pool(
+        &self,
+        targets: &[String],
+        balance: &Balance,
+        client: &ProxyClient,
+        cancel: &CancellationToken,
+    ) -> Arc<Pool> {
+        let mut pools = self.pools.lock().expect("upstream pools poisoned");
+        let now = Instant::now();
+        if now.duration_since(pools.swept) >= SWEEP {
+            pools.swept = now;
+            pools
+                .by_key
+                .retain(|_, cached| now.duration_since(cached.last_used) < IDLE);
+        }
+        let pools = &mut pools.by_key;
+
+        let key = (targets.to_vec(), balance.clone());
+        if let Some(cached) = pools.get_mut(&key) {
+            cached.last_used = now;
+            return cached.pool.clone();
+        }
+        if pools.len() >= MAX_POOLS {
+            if let Some(oldest) = pools
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(key, _)| key.clone())
+            {
+                pools.remove(&oldest);
+            }
+        }
+
+        let pool = Arc::new(Pool::new(targets, balance.clone()));
+        let health_check = balance.health_check.as_ref().map(|check| {
+            let cancel = cancel.child_token();
+            tokio::spawn(health_check(
+                pool.clone(),
+                check.clone(),
+                client.clone(),
+                cancel.clone(),
+            ));
+            cancel.drop_guard()
+        });
+        pools.insert(
+            key,
+            Cached {
+                pool: pool.clone(),
+                last_used: now,
+                _health_check: health_check,
+            },
+        );
+        pool
+    }
+}
+
+struct Target {
+    url: String,
+    inflight: AtomicUsize,
+    fails: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+    /// Cleared by a failing active health check
+    healthy: AtomicBool,
+}
+
+impl Target {
+    fn available(&self, now: Instant) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+            && self
+                .ejected_until
+                .lock()
+                .expect("upstream state poisoned")
+                .is_none_or(|until| now >= until)
+    }
+}
+
+pub struct Pool {
+    targets: Vec<Target>,
+    balance: Balance,
+    next: AtomicUsize,
+}
+
+impl Pool {
+    fn new(targets: &[String], balance: Balance) -> Self {
+        Self {
+            targets: targets
+                .iter()
+                .map(|url| Target {
+                    url: url.clone(),
+                    inflight: AtomicUsize::new(0),
+                    fails: AtomicU32::new(0),
+                    ejected_until: Mutex::new(None),
+                    healthy: AtomicBool::new(true),
+                })
+                .collect(),
+            balance,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Choose an upstream for a request. Ejected and unhealthy upstreams are
+    /// skipped; if none are left, all of them are tried rather than none.
+    pub fn pick(self: &Arc<Self>, headers: &HeaderMap) -> Lease {
+        let now = Instant::now();
+        let mut candidates: Vec<usize> = (0..self.targets.len())
+            .filter(|&i| self.targets[i].available(now))
+            .collect();
+        if candidates.is_empty() {
+            candidates = (0..self.targets.len()).collect();
+        }
+
+        let round_robin = |candidates: &[usize]| {
+            candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+        };
+        let index = match &self.balance.strategy {
+            Strategy::RoundRobin => round_robin(&candidates),
+            Strategy::LeastInflight => {
+                // Rotate the start so ties don't all land on the first upstream
+                let offset = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..candidates.len())
+                    .map(|i| candidates[(offset + i) % candidates.len()])
+                    .min_by_key(|&i| self.targets[i].inflight.load(Ordering::Relaxed))
+                    .expect("pool has at least one upstream")
+            }
+            // Rendezvous hashing: only keys on an ejected upstream move
+            Strategy::Hash(on) => match on.key(headers) {
+                Some(key) => *candidates
+                    .iter()
+                    .max_by_key(|&&i| {
+                        xxhash_rust::xxh3::xxh3_64(
+                            format!("{key}\0{}", self.targets[i].url).as_bytes(),
+                        )
+                    })
+                    .expect("pool has at least one upstream"),
+                None => round_robin(&candidates),
+            },
+        };
+
+        self.targets[index].inflight.fetch_add(1, Ordering::Relaxed);
+        Lease {
+            pool: self.clone(),
+            index,
+        }
+    }
+
+    fn report(&self, index: usize, ok: bool) {
+        let target = &self.targets[index];
+        if ok {
+            target.fails.store(0, Ordering::Relaxed);
+            return;
+        }
+        let fails = target.fails.fetch_add(1, Ordering::Relaxed) + 1;
+        if fails >= self.balance.max_fails {
+            target.fails.store(0, Ordering::Relaxed);
+            *target
+                .ejected_until
+                .lock()
+                .expect("upstream state poisoned") =
+                Some(Instant::now() + self.balance.fail_timeout);
+        }
+    }
+}
+
+/// A picked upstream. Counts as in flight until dropped, so hold it for as
+/// long as the response body streams.
+pub struct Lease {
+    pool: Arc<Pool>,
+    index: usize,
+}
+
+impl Lease {
+    pub fn url(&self) -> &str {
+        &self.pool.targets[self.index].url
+    }
+
+    /// Passive health: a 5xx or connect failure counts against the upstream,
+    /// anything else clears its failure count.
+    pub fn report(&self, ok: bool) {
+        self.pool.report(self.index, ok);
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.pool.targets[self.index]
+            .inflight
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Probe every upstream each `interval`, all at once. Any response below 500
+/// marks it healthy; a 5xx, connect failure or timeout takes it out of
+/// rotation.
+async fn health_check(
+    pool: Arc<Pool>,
+    check: HealthCheck,
+    client: ProxyClient,
+    cancel: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(check.interval);
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            _ = interval.tick() => {}
+        }
+        let probes = pool.targets.iter().map(|target| async {
+            let Ok(req) = crate::proxy::upstream_uri(&target.url, &check.path).and_then(|uri| {
+                Ok(hyper::Request::get(uri)
+                    .body(Empty::new().map_err(|never| match never {}).boxed())?)
+            }) else {
+                target.healthy.store(false, Ordering::Relaxed);
+                return;
+            };
+            let healthy = match client.request(req, Some(check.timeout)).await {
+                Ok(res) => !res.status().is_server_error(),
+                Err(_) => false,
+            };
+            target.healthy.store(healthy, Ordering::Relaxed);
+        });
+        futures_util::future::join_all(probes).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(n: usize, strategy: Strategy) -> Arc<Pool> {
+        let targets: Vec<String> = (0..n).map(|i| format!("http://backend-{i}")).collect();
+        Arc::new(Pool::new(
+            &targets,
+            Balance {
+                strategy,
+                ..Default::default()
+            },
+        ))
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let pool = pool(3, Strategy::RoundRobin);
+        let picks: Vec<String> = (0..4)
+            .map(|_| pool.pick(&HeaderMap::new()).url().to_string())
+            .collect();
+        assert_eq!(
+            picks,
+            [
+                "http://backend-0",
+                "http://backend-1",
+                "http://backend-2",
+                "http://backend-0"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_least_inflight() {
+        let pool = pool(2, Strategy::LeastInflight);
+        let busy = pool.pick(&HeaderMap::new());
+        // While the first lease is held, the other upstream is always chosen
+        for _ in 0..3 {
+            let lease = pool.pick(&HeaderMap::new());
+            assert_ne!(lease.url(), busy.url());
+        }
+    }
+
+    #[test]
+    fn test_hash_is_sticky_and_survives_ejection() {
+        let pool = pool(4, Strategy::Hash(HashOn::parse("cookie:sid").unwrap()));
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", "theme=dark; sid=abc123".parse().unwrap());
+
+        let first = pool.pick(&headers).url().to_string();
+        for _ in 0..5 {
+            assert_eq!(pool.pick(&headers).url(), first);
+        }
+
+        // Ejecting the sticky upstream moves the key elsewhere...
+        let lease = pool.pick(&headers);
+        lease.report(false);
+        drop(lease);
+        let moved = pool.pick(&headers).url().to_string();
+        assert_ne!(moved, first);
+        assert_eq!(pool.pick(&headers).url(), moved);
+    }
+
+    #[test]
+    fn test_passive_ejection() {
+        let pool = Arc::new(Pool::new(
+            &["http://a".to_string(), "http://b".to_string()],
+            Balance {
+                max_fails: 2,
+                ..Default::default()
+            },
+        ));
+        let a = || {
+            (0..2)
+                .map(|_| pool.pick(&HeaderMap::new()))
+                .find(|l| l.url() == "http://a")
+                .unwrap()
+        };
+        a().report(false);
+        // One failure is below max_fails: still in rotation
+        a().report(false);
+        // Ejected: every pick goes to b
+        for _ in 0..4 {
+            assert_eq!(pool.pick(&HeaderMap::new()).url(), "http://b");
+        }
+    }
+
+    #[test]
+    fn test_all_ejected_falls_back_to_all() {
+        let pool = pool(2, Strategy::RoundRobin);
+        for _ in 0..2 {
+            pool.pick(&HeaderMap::new()).report(false);
+        }
+        let urls: Vec<String> = (0..2)
+            .map(|_| pool.pick(&HeaderMap::new()).url().to_string())
+            .collect();
+        assert_eq!(urls, ["http://backend-0", "http://backend-1"]);
+    }
+
+    #[tokio::test]
+    async fn test_pools_keyed_by_balance() {
+        let upstreams = Upstreams::default();
+        let client = ProxyClient::new(&Default::default()).unwrap();
+        let cancel = CancellationToken::new();
+        let targets = ["http://a".to_string(), "http://b".to_string()];
+        let round_robin = Balance::default();
+        let hashed = Balance {
+            strategy: Strategy::Hash(HashOn::parse("cookie:sid").unwrap()),
+            ..Default::default()
+        };
+
+        let first = upstreams.pool(&targets, &round_robin, &client, &cancel);
+        let again = upstreams.pool(&targets, &round_robin, &client, &cancel);
+        let other = upstreams.pool(&targets, &hashed, &client, &cancel);
+        assert!(Arc::ptr_eq(&first, &again));
+        assert!(!Arc::ptr_eq(&first, &other));
+        assert_eq!(other.balance, hashed);
+    }
+
+    #[tokio::test]
+    async fn test_pools_capped_least_recently_used_first() {
+        let upstreams = Upstreams::default();
+        let client = ProxyClient::new(&Default::default()).unwrap();
+        let cancel = CancellationToken::new();
+        let balance = Balance::default();
+        let pool = |url: String| upstreams.pool(&[url], &balance, &client, &cancel);
+
+        let kept = pool("http://kept".into());
+        for i in 1..MAX_POOLS {
+            pool(format!("http://backend-{i}"));
+        }
+        // Using it again makes it the most recent, so a new pool evicts another
+        pool("http://kept".into());
+        pool("http://one-more".into());
+
+        assert_eq!(upstreams.pools.lock().unwrap().by_key.len(), MAX_POOLS);
+        assert!(Arc::ptr_eq(&kept, &pool("http://kept".into())));
+    }
+
+    #[test]
+    fn test_hash_on_parse() {
+        assert_eq!(
+            HashOn::parse("header:X-User"),
+            Some(HashOn::Header("x-user".into()))
+        );
+        assert_eq!(
+            HashOn::parse("cookie:sid"),
+            Some(HashOn::Cookie("sid".into()))
+        );
+        assert_eq!(HashOn::parse("query:id"), None);
+        assert_eq!(HashOn::parse("header:"), None);
+    }
+}
@@ -0,0 +1,168 @@
+use futures_util::{SinkExt, StreamExt};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tokio_util::sync::CancellationToken;
+
+use crate::logging::log_error;
+use crate::response::WebSocketMessage;
+
+/// True if the comma-separated header contains `token` (case-insensitive).
+fn header_has_token(headers: &HeaderMap, name: header::HeaderName, token: &str) -> bool {
+    headers.get_all(name).iter().any(|v| {
+        v.to_str()
+            .map(|s| s.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+            .unwrap_or(false)
+    })
+}
+
+/// Validate an RFC 6455 upgrade request and return the `Sec-WebSocket-Accept`
+/// value to answer it with. Returns `None` if the request is not a valid
+/// HTTP/1.1 WebSocket handshake.
+pub fn handshake_accept(parts: &http::request::Parts) -> Option<HeaderValue> {
+    if parts.method != http::Method::GET || parts.version != http::Version::HTTP_11 {
+        return None;
+    }
+    if !header_has_token(&parts.headers, header::CONNECTION, "upgrade")
+        || !header_has_token(&parts.headers, header::UPGRADE, "websocket")
+    {
+        return None;
+    }
+    if parts.headers.get(header::SEC_WEBSOCKET_VERSION)? != "13" {
+        return None;
+    }
+    let key = parts.headers.get(header::SEC_WEBSOCKET_KEY)?;
+    HeaderValue::from_str(&derive_accept_key(key.as_bytes())).ok()
+}
+
+/// Pump frames between an upgraded connection and the `.websocket` closure.
+///
+/// Inbound text/binary frames are forwarded to `inbound`; values the closure
+/// yields arrive on `outbound` and are written as frames. While the closure
+/// is behind on `$in`, the socket isn't read, so the client is held back
+/// rather than losing messages, but outbound frames and `cancel` are still
+/// served. The connection is closed when the closure finishes, the client
+/// disconnects, or `cancel` fires (--watch reload, shutdown). A closure that
+/// dropped `$in` can't take messages: sending one closes the socket with
+/// 1008.
+pub async fn run<S>(
+    io: S,
+    inbound: mpsc::Sender<WebSocketMessage>,
+    mut outbound: mpsc::Receiver<WebSocketMessage>,
+    cancel: CancellationToken,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ws = WebSocketStream::from_raw_socket(io, Role::Server, None).await;
+    let (mut sink, mut stream) = ws.split();
+
+    // A frame read from the client that the closure has no room for yet
+    let mut pending: Option<WebSocketMessage> = None;
+
+    loop {
+        tokio::select! {
+            biased;
+            _ = cancel.cancelled() => {
+                let _ = sink
+                    .send(Message::Close(Some(CloseFrame {
+                        code: CloseCode::Away,
+                        reason: "".into(),
+                    })))
+                    .await;
+                break;
+            }
+            msg = outbound.recv() => {
+                let res = match msg {
+                    Some(WebSocketMessage::Text(text)) => sink.send(Message::text(text)).await,
+                    Some(WebSocketMessage::Binary(bytes)) => sink.send(Message::binary(bytes)).await,
+                    // Closure finished: close normally
+                    None => {
+                        let _ = sink.send(Message::Close(None)).await;
+                        break;
+                    }
+                };
+                if res.is_err() {
+                    break;
+                }
+            }
+            permit = inbound.reserve(), if pending.is_some() => {
+                let Ok(permit) = permit else {
+                    log_error("WebSocket closed: the handler doesn't read incoming messages");
+                    let _ = sink
+                        .send(Message::Close(Some(CloseFrame {
+                            code: CloseCode::Policy,
+                            reason: "messages are not accepted".into(),
+                        })))
+                        .await;
+                    break;
+                };
+                permit.send(pending.take().expect("reserved for a pending frame"));
+            }
+            frame = stream.next(), if pending.is_none() => {
+                let msg = match frame {
+                    Some(Ok(Message::Text(text))) => WebSocketMessage::Text(text.to_string()),
+                    Some(Ok(Message::Binary(bytes))) => WebSocketMessage::Binary(bytes.to_vec()),
+                    // Pings are answered by tungstenite on the next read
+                    Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Err(err)) => {
+                        log_error(&format!("WebSocket error: {err}"));
+                        break;
+                    }
+                };
+                // Handed over by the `reserve` arm, which waits for the
+                // closure to make room
+                pending = Some(msg);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upgrade_parts(headers: &[(&str, &str)]) -> http::request::Parts {
+        let mut builder = http::Request::builder().method("GET").uri("/ws");
+        for (k, v) in headers {
+            builder = builder.header(*k, *v);
+        }
+        builder.body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn test_handshake_accept_rfc_example() {
+        // Example key/accept pair from RFC 6455 section 1.3
+        let parts = upgrade_parts(&[
+            ("connection", "keep-alive, Upgrade"),
+            ("upgrade", "websocket"),
+            ("sec-websocket-version", "13"),
+            ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="),
+        ]);
+        assert_eq!(
+            handshake_accept(&parts).unwrap(),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_handshake_rejects_plain_request() {
+        let parts = upgrade_parts(&[("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")]);
+        assert!(handshake_accept(&parts).is_none());
+    }
+
+    #[test]
+    fn test_handshake_rejects_missing_key() {
+        let parts = upgrade_parts(&[
+            ("connection", "upgrade"),
+            ("upgrade", "websocket"),
+            ("sec-websocket-version", "13"),
+        ]);
+        assert!(handshake_accept(&parts).is_none());
+    }
+}
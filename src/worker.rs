@@ -1,13 +1,14 @@
-use crate::commands::RESPONSE_TX;
+use crate::commands::{SpilledParts, BODY_LIMITS, RESPONSE_TX, SPILLED_PARTS};
+use crate::error_page::ScriptError;
 use crate::logging::log_error;
-use crate::request::{request_to_value, Request};
+use crate::request::{request_to_value, BodyLimits, Request};
 use crate::response::{
     extract_http_response_meta, value_to_bytes, value_to_json, HttpResponseMeta, Response,
     ResponseTransport,
 };
 use nu_protocol::{
-    engine::{Job, StateWorkingSet, ThreadJob},
-    format_cli_error, PipelineData, Value,
+    engine::{Job, ThreadJob},
+    PipelineData, PipelineMetadata, ShellError, Span, Value,
 };
 use std::io::Read;
 use std::sync::{mpsc, Arc};
@@ -23,10 +24,15 @@ type BoxError = Box<dyn std::error::Error + Send + Sync>;
 /// Result of pipeline evaluation containing content-type, HTTP response metadata, and body
 pub type PipelineResult = (Option<String>, HttpResponseMeta, ResponseTransport);
 
+/// Evaluate the closure for `request` on its own thread. If it fails before
+/// a response is under way, the script's `on_error` answers, else an error
+/// page: detailed when `dev` is set.
 pub fn spawn_eval_thread(
     engine: Arc<crate::Engine>,
     request: Request,
     stream: nu_protocol::ByteStream,
+    body_limits: Arc<BodyLimits>,
+    dev: bool,
 ) -> (
     oneshot::Receiver<Response>,
     oneshot::Receiver<PipelineResult>,
@@ -35,189 +41,35 @@ pub fn spawn_eval_thread(
     let (body_tx, body_rx) = tokio::sync::oneshot::channel();
 
     fn inner(
-        engine: Arc<crate::Engine>,
-        request: Request,
+        engine: &crate::Engine,
+        request: &Value,
         stream: nu_protocol::ByteStream,
+        body_limits: Arc<BodyLimits>,
+        spilled: Arc<SpilledParts>,
         meta_tx: oneshot::Sender<Response>,
-        body_tx: oneshot::Sender<PipelineResult>,
+        body_tx: &mut Option<oneshot::Sender<PipelineResult>>,
     ) -> Result<(), BoxError> {
         RESPONSE_TX.with(|tx| {
             *tx.borrow_mut() = Some(meta_tx);
         });
-        let result = engine.run_closure(
-            request_to_value(&request, nu_protocol::Span::unknown()),
-            stream.into(),
-        );
+        BODY_LIMITS.with(|limits| {
+            *limits.borrow_mut() = Some(body_limits);
+        });
+        SPILLED_PARTS.with(|parts| {
+            *parts.borrow_mut() = Some(spilled);
+        });
+        let result = engine.run_closure(request.clone(), stream.into());
         // Always clear the thread local storage after eval completes
         RESPONSE_TX.with(|tx| {
             let _ = tx.borrow_mut().take(); // This will drop the sender if it wasn't used
         });
-        let output = result?;
-
-        // Content-type inference (when pipeline metadata has no content-type):
-        //
-        // | Value type       | Content-Type           | Conversion          |
-        // |------------------|------------------------|---------------------|
-        // | Record (__html)  | text/html              | unwrap __html       |
-        // | Record           | application/json       | JSON object         |
-        // | List             | application/json       | JSON array          |
-        // | Binary           | application/octet-stream | raw bytes         |
-        // | Empty/Nothing    | None (no header)       | empty               |
-        // | ListStream       | application/x-ndjson   | JSONL (if records)  |
-        // | Other            | text/html (default)    | .to_string()        |
-        //
-        let inferred_content_type = match &output {
-            PipelineData::Value(Value::Record { val, .. }, meta)
-                if meta.as_ref().and_then(|m| m.content_type.clone()).is_none() =>
-            {
-                if val.get("__html").is_some() {
-                    Some("text/html; charset=utf-8".to_string())
-                } else {
-                    Some("application/json".to_string())
-                }
-            }
-            PipelineData::Value(Value::List { .. }, meta)
-                if meta.as_ref().and_then(|m| m.content_type.clone()).is_none() =>
-            {
-                Some("application/json".to_string())
-            }
-            PipelineData::Value(Value::Binary { .. }, meta)
-                if meta.as_ref().and_then(|m| m.content_type.clone()).is_none() =>
-            {
-                Some("application/octet-stream".to_string())
-            }
-            PipelineData::Value(_, meta) | PipelineData::ListStream(_, meta) => {
-                meta.as_ref().and_then(|m| m.content_type.clone())
-            }
-            _ => None,
-        };
-        match output {
-            PipelineData::Empty => {
-                let _ = body_tx.send((
-                    inferred_content_type,
-                    HttpResponseMeta::default(),
-                    ResponseTransport::Empty,
-                ));
-                Ok(())
-            }
-            PipelineData::Value(Value::Nothing { .. }, meta) => {
-                let http_meta = extract_http_response_meta(meta.as_ref());
-                let _ = body_tx.send((inferred_content_type, http_meta, ResponseTransport::Empty));
-                Ok(())
-            }
-            PipelineData::Value(Value::Error { error, .. }, _) => {
-                let working_set = StateWorkingSet::new(&engine.state);
-                Err(format_cli_error(None, &working_set, error.as_ref(), None).into())
-            }
-            PipelineData::Value(value, meta) => {
-                let http_meta = extract_http_response_meta(meta.as_ref());
-                let _ = body_tx.send((
-                    inferred_content_type,
-                    http_meta,
-                    ResponseTransport::Full(value_to_bytes(value)),
-                ));
-                Ok(())
-            }
-            PipelineData::ListStream(stream, meta) => {
-                let http_meta = extract_http_response_meta(meta.as_ref());
-                let (stream_tx, stream_rx) = tokio_mpsc::channel(32);
-                let mut iter = stream.into_inner();
-
-                // Peek first value to determine mode
-                let first = iter.next();
-                let use_jsonl = first.as_ref().is_some_and(is_jsonl_record);
-                let content_type = if use_jsonl {
-                    Some("application/x-ndjson".to_string())
-                } else {
-                    inferred_content_type
-                };
-
-                let _ = body_tx.send((
-                    content_type,
-                    http_meta,
-                    ResponseTransport::Stream(stream_rx),
-                ));
-
-                // Helper to send a value
-                let send_value = |stream_tx: &tokio_mpsc::Sender<Vec<u8>>, value: Value| -> bool {
-                    let bytes = if use_jsonl {
-                        let mut line =
-                            serde_json::to_vec(&value_to_json(&value)).unwrap_or_default();
-                        line.push(b'\n');
-                        line
-                    } else {
-                        value_to_bytes(value)
-                    };
-                    stream_tx.blocking_send(bytes).is_ok()
-                };
-
-                // Process first value
-                if let Some(value) = first {
-                    if let Value::Error { error, .. } = &value {
-                        let working_set = StateWorkingSet::new(&engine.state);
-                        log_error(&format_cli_error(None, &working_set, error.as_ref(), None));
-                        return Ok(());
-                    }
-                    if !send_value(&stream_tx, value) {
-                        return Ok(());
-                    }
-                }
-
-                // Process remaining values
-                for value in iter {
-                    if let Value::Error { error, .. } = &value {
-                        let working_set = StateWorkingSet::new(&engine.state);
-                        log_error(&format_cli_error(None, &working_set, error.as_ref(), None));
-                        break;
-                    }
-                    if !send_value(&stream_tx, value) {
-                        break;
-                    }
-                }
-                Ok(())
-            }
-            PipelineData::ByteStream(stream, meta) => {
-                let http_meta = extract_http_response_meta(meta.as_ref());
-                let (stream_tx, stream_rx) = tokio_mpsc::channel(32);
-                let content_type = meta
-                    .as_ref()
-                    .and_then(|m| m.content_type.clone())
-                    .or_else(|| Some("application/octet-stream".to_string()));
-                let _ = body_tx.send((
-                    content_type,
-                    http_meta,
-                    ResponseTransport::Stream(stream_rx),
-                ));
-                let mut reader = stream
-                    .reader()
-                    .ok_or_else(|| "ByteStream has no reader".to_string())?;
-                let mut buf = vec![0; 8192];
-                loop {
-                    match reader.read(&mut buf) {
-                        Ok(0) => break, // EOF
-                        Ok(n) => {
-                            if stream_tx.blocking_send(buf[..n].to_vec()).is_err() {
-                                break;
-                            }
-                        }
-                        Err(err) => {
-                            // Try to extract ShellError from the io::Error for proper formatting
-                            use nu_protocol::shell_error::bridge::ShellErrorBridge;
-                            if let Some(bridge) = err
-                                .get_ref()
-                                .and_then(|e| e.downcast_ref::<ShellErrorBridge>())
-                            {
-                                let working_set = StateWorkingSet::new(&engine.state);
-                                log_error(&format_cli_error(None, &working_set, &bridge.0, None));
-                                break; // Error already logged, just stop streaming
-                            }
-                            return Err(err.into());
-                        }
-                    }
-                }
-                Ok(())
-            }
-        }
+        BODY_LIMITS.with(|limits| {
+            let _ = limits.borrow_mut().take();
+        });
+        SPILLED_PARTS.with(|parts| {
+            let _ = parts.borrow_mut().take();
+        });
+        send_output(engine, result?, body_tx, Some(request), None)
     }
 
     // Create a thread job for this evaluation
@@ -232,51 +84,80 @@ pub fn spawn_eval_thread(
     };
 
     std::thread::spawn(move || -> Result<(), std::convert::Infallible> {
+        let accept = request.headers.get(hyper::header::ACCEPT).cloned();
+        let request = request_to_value(&request, nu_protocol::Span::unknown());
+        let mut local_engine = (*engine).clone();
+        local_engine.state.current_job.background_thread_job = Some(job);
         let mut meta_tx_opt = Some(meta_tx);
         let mut body_tx_opt = Some(body_tx);
+        let spilled = Arc::new(SpilledParts::default());
 
         // Wrap the evaluation in catch_unwind so that panics don't poison the
         // async runtime and we can still send a response back to the caller.
         let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-            let mut local_engine = (*engine).clone();
-            local_engine.state.current_job.background_thread_job = Some(job);
-
-            // Take the senders for the inner call. If the evaluation completes
-            // successfully, these senders will have been consumed. Otherwise we
-            // will use the remaining ones to send an error response.
+            // The body sender is only taken once the response is under way:
+            // if the closure fails first, it's left for the error response.
             inner(
-                Arc::new(local_engine),
-                request,
+                &local_engine,
+                &request,
                 stream,
+                body_limits,
+                spilled.clone(),
                 meta_tx_opt.take().unwrap(),
-                body_tx_opt.take().unwrap(),
+                &mut body_tx_opt,
             )
         }));
 
-        let err_msg: Option<String> = match result {
+        let error: Option<ScriptError> = match result {
             Ok(Ok(())) => None,
-            Ok(Err(e)) => Some(e.to_string()),
-            Err(panic) => Some(format!("panic: {panic:?}")),
+            Ok(Err(e)) => Some(script_error(e)),
+            Err(panic) => Some(panic_error(panic)),
         };
 
-        if let Some(err) = err_msg {
-            log_error(&err);
+        if let Some(err) = error {
+            log_error(&err.to_string());
             // Drop meta_tx - we don't use it for normal responses anymore
             // (only .static and .reverse-proxy use it)
             drop(meta_tx_opt.take());
+            if body_tx_opt.is_some() && local_engine.error_handler.is_some() {
+                let input = error_record(&request, &err, "eval");
+                let handled = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    let output = local_engine.run_error_handler(input)?;
+                    send_output(
+                        &local_engine,
+                        output,
+                        &mut body_tx_opt,
+                        None,
+                        Some(err.status()),
+                    )
+                }));
+                let failed = match handled {
+                    Ok(Ok(())) => None,
+                    Ok(Err(e)) => Some(script_error(e)),
+                    Err(panic) => Some(panic_error(panic)),
+                };
+                if let Some(failed) = failed {
+                    log_error(&format!("on_error failed: {failed}"));
+                }
+            }
             if let Some(body_tx) = body_tx_opt.take() {
                 let error_meta = HttpResponseMeta {
-                    status: Some(500),
-                    headers: std::collections::HashMap::new(),
+                    status: Some(err.status()),
+                    headers: err.http.headers.clone(),
                 };
+                let (content_type, body) =
+                    crate::error_page::render(&err, dev, accept.as_ref(), dev.then_some(&request));
                 let _ = body_tx.send((
-                    Some("text/plain; charset=utf-8".to_string()),
+                    Some(content_type.to_string()),
                     error_meta,
-                    ResponseTransport::Full(format!("Script error: {err}").into_bytes()),
+                    ResponseTransport::Full(body),
                 ));
             }
         }
 
+        // The response is done: drop the uploads the script didn't keep
+        spilled.remove_all();
+
         // Clean up job when done
         {
             let mut jobs = engine.state.jobs.lock().expect("jobs mutex poisoned");
@@ -288,3 +169,243 @@ pub fn spawn_eval_thread(
 
     (meta_rx, body_rx)
 }
+
+/// Send a pipeline's output as the response, with `status` as the default
+/// when its `http.response` metadata sets none. An error before the response
+/// is under way is returned, with `body_tx` left in place for the caller to
+/// answer it. Errors after that can only be logged; given the `request`,
+/// `on_error` can add a last chunk to the stream.
+fn send_output(
+    engine: &crate::Engine,
+    output: PipelineData,
+    body_tx: &mut Option<oneshot::Sender<PipelineResult>>,
+    request: Option<&Value>,
+    status: Option<u16>,
+) -> Result<(), BoxError> {
+    if let PipelineData::Value(Value::Error { error, .. }, _) = &output {
+        return Err(ScriptError::from_shell_error(&engine.state, error).into());
+    }
+    let http_meta = |meta: Option<&PipelineMetadata>| {
+        let mut http_meta = extract_http_response_meta(meta);
+        http_meta.status = http_meta.status.or(status);
+        http_meta
+    };
+
+    // Content-type inference (when pipeline metadata has no content-type):
+    //
+    // | Value type       | Content-Type           | Conversion          |
+    // |------------------|------------------------|---------------------|
+    // | Record (__html)  | text/html              | unwrap __html       |
+    // | Record           | application/json       | JSON object         |
+    // | List             | application/json       | JSON array          |
+    // | Binary           | application/octet-stream | raw bytes         |
+    // | Empty/Nothing    | None (no header)       | empty               |
+    // | ListStream       | application/x-ndjson   | JSONL (if records)  |
+    // | Other            | text/html (default)    | .to_string()        |
+    //
+    let inferred_content_type = match &output {
+        PipelineData::Value(Value::Record { val, .. }, meta)
+            if meta.as_ref().and_then(|m| m.content_type.clone()).is_none() =>
+        {
+            if val.get("__html").is_some() {
+                Some("text/html; charset=utf-8".to_string())
+            } else {
+                Some("application/json".to_string())
+            }
+        }
+        PipelineData::Value(Value::List { .. }, meta)
+            if meta.as_ref().and_then(|m| m.content_type.clone()).is_none() =>
+        {
+            Some("application/json".to_string())
+        }
+        PipelineData::Value(Value::Binary { .. }, meta)
+            if meta.as_ref().and_then(|m| m.content_type.clone()).is_none() =>
+        {
+            Some("application/octet-stream".to_string())
+        }
+        PipelineData::Value(_, meta) | PipelineData::ListStream(_, meta) => {
+            meta.as_ref().and_then(|m| m.content_type.clone())
+        }
+        _ => None,
+    };
+    match output {
+        PipelineData::Empty => {
+            let body_tx = body_tx.take().expect("body sender taken once");
+            let _ = body_tx.send((
+                inferred_content_type,
+                http_meta(None),
+                ResponseTransport::Empty,
+            ));
+            Ok(())
+        }
+        PipelineData::Value(Value::Nothing { .. }, meta) => {
+            let body_tx = body_tx.take().expect("body sender taken once");
+            let _ = body_tx.send((
+                inferred_content_type,
+                http_meta(meta.as_ref()),
+                ResponseTransport::Empty,
+            ));
+            Ok(())
+        }
+        PipelineData::Value(value, meta) => {
+            let body_tx = body_tx.take().expect("body sender taken once");
+            let _ = body_tx.send((
+                inferred_content_type,
+                http_meta(meta.as_ref()),
+                ResponseTransport::Full(value_to_bytes(value)),
+            ));
+            Ok(())
+        }
+        PipelineData::ListStream(stream, meta) => {
+            let mut iter = stream.into_inner();
+
+            // Peek first value to determine mode. A stream that fails
+            // straight away hasn't started the response yet.
+            let first = iter.next();
+            if let Some(Value::Error { error, .. }) = &first {
+                return Err(ScriptError::from_shell_error(&engine.state, error).into());
+            }
+            let use_jsonl = first.as_ref().is_some_and(is_jsonl_record);
+            let content_type = if use_jsonl {
+                Some("application/x-ndjson".to_string())
+            } else {
+                inferred_content_type
+            };
+
+            let (stream_tx, stream_rx) = tokio_mpsc::channel(32);
+            let body_tx = body_tx.take().expect("body sender taken once");
+            let _ = body_tx.send((
+                content_type,
+                http_meta(meta.as_ref()),
+                ResponseTransport::Stream(stream_rx),
+            ));
+
+            // Helper to send a value
+            let send_value = |stream_tx: &tokio_mpsc::Sender<Vec<u8>>, value: Value| -> bool {
+                let bytes = if use_jsonl {
+                    let mut line = serde_json::to_vec(&value_to_json(&value)).unwrap_or_default();
+                    line.push(b'\n');
+                    line
+                } else {
+                    value_to_bytes(value)
+                };
+                stream_tx.blocking_send(bytes).is_ok()
+            };
+
+            for value in first.into_iter().chain(iter) {
+                if let Value::Error { error, .. } = &value {
+                    if let Some(chunk) = stream_error(engine, request, error) {
+                        let _ = stream_tx.blocking_send(chunk);
+                    }
+                    break;
+                }
+                if !send_value(&stream_tx, value) {
+                    break;
+                }
+            }
+            Ok(())
+        }
+        PipelineData::ByteStream(stream, meta) => {
+            let (stream_tx, stream_rx) = tokio_mpsc::channel(32);
+            let content_type = meta
+                .as_ref()
+                .and_then(|m| m.content_type.clone())
+                .or_else(|| Some("application/octet-stream".to_string()));
+            let body_tx = body_tx.take().expect("body sender taken once");
+            let _ = body_tx.send((
+                content_type,
+                http_meta(meta.as_ref()),
+                ResponseTransport::Stream(stream_rx),
+            ));
+            let mut reader = stream
+                .reader()
+                .ok_or_else(|| "ByteStream has no reader".to_string())?;
+            let mut buf = vec![0; 8192];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) => break, // EOF
+                    Ok(n) => {
+                        if stream_tx.blocking_send(buf[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        // Try to extract ShellError from the io::Error for proper formatting
+                        use nu_protocol::shell_error::bridge::ShellErrorBridge;
+                        if let Some(bridge) = err
+                            .get_ref()
+                            .and_then(|e| e.downcast_ref::<ShellErrorBridge>())
+                        {
+                            if let Some(chunk) = stream_error(engine, request, &bridge.0) {
+                                let _ = stream_tx.blocking_send(chunk);
+                            }
+                            break; // Error already logged, just stop streaming
+                        }
+                        return Err(err.into());
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Log an error from a stream whose response is already under way. With a
+/// `request`, the script's `on_error` can add a last chunk to the stream.
+fn stream_error(
+    engine: &crate::Engine,
+    request: Option<&Value>,
+    error: &ShellError,
+) -> Option<Vec<u8>> {
+    let err = ScriptError::from_shell_error(&engine.state, error);
+    log_error(&err.to_string());
+    let request = request.filter(|_| engine.error_handler.is_some())?;
+    let output = engine
+        .run_error_handler(error_record(request, &err, "stream"))
+        .and_then(|output| {
+            output
+                .into_value(Span::unknown())
+                .map_err(|e| ScriptError::from_shell_error(&engine.state, &e).into())
+        });
+    match output {
+        Ok(Value::Error { error, .. }) => {
+            let failed = ScriptError::from_shell_error(&engine.state, &error);
+            log_error(&format!("on_error failed: {failed}"));
+            None
+        }
+        Ok(value) => Some(value_to_bytes(value)),
+        Err(e) => {
+            log_error(&format!("on_error failed: {e}"));
+            None
+        }
+    }
+}
+
+/// `on_error`'s input: `{request, error, phase}`
+fn error_record(request: &Value, err: &ScriptError, phase: &str) -> Value {
+    let span = Span::unknown();
+    Value::record(
+        nu_protocol::record! {
+            "request" => request.clone(),
+            "error" => err.to_value(span),
+            "phase" => Value::string(phase, span),
+        },
+        span,
+    )
+}
+
+fn script_error(err: BoxError) -> ScriptError {
+    match err.downcast::<ScriptError>() {
+        Ok(err) => *err,
+        Err(err) => ScriptError::message(err.to_string()),
+    }
+}
+
+fn panic_error(panic: Box<dyn std::any::Any + Send>) -> ScriptError {
+    let payload = panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| format!("{panic:?}"));
+    ScriptError::message(format!("panic: {payload}"))
+}
@@ -50,28 +50,39 @@ impl TestServer {
         store_path: Option<&std::path::Path>,
         services: bool,
     ) -> Self {
-        let mut cmd = tokio::process::Command::new(assert_cmd::cargo::cargo_bin!("http-nu"));
-        cmd.arg("--log-format").arg("jsonl");
+        let mut args: Vec<std::ffi::OsString> = Vec::new();
 
         // Add plugin arguments first
         for plugin in plugins {
-            cmd.arg("--plugin").arg(plugin);
+            args.extend(["--plugin".into(), plugin.into()]);
         }
 
         // Add store path if provided
         if let Some(path) = store_path {
-            cmd.arg("--store").arg(path);
+            args.extend(["--store".into(), path.into()]);
             if services {
-                cmd.arg("--services");
+                args.push("--services".into());
             }
         }
 
-        cmd.arg(addr).arg("-c").arg(closure);
-
         if tls {
-            cmd.arg("--tls").arg("tests/combined.pem");
+            args.extend(["--tls".into(), "tests/combined.pem".into()]);
         }
 
+        Self::new_with_args(addr, closure, &args).await
+    }
+
+    /// Start the server with extra command line `args`
+    async fn new_with_args(
+        addr: &str,
+        closure: &str,
+        args: &[impl AsRef<std::ffi::OsStr>],
+    ) -> Self {
+        let mut cmd = tokio::process::Command::new(assert_cmd::cargo::cargo_bin!("http-nu"));
+        cmd.arg("--log-format").arg("jsonl");
+        cmd.args(args);
+        cmd.arg(addr).arg("-c").arg(closure);
+
         let mut child = cmd
             .stdout(std::process::Stdio::piped())
             .stderr(std::process::Stdio::piped())
@@ -336,6 +347,305 @@ async fn test_server_tls_socket() {
     assert_eq!(stdout.trim(), "GET");
 }
 
+#[tokio::test]
+async fn test_server_tls_sni_and_reload() {
+    let tmp = tempfile::tempdir().unwrap();
+    // A self-signed certificate for `name`, as `<name>.crt` and `<name>.key`.
+    // Returns a copy to trust it by, named after `version`.
+    let write_cert = |name: &str, version: &str| {
+        let generated = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let cert = tmp.path().join(format!("{name}.crt"));
+        std::fs::write(&cert, generated.cert.pem()).unwrap();
+        std::fs::write(
+            tmp.path().join(format!("{name}.key")),
+            generated.signing_key.serialize_pem(),
+        )
+        .unwrap();
+        let ca = tmp.path().join(format!("{version}.ca"));
+        std::fs::copy(&cert, &ca).unwrap();
+        ca
+    };
+    let a_ca = write_cert("a.test", "a1");
+    let b_ca = write_cert("b.test", "b1");
+
+    let mut args = Vec::new();
+    for name in ["a.test", "b.test"] {
+        args.push("--tls-cert".into());
+        args.push(tmp.path().join(format!("{name}.crt")).into_os_string());
+        args.push("--tls-key".into());
+        args.push(tmp.path().join(format!("{name}.key")).into_os_string());
+    }
+    let server = TestServer::new_with_args("127.0.0.1:0", "{|req| 'ok'}", &args).await;
+    let port = server.address.split(':').next_back().unwrap().to_string();
+    let curl = |name: &'static str, ca: PathBuf| {
+        let port = port.clone();
+        async move {
+            tokio::process::Command::new("curl")
+                .arg("-s")
+                .arg("--cacert")
+                .arg(ca)
+                .arg("--resolve")
+                .arg(format!("{name}:{port}:127.0.0.1"))
+                .arg(format!("https://{name}:{port}/"))
+                .output()
+                .await
+                .expect("Failed to execute curl")
+                .status
+                .success()
+        }
+    };
+
+    assert!(curl("a.test", a_ca.clone()).await);
+    assert!(curl("b.test", b_ca.clone()).await);
+    assert!(!curl("b.test", a_ca.clone()).await);
+
+    // Renewing b.test's certificate swaps it in without a restart
+    let renewed_ca = write_cert("b.test", "b2");
+    let mut reloaded = false;
+    for _ in 0..50 {
+        if curl("b.test", renewed_ca.clone()).await {
+            reloaded = true;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert!(reloaded, "renewed certificate was not served");
+    assert!(!curl("b.test", b_ca).await);
+    assert!(curl("a.test", a_ca).await);
+}
+
+#[tokio::test]
+async fn test_server_mtls() {
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer,
+        KeyPair, SanType,
+    };
+
+    let tmp = tempfile::tempdir().unwrap();
+    let path = |name: &str| tmp.path().join(name);
+
+    // A CA, and a client certificate it issued
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, "Test CA");
+    let ca_key = KeyPair::generate().unwrap();
+    std::fs::write(
+        path("ca.pem"),
+        ca_params.self_signed(&ca_key).unwrap().pem(),
+    )
+    .unwrap();
+    let issuer = Issuer::new(ca_params, ca_key);
+
+    let mut client_params = CertificateParams::new(vec!["billing.internal".to_string()]).unwrap();
+    client_params
+        .distinguished_name
+        .push(DnType::CommonName, "billing");
+    client_params
+        .subject_alt_names
+        .push(SanType::URI("spiffe://acme/billing".try_into().unwrap()));
+    client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let client_key = KeyPair::generate().unwrap();
+    let client_cert = client_params.signed_by(&client_key, &issuer).unwrap();
+    std::fs::write(path("client.pem"), client_cert.pem()).unwrap();
+    std::fs::write(path("client.key"), client_key.serialize_pem()).unwrap();
+
+    // The server's own certificate
+    let server_cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    std::fs::write(path("server.crt"), server_cert.cert.pem()).unwrap();
+    std::fs::write(path("server.key"), server_cert.signing_key.serialize_pem()).unwrap();
+
+    let start = |mode: &'static str| {
+        let args: Vec<std::ffi::OsString> = vec![
+            "--tls-cert".into(),
+            path("server.crt").into(),
+            "--tls-key".into(),
+            path("server.key").into(),
+            "--tls-client-ca".into(),
+            path("ca.pem").into(),
+            "--tls-client-auth".into(),
+            mode.into(),
+        ];
+        async move {
+            TestServer::new_with_args(
+                "127.0.0.1:0",
+                "{|req| $req.tls.client_cert | to json -r }",
+                &args,
+            )
+            .await
+        }
+    };
+    let curl = |server: &TestServer, with_cert: bool| {
+        let port = server.address.split(':').next_back().unwrap().to_string();
+        let mut cmd = tokio::process::Command::new("curl");
+        cmd.arg("-s")
+            .arg("--cacert")
+            .arg(path("server.crt"))
+            .arg("--resolve")
+            .arg(format!("localhost:{port}:127.0.0.1"));
+        if with_cert {
+            cmd.arg("--cert")
+                .arg(path("client.pem"))
+                .arg("--key")
+                .arg(path("client.key"));
+        }
+        cmd.arg(format!("https://localhost:{port}/"));
+        async move { cmd.output().await.expect("Failed to execute curl") }
+    };
+
+    let server = start("required").await;
+    assert!(!curl(&server, false).await.status.success());
+    let output = curl(&server, true).await;
+    assert!(output.status.success());
+    let cert: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(cert["subject"], "CN=billing");
+    assert_eq!(cert["issuer"], "CN=Test CA");
+    assert_eq!(cert["sans"]["dns"], serde_json::json!(["billing.internal"]));
+    assert_eq!(
+        cert["sans"]["uri"],
+        serde_json::json!(["spiffe://acme/billing"])
+    );
+    let fingerprint: String = {
+        use sha2::Digest;
+        sha2::Sha256::digest(client_cert.der())
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    };
+    assert_eq!(cert["fingerprint"], fingerprint);
+
+    let server = start("optional").await;
+    let output = curl(&server, false).await;
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "null");
+    let output = curl(&server, true).await;
+    let cert: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(cert["subject"], "CN=billing");
+}
+
+#[tokio::test]
+async fn test_server_listen_and_redirect_https() {
+    let tmp = tempfile::tempdir().unwrap();
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert = tmp.path().join("localhost.crt");
+    let key = tmp.path().join("localhost.key");
+    std::fs::write(&cert, generated.cert.pem()).unwrap();
+    std::fs::write(&key, generated.signing_key.serialize_pem()).unwrap();
+    let socket = tmp.path().join("http-nu.sock");
+    let free_port = || {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    };
+    let curl = |args: Vec<std::ffi::OsString>| async move {
+        let output = tokio::process::Command::new("curl")
+            .arg("-si")
+            .args(args)
+            .output()
+            .await
+            .expect("Failed to execute curl");
+        String::from_utf8_lossy(&output.stdout).to_string()
+    };
+
+    // Plain --listen addresses serve the same handler
+    let plain = format!("127.0.0.1:{}", free_port());
+    let args = [
+        "--listen",
+        plain.as_str(),
+        "--listen",
+        socket.to_str().unwrap(),
+    ];
+    let server = TestServer::new_with_args("127.0.0.1:0", "{|req| 'ok'}", &args).await;
+    for args in [
+        vec![server.address.clone().into()],
+        vec![format!("http://{plain}/").into()],
+        vec![
+            "--unix-socket".into(),
+            socket.clone().into_os_string(),
+            "http://localhost/".into(),
+        ],
+    ] {
+        assert!(curl(args).await.ends_with("ok"));
+    }
+    drop(server);
+
+    // With --redirect-https, plain TCP ones redirect; the socket and the
+    // --listen-tls address still serve
+    let plain = format!("127.0.0.1:{}", free_port());
+    let secure = format!("127.0.0.1:{}", free_port());
+    let mut args: Vec<std::ffi::OsString> = vec![
+        "--tls-cert".into(),
+        cert.clone().into_os_string(),
+        "--tls-key".into(),
+        key.clone().into_os_string(),
+        "--redirect-https".into(),
+        "--listen-tls".into(),
+        secure.clone().into(),
+    ];
+    for addr in [plain.clone().into(), socket.clone().into_os_string()] {
+        args.push("--listen".into());
+        args.push(addr);
+    }
+    let server = TestServer::new_with_args("127.0.0.1:0", "{|req| 'ok'}", &args).await;
+    let https_port = server.address.split(':').next_back().unwrap();
+
+    let response = curl(vec![format!("http://{plain}/a/b?c=1").into()]).await;
+    assert!(response.starts_with("HTTP/1.1 308"), "{response}");
+    assert!(
+        response.contains(&format!("location: https://127.0.0.1:{https_port}/a/b?c=1")),
+        "{response}"
+    );
+    let response = curl(vec![
+        "-H".into(),
+        "Host: example.test:8080".into(),
+        format!("http://{plain}/").into(),
+    ])
+    .await;
+    assert!(
+        response.contains(&format!("location: https://example.test:{https_port}/")),
+        "{response}"
+    );
+
+    let response = curl(vec![
+        "--unix-socket".into(),
+        socket.into_os_string(),
+        "http://localhost/".into(),
+    ])
+    .await;
+    assert!(response.ends_with("ok"), "{response}");
+    assert!(curl(vec!["-k".into(), server.address.clone().into()])
+        .await
+        .ends_with("ok"));
+    assert!(curl(vec!["-k".into(), format!("https://{secure}/").into()])
+        .await
+        .ends_with("ok"));
+
+    // There's no HTTPS port to redirect to when ADDR is a Unix socket
+    assert_cmd::Command::new(assert_cmd::cargo::cargo_bin!("http-nu"))
+        .arg("--tls-cert")
+        .arg(&cert)
+        .arg("--tls-key")
+        .arg(&key)
+        .args(["--redirect-https", "--listen", &plain])
+        .arg(tmp.path().join("tls.sock"))
+        .args(["-c", "{|req| 'ok'}"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("--redirect-https needs ADDR"));
+
+    // --listen-tls has no certificates to share without TLS on ADDR
+    assert_cmd::Command::new(assert_cmd::cargo::cargo_bin!("http-nu"))
+        .args(["--listen-tls", &plain, "127.0.0.1:0", "-c", "{|req| 'ok'}"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "--listen-tls needs a certificate",
+        ));
+}
+
 #[tokio::test]
 async fn test_server_static_files() {
     let tmp = tempfile::tempdir().unwrap();
@@ -490,6 +800,22 @@ async fn test_server_reverse_proxy_body_handling() {
     assert!(output.status.success());
     let stdout = String::from_utf8_lossy(&output.stdout);
     assert_eq!(stdout.trim(), "override");
+
+    // A body that can't be sent as bytes is an error, not a crash
+    let proxy_closure = format!(
+        r#"{{|req| date now | .reverse-proxy "{}" }}"#,
+        backend.address
+    );
+    let proxy_date = TestServer::new_with_args("127.0.0.1:0", &proxy_closure, &["--dev"]).await;
+    let output = tokio::process::Command::new("curl")
+        .args(["-s", "-w", "%{http_code}"])
+        .arg(&proxy_date.address)
+        .output()
+        .await
+        .expect("Failed to execute curl");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.ends_with("500"), "{stdout}");
+    assert!(stdout.contains("input type: datetime"), "{stdout}");
 }
 
 #[tokio::test]
@@ -626,6 +952,356 @@ async fn test_reverse_proxy_streaming() {
     );
 }
 
+#[tokio::test]
+async fn test_server_reverse_proxy_streams_request_body() {
+    // Backend echoes the body along with how it was framed
+    let backend = TestServer::new(
+        "127.0.0.1:0",
+        r#"{|req|
+            let framing = match [($req.headers | get -o content-length) ($req.headers | get -o transfer-encoding)] {
+                [null null] => { "none" }
+                [null _] => { "chunked" }
+                _ => { "length" }
+            }
+            $"($framing):($in)"
+        }"#,
+        false,
+    )
+    .await;
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    let proxy_closure = format!(r#"{{|req| .reverse-proxy "{}" }}"#, backend.address);
+    let proxy = TestServer::new("127.0.0.1:0", &proxy_closure, false).await;
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    // A bodiless request stays bodiless
+    let output = proxy.curl("/").await;
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "none:");
+
+    // Content-Length of an untouched body is kept
+    let output = tokio::process::Command::new("curl")
+        .args(["-s", "--data-binary", "sized"])
+        .arg(&proxy.address)
+        .output()
+        .await
+        .expect("Failed to execute curl");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "length:sized");
+
+    // A chunked upload is streamed through chunked
+    let output = tokio::process::Command::new("curl")
+        .args(["-s", "-H", "Transfer-Encoding: chunked"])
+        .args(["--data-binary", "streamed"])
+        .arg(&proxy.address)
+        .output()
+        .await
+        .expect("Failed to execute curl");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "chunked:streamed");
+}
+
+#[tokio::test]
+async fn test_server_reverse_proxy_timeout() {
+    let backend = TestServer::new("127.0.0.1:0", r#"{|req| sleep 2sec; "late"}"#, false).await;
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    let proxy_closure = format!(
+        r#"{{|req| .reverse-proxy "{}" {{ timeout: 200ms }} }}"#,
+        backend.address
+    );
+    let proxy = TestServer::new("127.0.0.1:0", &proxy_closure, false).await;
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    let start = std::time::Instant::now();
+    let output = tokio::process::Command::new("curl")
+        .args(["-s", "-w", "\\n%{http_code}"])
+        .arg(&proxy.address)
+        .output()
+        .await
+        .expect("Failed to execute curl");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout, "Gateway Timeout\n504");
+    assert!(start.elapsed() < std::time::Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn test_server_reverse_proxy_forwarded_headers() {
+    let backend = TestServer::new(
+        "127.0.0.1:0",
+        r#"{|req| [$req.headers.x-forwarded-for? $req.headers.x-forwarded-proto? $req.headers.x-forwarded-host? $req.headers.forwarded?] | str join "|"}"#,
+        false,
+    )
+    .await;
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    let proxy_closure = format!(r#"{{|req| .reverse-proxy "{}" }}"#, backend.address);
+    let proxy = TestServer::new("127.0.0.1:0", &proxy_closure, false).await;
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    // Without --trust-proxy, a client-supplied X-Forwarded-For is replaced
+    let output = tokio::process::Command::new("curl")
+        .args(["-s", "-H", "X-Forwarded-For: 6.6.6.6"])
+        .arg(&proxy.address)
+        .output()
+        .await
+        .expect("Failed to execute curl");
+    let host = proxy.address.trim_start_matches("http://");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!(r#"127.0.0.1|http|{host}|for=127.0.0.1;proto=http;host="{host}""#)
+    );
+
+    // `forwarded: false` passes the original headers through untouched
+    let proxy_closure = format!(
+        r#"{{|req| .reverse-proxy "{}" {{ forwarded: false }} }}"#,
+        backend.address
+    );
+    let proxy = TestServer::new("127.0.0.1:0", &proxy_closure, false).await;
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    let output = tokio::process::Command::new("curl")
+        .args(["-s", "-H", "X-Forwarded-For: 6.6.6.6"])
+        .arg(&proxy.address)
+        .output()
+        .await
+        .expect("Failed to execute curl");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "6.6.6.6|||");
+}
+
+#[tokio::test]
+async fn test_server_reverse_proxy_unreachable() {
+    // Grab a free port, then close it so nothing is listening
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let proxy_closure = format!(r#"{{|req| .reverse-proxy "http://127.0.0.1:{port}" }}"#);
+    let proxy = TestServer::new("127.0.0.1:0", &proxy_closure, false).await;
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    let output = tokio::process::Command::new("curl")
+        .args(["-s", "-o", "/dev/null", "-w", "%{http_code}"])
+        .arg(&proxy.address)
+        .output()
+        .await
+        .expect("Failed to execute curl");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "502");
+}
+
+#[tokio::test]
+async fn test_server_reverse_proxy_load_balancing() {
+    let a = TestServer::new("127.0.0.1:0", r#"{|req| "a"}"#, false).await;
+    let b = TestServer::new("127.0.0.1:0", r#"{|req| "b"}"#, false).await;
+    // Nothing listening here
+    let dead = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let proxy_closure = format!(
+        r#"{{|req| .reverse-proxy ["{}" "{}"] }}"#,
+        a.address, b.address
+    );
+    let proxy = TestServer::new("127.0.0.1:0", &proxy_closure, false).await;
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    let mut seen = Vec::new();
+    for _ in 0..4 {
+        let output = proxy.curl("/").await;
+        seen.push(String::from_utf8_lossy(&output.stdout).to_string());
+    }
+    assert_eq!(seen, ["a", "b", "a", "b"]);
+
+    // A dead upstream costs one 502, then it's ejected
+    let proxy_closure = format!(
+        r#"{{|req| .reverse-proxy ["http://{dead}" "{}"] }}"#,
+        a.address
+    );
+    let proxy = TestServer::new("127.0.0.1:0", &proxy_closure, false).await;
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    let output = proxy.curl("/").await;
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Bad Gateway");
+    for _ in 0..3 {
+        let output = proxy.curl("/").await;
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "a");
+    }
+}
+
+#[tokio::test]
+async fn test_server_reverse_proxy_health_check() {
+    let a = TestServer::new("127.0.0.1:0", r#"{|req| "a"}"#, false).await;
+    let b = TestServer::new(
+        "127.0.0.1:0",
+        r#"{|req| if $req.path == "/health" { "down" | metadata set { merge {'http.response': {status: 503}} } } else { "b" } }"#,
+        false,
+    )
+    .await;
+
+    let proxy_closure = format!(
+        r#"{{|req| .reverse-proxy ["{}" "{}"] {{ health_check: {{ path: "/health" interval: 200ms }} }} }}"#,
+        a.address, b.address
+    );
+    let proxy = TestServer::new("127.0.0.1:0", &proxy_closure, false).await;
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    // The first request starts the probes
+    proxy.curl("/").await;
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    for _ in 0..4 {
+        let output = proxy.curl("/").await;
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "a");
+    }
+}
+
+#[tokio::test]
+async fn test_server_reverse_proxy_sticky_hash() {
+    let a = TestServer::new("127.0.0.1:0", r#"{|req| "a"}"#, false).await;
+    let b = TestServer::new("127.0.0.1:0", r#"{|req| "b"}"#, false).await;
+
+    // Wait until both backends answer, so none is ejected (moving keys) by
+    // a connect failure while the machine is loaded
+    for (backend, want) in [(&a, "a"), (&b, "b")] {
+        timeout(std::time::Duration::from_secs(10), async {
+            while String::from_utf8_lossy(&backend.curl("").await.stdout) != want {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("backend never became ready");
+    }
+
+    let proxy_closure = format!(
+        r#"{{|req| .reverse-proxy ["{}" "{}"] {{ strategy: "hash" hash_on: "header:x-user" max_fails: 1000 }} }}"#,
+        a.address, b.address
+    );
+    let proxy = TestServer::new("127.0.0.1:0", &proxy_closure, false).await;
+
+    let fetch = |user: String| {
+        let address = proxy.address.clone();
+        async move {
+            let output = tokio::process::Command::new("curl")
+                .args(["-s", "-H", &format!("x-user: {user}")])
+                .arg(&address)
+                .output()
+                .await
+                .expect("Failed to execute curl");
+            String::from_utf8_lossy(&output.stdout).to_string()
+        }
+    };
+
+    timeout(std::time::Duration::from_secs(10), async {
+        while fetch("ann".into()).await.is_empty() {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("proxy never became ready");
+
+    // Upstream ports are random, and so is which keys land where: enough
+    // users that they all landing on one upstream is vanishingly unlikely
+    let mut backends = std::collections::HashSet::new();
+    for user in (0..32).map(|i| format!("user-{i}")) {
+        let first = fetch(user.clone()).await;
+        assert!(first == "a" || first == "b", "user {user} got {first:?}");
+        for _ in 0..2 {
+            assert_eq!(fetch(user.clone()).await, first, "user {user} moved");
+        }
+        backends.insert(first);
+    }
+    // Keys spread over both upstreams
+    assert_eq!(backends.len(), 2);
+}
+
+#[tokio::test]
+async fn test_server_reverse_proxy_on_response() {
+    let backend = TestServer::new(
+        "127.0.0.1:0",
+        r#"{|req| "<body>hi</body>" | metadata set { merge {'http.response': {headers: {"content-type": "text/html" "set-cookie": ["a=1" "b=2"] "x-upstream": "yes"}}} } }"#,
+        false,
+    )
+    .await;
+
+    // Rewrite the body, drop Set-Cookie and change the status
+    let proxy_closure = format!(
+        r#"{{|req| .reverse-proxy "{}" {{
+            on_response: {{|res|
+                $in | str replace "</body>" "<script></script></body>"
+                | metadata set {{ merge {{'http.response': {{status: 203 headers: ($res.headers | reject set-cookie)}}}} }}
+            }}
+        }} }}"#,
+        backend.address
+    );
+    let proxy = TestServer::new("127.0.0.1:0", &proxy_closure, false).await;
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    let output = tokio::process::Command::new("curl")
+        .arg("-si")
+        .arg(&proxy.address)
+        .output()
+        .await
+        .expect("Failed to execute curl");
+    let response = String::from_utf8_lossy(&output.stdout).to_lowercase();
+    assert!(response.starts_with("http/1.1 203"), "{response}");
+    assert!(response.contains("content-type: text/html\r\n"));
+    assert!(response.contains("x-upstream: yes"));
+    assert!(!response.contains("set-cookie"));
+    assert!(response.ends_with("<body>hi<script></script></body>"));
+
+    // Without its own metadata the upstream status and headers are kept
+    let proxy_closure = format!(
+        r#"{{|req| .reverse-proxy "{}" {{ on_response: {{|res| $in | str upcase }} }} }}"#,
+        backend.address
+    );
+    let proxy = TestServer::new("127.0.0.1:0", &proxy_closure, false).await;
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    let output = tokio::process::Command::new("curl")
+        .arg("-si")
+        .arg(&proxy.address)
+        .output()
+        .await
+        .expect("Failed to execute curl");
+    let response = String::from_utf8_lossy(&output.stdout);
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(response.contains("set-cookie: a=1\r\nset-cookie: b=2\r\n"));
+    assert!(response.ends_with("<BODY>HI</BODY>"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_server_reverse_proxy_unix_socket() {
+    let tmp = tempfile::tempdir().unwrap();
+    let socket_path = tmp.path().join("backend.sock");
+    let socket_path_str = socket_path.to_str().unwrap();
+    let _backend = TestServer::new(
+        socket_path_str,
+        r#"{|req| $"($req.method) ($req.path) ($req.headers.host)"}"#,
+        false,
+    )
+    .await;
+    tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+
+    // Plain socket target
+    let proxy_closure = format!(r#"{{|req| .reverse-proxy "unix://{socket_path_str}" }}"#);
+    let proxy = TestServer::new("127.0.0.1:0", &proxy_closure, false).await;
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    let output = proxy.curl("/users").await;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("GET /users 127.0.0.1:"), "{stdout}");
+
+    // Socket target with a base path and its own Host header
+    let proxy_closure = format!(
+        r#"{{|req| .reverse-proxy "unix://{socket_path_str}:/api" {{ preserve_host: false }} }}"#
+    );
+    let proxy = TestServer::new("127.0.0.1:0", &proxy_closure, false).await;
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    let output = proxy.curl("/users").await;
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "GET /api/users localhost"
+    );
+}
+
 #[tokio::test]
 async fn test_server_reverse_proxy_custom_query() {
     // Start a backend server that echoes the query parameters it receives.
@@ -979,6 +1655,228 @@ async fn test_to_sse_command() {
     );
 }
 
+#[tokio::test]
+async fn test_websocket_echo() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    let server = TestServer::new(
+        "127.0.0.1:0",
+        r#"{|req| .websocket {|| each {|msg| $"echo: ($msg)" } } }"#,
+        false,
+    )
+    .await;
+
+    let url = server.address.replace("http://", "ws://");
+    let (mut ws, resp) = timeout(
+        std::time::Duration::from_secs(5),
+        tokio_tungstenite::connect_async(url),
+    )
+    .await
+    .expect("connect timed out")
+    .expect("websocket handshake failed");
+    assert_eq!(resp.status(), 101);
+
+    for word in ["hello", "world"] {
+        ws.send(Message::text(word)).await.unwrap();
+        let reply = timeout(std::time::Duration::from_secs(5), ws.next())
+            .await
+            .expect("no reply")
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply, Message::text(format!("echo: {word}")));
+    }
+
+    ws.close(None).await.unwrap();
+
+    // A plain HTTP request to the same handler is refused
+    let output = std::process::Command::new("curl")
+        .arg("-s")
+        .arg("-o")
+        .arg("/dev/null")
+        .arg("-w")
+        .arg("%{http_code}")
+        .arg(&server.address)
+        .output()
+        .expect("curl failed");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "426");
+}
+
+#[tokio::test]
+async fn test_websocket_sends_other_values_as_json() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    let server = TestServer::new(
+        "127.0.0.1:0",
+        r#"{|req| .websocket {|| each {|msg|
+            match $msg {
+                "date" => 2024-01-02T03:04:05+00:00
+                "duration" => 90sec
+                _ => {n: ($msg | into int)}
+            }
+        } } }"#,
+        false,
+    )
+    .await;
+
+    let url = server.address.replace("http://", "ws://");
+    let (mut ws, _) = timeout(
+        std::time::Duration::from_secs(5),
+        tokio_tungstenite::connect_async(url),
+    )
+    .await
+    .expect("connect timed out")
+    .expect("websocket handshake failed");
+
+    for (sent, want) in [
+        ("date", r#""2024-01-02T03:04:05+00:00""#),
+        ("duration", r#""1min 30sec""#),
+        ("7", r#"{"n":7}"#),
+    ] {
+        ws.send(Message::text(sent)).await.unwrap();
+        let reply = timeout(std::time::Duration::from_secs(5), ws.next())
+            .await
+            .expect("no reply")
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply, Message::text(want));
+    }
+    ws.close(None).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_websocket_slow_reader_loses_nothing() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    // The closure reads `$in` slower than the client sends, well past the 32
+    // message buffer, while pushing ticks of its own
+    let server = TestServer::new(
+        "127.0.0.1:0",
+        r#"{|req| .websocket {||
+            each {|msg| sleep 10ms; $"echo: ($msg)" }
+            | interleave { 1..1000 | each {|i| sleep 20ms; $"tick ($i)" } }
+        } }"#,
+        false,
+    )
+    .await;
+
+    let url = server.address.replace("http://", "ws://");
+    let (ws, _) = timeout(
+        std::time::Duration::from_secs(5),
+        tokio_tungstenite::connect_async(url),
+    )
+    .await
+    .expect("connect timed out")
+    .expect("websocket handshake failed");
+    let (mut tx, mut rx) = ws.split();
+
+    let sending = tokio::spawn(async move {
+        for i in 0..100 {
+            tx.send(Message::text(format!("msg {i}"))).await.unwrap();
+        }
+        tx
+    });
+
+    let mut echoes = Vec::new();
+    let mut ticks_while_echoing = 0;
+    while echoes.len() < 100 {
+        let frame = timeout(std::time::Duration::from_secs(5), rx.next())
+            .await
+            .expect("outbound frames stalled")
+            .unwrap()
+            .unwrap();
+        let text = frame.to_text().unwrap().to_string();
+        match text.strip_prefix("echo: ") {
+            Some(msg) => echoes.push(msg.to_string()),
+            None if !echoes.is_empty() => ticks_while_echoing += 1,
+            None => {}
+        }
+    }
+
+    let want: Vec<String> = (0..100).map(|i| format!("msg {i}")).collect();
+    assert_eq!(echoes, want);
+    assert!(ticks_while_echoing > 0, "ticks stalled behind inbound");
+    let mut tx = sending.await.unwrap();
+    tx.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_websocket_push_only_closes_on_message() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::Message;
+
+    // The closure never uses `$in`, so there's nowhere to deliver messages
+    let server = TestServer::new(
+        "127.0.0.1:0",
+        r#"{|req| .websocket {|| 1..1000 | each {|i| sleep 20ms; $"tick ($i)" } } }"#,
+        false,
+    )
+    .await;
+
+    let url = server.address.replace("http://", "ws://");
+    let (mut ws, _) = timeout(
+        std::time::Duration::from_secs(5),
+        tokio_tungstenite::connect_async(url),
+    )
+    .await
+    .expect("connect timed out")
+    .expect("websocket handshake failed");
+
+    ws.send(Message::text("hello")).await.unwrap();
+    loop {
+        let frame = timeout(std::time::Duration::from_secs(5), ws.next())
+            .await
+            .expect("socket never closed")
+            .unwrap()
+            .unwrap();
+        if let Message::Close(close) = frame {
+            assert_eq!(close.unwrap().code, CloseCode::Policy);
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_reverse_proxy_websocket_upgrade() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    let backend = TestServer::new(
+        "127.0.0.1:0",
+        r#"{|req| .websocket {|| each {|msg| $"echo: ($msg)" } } }"#,
+        false,
+    )
+    .await;
+    let proxy_closure = format!(r#"{{|req| .reverse-proxy "{}" }}"#, backend.address);
+    let proxy = TestServer::new("127.0.0.1:0", &proxy_closure, false).await;
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    let url = proxy.address.replace("http://", "ws://");
+    let (mut ws, resp) = timeout(
+        std::time::Duration::from_secs(5),
+        tokio_tungstenite::connect_async(url),
+    )
+    .await
+    .expect("connect timed out")
+    .expect("websocket handshake failed");
+    assert_eq!(resp.status(), 101);
+
+    for word in ["through", "proxy"] {
+        ws.send(Message::text(word)).await.unwrap();
+        let reply = timeout(std::time::Duration::from_secs(5), ws.next())
+            .await
+            .expect("no reply")
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply, Message::text(format!("echo: {word}")));
+    }
+
+    ws.close(None).await.unwrap();
+}
+
 #[tokio::test]
 async fn test_to_sse_ignores_null_fields() {
     // Test that `to sse` ignores null values for optional fields